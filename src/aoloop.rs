@@ -5,32 +5,98 @@ use std::thread;
 use std::option;
 use std::time::{Duration, Instant};
 use log::{trace, debug, info, warn};
+use ndarray::{Array1, Array2};
 
-use crate::fakecamera::Camera;
-use crate::wfs::ShackHartmann;
+use crate::detector::Detector;
+use crate::wfs::{PixelCalibration, WavefrontSensor};
 use crate::fakedm::DM;
-use crate::controller::IntegratorController;
+use crate::commandmap::CommandMap;
+use crate::measurementfusion::MeasurementFusion;
+use crate::controller::Controller;
 use crate::shmupdater::ShmUpdater;
+use crate::calibration::{PokeCalibration, PokePattern, CalibrationResult};
 
 pub struct AOLoop {
-    cameras: Arc<Vec<Camera>>,
-    wfs: Arc<Vec<ShackHartmann>>,
-    controller: Arc<Mutex<IntegratorController>>,
+    cameras: Arc<Vec<Box<dyn Detector>>>,
+    wfs: Arc<Vec<Box<dyn WavefrontSensor>>>,
+    measurement_fusion: Arc<Mutex<MeasurementFusion>>,
+    controller: Arc<Mutex<Box<dyn Controller>>>,
     dms: Arc<Mutex<Vec<DM>>>,
+    command_map: Arc<Mutex<CommandMap>>,
     thread_handle: option::Option<thread::JoinHandle<()>>,
     loop_running: Arc<AtomicBool>,
     iteration_number: Arc<AtomicU64>,
+    dropped_frames: Arc<AtomicU64>,
+    camera_stalls: Arc<AtomicU64>,
+    saturated_actuators: Arc<AtomicU64>,
     timer: Arc<Mutex<LoopTimers>>,
     shm_updater: Arc<Mutex<ShmUpdater>>,
+    frame_timeout: Duration,
+    pipelined: bool,
+    /// Whether the loop thread, if any, was started in pipelined mode
+    running_pipelined: bool,
 }
 
 
+/// How often a camera that stays stalled is reported again
+const STALL_WARNING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Follows one camera through stalls, so each stall is counted once and
+/// reported when it starts, every `STALL_WARNING_INTERVAL` while it lasts
+/// and when it ends, rather than on every timeout
+struct StallTracker {
+    camera: usize,
+    /// When the camera stopped delivering frames, and when that was last reported
+    stalled: Option<(Instant, Instant)>,
+}
+
+impl StallTracker {
+    fn new(camera: usize) -> Self {
+        Self {
+            camera: camera,
+            stalled: None,
+        }
+    }
+
+    fn is_stalled(&self) -> bool {
+        self.stalled.is_some()
+    }
+
+    /// The camera gave no new frame within `timeout`
+    fn timed_out(&mut self, timeout: Duration, camera_stalls: &AtomicU64) {
+        let now = Instant::now();
+        match &mut self.stalled {
+            None => {
+                warn!("Camera {} stalled: no new frame for {:?}", self.camera, timeout);
+                camera_stalls.fetch_add(1, Ordering::Relaxed);
+                self.stalled = Some((now, now));
+            }
+            Some((since, last_warning)) => {
+                if now.duration_since(*last_warning) >= STALL_WARNING_INTERVAL {
+                    warn!("Camera {} still stalled after {:?}", self.camera, now.duration_since(*since));
+                    *last_warning = now;
+                }
+            }
+        }
+    }
+
+    /// The camera delivered a frame
+    fn frame_arrived(&mut self) {
+        if let Some((since, _)) = self.stalled.take() {
+            info!("Camera {} recovered after {:?}", self.camera, since.elapsed());
+        }
+    }
+}
+
 struct LoopTimers {
     pub total_time: Duration,
     pub cam_time:   Duration,
     pub wfs_time:   Duration,
     pub ctrl_time:  Duration,
     pub dm_time:    Duration,
+    /// From the last pixel the loop needs arriving to the DM commands being set
+    pub latency:     Duration,
+    pub max_latency: Duration,
 }
 
 impl LoopTimers{
@@ -41,10 +107,24 @@ impl LoopTimers{
         info!("Controller Time: {:?}", self.ctrl_time);
         info!("DM Time:         {:?}", self.dm_time);
     }
+
+    pub fn add_latency(&mut self, latency: Duration) {
+        self.latency += latency;
+        self.max_latency = self.max_latency.max(latency);
+    }
 }
 
 impl AOLoop {
-    pub fn new(cameras: Vec<Camera>, wfs: Vec<ShackHartmann>, controller: IntegratorController, dms: Vec<DM>) -> Self {
+    /// The measurements of every WFS are concatenated in order for the
+    /// controller, and its commands are split between the DMs in order, so
+    /// the controller must match the total number of measurements and actuators.
+    pub fn new(cameras: Vec<Box<dyn Detector>>, wfs: Vec<Box<dyn WavefrontSensor>>, controller: Box<dyn Controller>, dms: Vec<DM>) -> Self {
+        let measurement_fusion = MeasurementFusion::new(&wfs.iter().map(|wfs| wfs.n_measurements()).collect::<Vec<_>>());
+        assert_eq!(controller.n_measurements(), measurement_fusion.n_outputs(),
+            "AOLoop: controller takes {} measurements but the WFSs produce {}", controller.n_measurements(), measurement_fusion.n_outputs());
+        let command_map = CommandMap::new(&dms.iter().map(|dm| dm.n_acts).collect::<Vec<_>>());
+        assert_eq!(controller.n_commands(), command_map.n_commands(),
+            "AOLoop: controller produces {} commands but the DMs have {} actuators", controller.n_commands(), command_map.n_commands());
         let loop_running = Arc::new(AtomicBool::new(false));
         let iteration_number = Arc::new(AtomicU64::new(0));
 
@@ -54,99 +134,462 @@ impl AOLoop {
             wfs_time: Duration::new(0, 0),
             ctrl_time: Duration::new(0, 0),
             dm_time: Duration::new(0, 0),
+            latency: Duration::new(0, 0),
+            max_latency: Duration::new(0, 0),
         };
 
         let shm_updater = ShmUpdater::new(
-            wfs[0].n_measurements, dms[0].n_acts, cameras[0].n_rows, cameras[0].n_cols
+            measurement_fusion.n_outputs(), command_map.n_commands(), cameras[0].n_rows(), cameras[0].n_cols()
         );
         Self {
             cameras: Arc::new(cameras),
             wfs: Arc::new(wfs),
+            measurement_fusion: Arc::new(Mutex::new(measurement_fusion)),
             controller: Arc::new(Mutex::new(controller)),
             dms: Arc::new(Mutex::new(dms)),
+            command_map: Arc::new(Mutex::new(command_map)),
             thread_handle: None,
             loop_running: loop_running,
             iteration_number: iteration_number,
+            dropped_frames: Arc::new(AtomicU64::new(0)),
+            camera_stalls: Arc::new(AtomicU64::new(0)),
+            saturated_actuators: Arc::new(AtomicU64::new(0)),
             timer: Arc::new(Mutex::new(timer)),
             shm_updater: Arc::new(Mutex::new(shm_updater)),
+            frame_timeout: Duration::from_secs(1),
+            pipelined: false,
+            running_pipelined: false,
         }
     }
 
+    /// Replaces how the WFS measurements are combined, e.g. to add a fusion
+    /// matrix. It must produce as many measurements as the controller takes.
+    pub fn set_measurement_fusion(&mut self, measurement_fusion: MeasurementFusion) {
+        assert_eq!(measurement_fusion.n_wfs(), self.wfs.len(), "AOLoop: measurement fusion is for {} WFSs, loop has {}", measurement_fusion.n_wfs(), self.wfs.len());
+        self.check_fusion(&measurement_fusion);
+        *self.measurement_fusion.lock().unwrap() = measurement_fusion;
+    }
+
+    /// Multiplies the concatenated WFS measurements by `fusion_matrix`, keeping
+    /// the current offsets, weights and enabled WFSs. Not allowed while the
+    /// loop runs pipelined.
+    pub fn set_fusion_matrix(&mut self, fusion_matrix: Array2<f32>) {
+        let mut measurement_fusion = self.measurement_fusion.lock().unwrap().clone();
+        measurement_fusion.set_fusion_matrix(fusion_matrix);
+        self.check_fusion(&measurement_fusion);
+        *self.measurement_fusion.lock().unwrap() = measurement_fusion;
+    }
+
+    pub fn clear_fusion_matrix(&mut self) {
+        let mut measurement_fusion = self.measurement_fusion.lock().unwrap().clone();
+        measurement_fusion.clear_fusion_matrix();
+        self.check_fusion(&measurement_fusion);
+        *self.measurement_fusion.lock().unwrap() = measurement_fusion;
+    }
+
+    /// Reference measurements subtracted from WFS `wfs_index`, see `MeasurementFusion::set_offsets`
+    pub fn set_wfs_offsets(&mut self, wfs_index: usize, offsets: Array1<f32>) {
+        self.measurement_fusion.lock().unwrap().set_offsets(wfs_index, offsets);
+    }
+
+    pub fn set_wfs_weight(&mut self, wfs_index: usize, weight: f32) {
+        self.measurement_fusion.lock().unwrap().set_weight(wfs_index, weight);
+    }
+
+    pub fn set_wfs_enabled(&mut self, wfs_index: usize, enabled: bool) {
+        self.measurement_fusion.lock().unwrap().set_enabled(wfs_index, enabled);
+    }
+
+    /// Checks that `measurement_fusion` can replace the current one
+    fn check_fusion(&self, measurement_fusion: &MeasurementFusion) {
+        let n_measurements = self.controller.lock().unwrap().n_measurements();
+        assert_eq!(measurement_fusion.n_outputs(), n_measurements,
+            "AOLoop: measurement fusion produces {} measurements, controller takes {}", measurement_fusion.n_outputs(), n_measurements);
+        assert!(!(self.running_pipelined && measurement_fusion.has_fusion_matrix()), "AOLoop: pipelined mode cannot use a fusion matrix");
+    }
+
+    /// Replaces the mapping of commands to DMs, e.g. to add offloads. It must
+    /// give each DM its own number of actuators.
+    pub fn set_command_map(&mut self, command_map: CommandMap) {
+        let dms = self.dms.lock().unwrap();
+        assert_eq!(command_map.n_dms(), dms.len(), "AOLoop: command map is for {} DMs, loop has {}", command_map.n_dms(), dms.len());
+        for (i, dm) in dms.iter().enumerate() {
+            assert_eq!(command_map.n_acts(i), dm.n_acts, "AOLoop: command map gives DM {} {} actuators, it has {}", i, command_map.n_acts(i), dm.n_acts);
+        }
+        *self.command_map.lock().unwrap() = command_map;
+    }
+
+    /// Sets how long the loop waits for a new camera frame before reporting
+    /// that camera as stalled. While one camera is stalled the loop runs on
+    /// the others, leaving out the WFSs that read the stalled one.
+    pub fn set_frame_timeout(&mut self, frame_timeout: Duration) {
+        self.frame_timeout = frame_timeout;
+    }
+
+    /// Pipelined mode starts centroiding sub-apertures as soon as their rows
+    /// arrive from the camera and feeds each block of measurements to the
+    /// controller's partial product, so the commands are ready shortly after
+    /// the last pixel. Needs a single WFS and no fusion matrix. Takes effect
+    /// the next time the loop is started. A control matrix changed while a
+    /// frame reads out is used from the next frame on.
+    pub fn set_pipelined(&mut self, pipelined: bool) {
+        self.pipelined = pipelined;
+    }
+
     pub fn start_loop(&mut self) {
+        if self.pipelined {
+            return self.start_pipelined_loop();
+        }
         let loop_running = Arc::clone(&self.loop_running);
         let iteration_number = Arc::clone(&self.iteration_number);
+        let dropped_frames = Arc::clone(&self.dropped_frames);
+        let camera_stalls = Arc::clone(&self.camera_stalls);
+        let saturated_actuators = Arc::clone(&self.saturated_actuators);
         let cameras = Arc::clone(&self.cameras);
         let wfs = Arc::clone(&self.wfs);
+        let measurement_fusion_mut = Arc::clone(&self.measurement_fusion);
         let controller_mut = Arc::clone(&self.controller);
         let dms_mut = Arc::clone(&self.dms);
+        let command_map_mut = Arc::clone(&self.command_map);
         let timer_mutex = Arc::clone(&self.timer);
         let shm_updater_mutex = self.shm_updater.clone();
+        let frame_timeout = self.frame_timeout;
 
         loop_running.store(true, std::sync::atomic::Ordering::Relaxed);
 
         self.thread_handle = option::Option::Some(std::thread::spawn(move || {
+            let mut stall_trackers = (0..cameras.len()).map(StallTracker::new).collect::<Vec<_>>();
+            let mut last_frame_numbers = cameras.iter().map(|cam| cam.get_frame_number()).collect::<Vec<_>>();
+            let mut measurements = wfs.iter().map(|wfs| Array1::<f32>::zeros(wfs.n_measurements())).collect::<Vec<_>>();
+
             while loop_running.load(std::sync::atomic::Ordering::Relaxed) {
                 let loop_start = Instant::now();
                 trace!("Iteration: {}", iteration_number.load(Ordering::Relaxed));
 
+                // Wait for a new frame from every camera. Cameras already
+                // stalled are only polled while another camera still runs,
+                // so they do not hold up its frames.
+                let cam_start = Instant::now();
+                let all_stalled = stall_trackers.iter().all(|tracker| tracker.is_stalled());
+                let mut fresh = vec![false; cameras.len()];
+                for (i, cam) in cameras.iter().enumerate() {
+                    let timeout = if stall_trackers[i].is_stalled() && !all_stalled { Duration::ZERO } else { frame_timeout };
+                    fresh[i] = cam.wait_for_frame(last_frame_numbers[i], timeout).is_some();
+                    if fresh[i] {
+                        stall_trackers[i].frame_arrived();
+                    } else {
+                        stall_trackers[i].timed_out(frame_timeout, &camera_stalls);
+                    }
+                }
+                if !fresh.contains(&true) {
+                    continue;
+                }
+                let frames_ready = Instant::now();
+
                 let mut timer = timer_mutex.lock().unwrap();
 
-                // Get detector images
-                let cam_start = Instant::now();
-                let detector_images = cameras.iter().map(|cam| cam.get_frame()).collect::<Vec<_>>();
+                // Borrow the new detector images, checking for frames we never saw
+                let detector_images = cameras.iter().zip(&fresh)
+                    .map(|(cam, &fresh)| if fresh { Some(cam.latest_frame()) } else { None })
+                    .collect::<Vec<_>>();
+                for (i, frame) in detector_images.iter().enumerate() {
+                    let Some(frame) = frame else { continue };
+                    let missed = frame.sequence().saturating_sub(last_frame_numbers[i] + 1);
+                    if missed > 0 {
+                        debug!("Camera {}: dropped {} frame(s) before frame {}", i, missed, frame.sequence());
+                        dropped_frames.fetch_add(missed, Ordering::Relaxed);
+                    }
+                    last_frame_numbers[i] = frame.sequence();
+                }
                 timer.cam_time += cam_start.elapsed();
 
+                // WFSs on a stalled camera sit this iteration out, see
+                // `MeasurementFusion::fuse_available`
                 let wfs_start = Instant::now();
-                let measurements = wfs.iter().map(|
-                        wfs| wfs.measure(&detector_images[wfs.detector_id])
-                    ).collect::<Vec<_>>();
+                let available = wfs.iter().map(|wfs| fresh[wfs.detector_id()]).collect::<Vec<_>>();
+                for (wfs, measurements) in wfs.iter().zip(measurements.iter_mut()) {
+                    if let Some(frame) = &detector_images[wfs.detector_id()] {
+                        wfs.measure_into(&frame.view(), measurements);
+                    }
+                }
                 timer.wfs_time += wfs_start.elapsed();
 
                 // Compute Commands
                 let ctrl_start = Instant::now();
                 let mut controller = controller_mut.lock().unwrap();
-                let commands = controller.compute_commands(&measurements[0]);
+                let fused_measurements = measurement_fusion_mut.lock().unwrap().fuse_available(&measurements, &available);
+                let commands = controller.compute_commands(&fused_measurements);
+                let modal_coefficients = controller.modal_coefficients();
                 timer.ctrl_time += ctrl_start.elapsed();
 
-                // Apply Commands
-                // TODO - sort specific commands to specific DMs
+                // Apply Commands, each DM clipping its own slice to its stroke
                 let dm_start = Instant::now();
-                let mut dms = dms_mut.lock().unwrap();
-                dms.iter_mut().for_each(|dm| dm.set_actuators(&commands));
+                let (applied, n_saturated) = apply_commands(
+                    &commands, controller.as_mut(), &dms_mut, &command_map_mut, &saturated_actuators
+                );
                 timer.dm_time += dm_start.elapsed();
+                timer.add_latency(frames_ready.elapsed());
 
                 let iteration = iteration_number.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
                 trace!("Iteration: {}", iteration);
 
                 let mut shm_updater = shm_updater_mutex.lock().unwrap();
                 // shm_updater.update_camera_frame(&detector_images[0], iteration);
-                shm_updater.update_actuator_commands(&commands, iteration);
-                shm_updater.update_wfs_measurements(&measurements[0], iteration);
+                shm_updater.update_actuator_commands(&applied, iteration);
+                shm_updater.update_saturation(n_saturated, iteration);
+                shm_updater.update_wfs_measurements(&fused_measurements, iteration);
+                if let Some(modal_coefficients) = &modal_coefficients {
+                    shm_updater.update_modal_coefficients(modal_coefficients, iteration);
+                }
 
                 timer.total_time += loop_start.elapsed();
             }
         }));
     }
 
+    fn start_pipelined_loop(&mut self) {
+        assert_eq!(self.wfs.len(), 1, "AOLoop: pipelined mode supports a single WFS");
+        assert!(!self.measurement_fusion.lock().unwrap().has_fusion_matrix(), "AOLoop: pipelined mode cannot use a fusion matrix");
+        self.running_pipelined = true;
+        let loop_running = Arc::clone(&self.loop_running);
+        let iteration_number = Arc::clone(&self.iteration_number);
+        let dropped_frames = Arc::clone(&self.dropped_frames);
+        let camera_stalls = Arc::clone(&self.camera_stalls);
+        let saturated_actuators = Arc::clone(&self.saturated_actuators);
+        let cameras = Arc::clone(&self.cameras);
+        let wfs = Arc::clone(&self.wfs);
+        let measurement_fusion_mut = Arc::clone(&self.measurement_fusion);
+        let controller_mut = Arc::clone(&self.controller);
+        let dms_mut = Arc::clone(&self.dms);
+        let command_map_mut = Arc::clone(&self.command_map);
+        let timer_mutex = Arc::clone(&self.timer);
+        let shm_updater_mutex = self.shm_updater.clone();
+        let frame_timeout = self.frame_timeout;
+
+        loop_running.store(true, std::sync::atomic::Ordering::Relaxed);
+
+        self.thread_handle = option::Option::Some(std::thread::spawn(move || {
+            let wfs = &wfs[0];
+            let mut stall_tracker = StallTracker::new(wfs.detector_id());
+            let cam = &cameras[wfs.detector_id()];
+            let mut slope_stream = wfs.stream();
+            let mut fused_measurements = Array1::<f32>::zeros(measurement_fusion_mut.lock().unwrap().n_outputs());
+            let mut last_frame_number = cam.get_frame_number();
+
+            while loop_running.load(std::sync::atomic::Ordering::Relaxed) {
+                let loop_start = Instant::now();
+                trace!("Iteration: {}", iteration_number.load(Ordering::Relaxed));
+
+                // Wait for the next frame to start reading out
+                let mut cam_time = Duration::ZERO;
+                let cam_start = Instant::now();
+                let Some(frame) = cam.wait_for_partial_frame(last_frame_number, frame_timeout) else {
+                    stall_tracker.timed_out(frame_timeout, &camera_stalls);
+                    continue;
+                };
+                stall_tracker.frame_arrived();
+                let missed = frame.sequence().saturating_sub(last_frame_number + 1);
+                if missed > 0 {
+                    debug!("Camera {}: dropped {} frame(s) before frame {}", wfs.detector_id(), missed, frame.sequence());
+                    dropped_frames.fetch_add(missed, Ordering::Relaxed);
+                }
+                last_frame_number = frame.sequence();
+                cam_time += cam_start.elapsed();
+
+                // Centroid each band of sub-apertures as its last row arrives
+                // and fold it into the controller's partial product. The
+                // locks are only held per band, not while waiting for rows,
+                // so parameter changes get through during the readout. The
+                // controller holds back a new control matrix until the next
+                // frame, so each frame uses one control law.
+                let mut wfs_time = Duration::ZERO;
+                let mut ctrl_time = Duration::ZERO;
+                controller_mut.lock().unwrap().begin_frame();
+                slope_stream.reset();
+                let mut last_pixel = Instant::now();
+                while !slope_stream.is_complete() {
+                    let cam_start = Instant::now();
+                    let rows_needed = slope_stream.rows_needed();
+                    let rows_ready = frame.wait_for_rows(rows_needed, frame_timeout);
+                    last_pixel = Instant::now();
+                    cam_time += cam_start.elapsed();
+                    if rows_ready < rows_needed {
+                        warn!("Camera {} stalled: frame {} stopped at row {}", wfs.detector_id(), frame.sequence(), rows_ready);
+                        break;
+                    }
+
+                    let wfs_start = Instant::now();
+                    let updated = slope_stream.update(&frame.rows(0..rows_ready));
+                    let fused_updated = measurement_fusion_mut.lock().unwrap()
+                        .fuse_ranges(0, slope_stream.measurements(), &updated, &mut fused_measurements);
+                    wfs_time += wfs_start.elapsed();
+
+                    let ctrl_start = Instant::now();
+                    controller_mut.lock().unwrap().accumulate(&fused_measurements, &fused_updated);
+                    ctrl_time += ctrl_start.elapsed();
+                }
+                drop(frame);
+                if !slope_stream.is_complete() {
+                    continue;
+                }
+
+                let mut controller = controller_mut.lock().unwrap();
+                let ctrl_start = Instant::now();
+                let commands = controller.finish_frame(&fused_measurements);
+                let modal_coefficients = controller.modal_coefficients();
+                ctrl_time += ctrl_start.elapsed();
+
+                let dm_start = Instant::now();
+                let (applied, n_saturated) = apply_commands(
+                    &commands, controller.as_mut(), &dms_mut, &command_map_mut, &saturated_actuators
+                );
+                let dm_time = dm_start.elapsed();
+                let latency = last_pixel.elapsed();
+                drop(controller);
+
+                let iteration = iteration_number.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                trace!("Iteration: {}", iteration);
+
+                let mut shm_updater = shm_updater_mutex.lock().unwrap();
+                shm_updater.update_actuator_commands(&applied, iteration);
+                shm_updater.update_saturation(n_saturated, iteration);
+                shm_updater.update_wfs_measurements(&fused_measurements, iteration);
+                if let Some(modal_coefficients) = &modal_coefficients {
+                    shm_updater.update_modal_coefficients(modal_coefficients, iteration);
+                }
+
+                let mut timer = timer_mutex.lock().unwrap();
+                timer.cam_time += cam_time;
+                timer.wfs_time += wfs_time;
+                timer.ctrl_time += ctrl_time;
+                timer.dm_time += dm_time;
+                timer.add_latency(latency);
+                timer.total_time += loop_start.elapsed();
+            }
+        }));
+    }
+
     pub fn stop_loop(&mut self) {
         self.loop_running.store(false,Ordering::Relaxed);
-        self.thread_handle.take().map(|h| h.join().unwrap());
+        if let Some(handle) = self.thread_handle.take() {
+            handle.join().unwrap();
+        }
+        self.running_pipelined = false;
     }
 
     pub fn get_iteration_number(&self) -> u64 {
         self.iteration_number.load(std::sync::atomic::Ordering::Relaxed)
     }
 
+    /// Shared handle to the controller, e.g. to install a new control matrix
+    /// while the loop is running
+    pub fn controller(&self) -> Arc<Mutex<Box<dyn Controller>>> {
+        Arc::clone(&self.controller)
+    }
+
+    /// Measures the interaction matrix between DM `dm_index` and WFS `wfs_index`
+    /// using the loop's own components. The loop must be stopped.
+    pub fn measure_interaction_matrix(&self, calibration: &PokeCalibration, wfs_index: usize, dm_index: usize) -> Option<Array2<f32>> {
+        assert!(!self.loop_running.load(Ordering::Relaxed), "AOLoop: stop the loop before calibrating");
+        let wfs = &self.wfs[wfs_index];
+        let camera = &self.cameras[wfs.detector_id()];
+        let mut dms = self.dms.lock().unwrap();
+        calibration.measure_interaction_matrix(camera.as_ref(), wfs.as_ref(), &mut dms[dm_index])
+    }
+
+    /// As `measure_interaction_matrix`, driving the DM through `pattern`
+    pub fn calibrate(&self, calibration: &PokeCalibration, pattern: &PokePattern, wfs_index: usize, dm_index: usize) -> Option<CalibrationResult> {
+        assert!(!self.loop_running.load(Ordering::Relaxed), "AOLoop: stop the loop before calibrating");
+        let wfs = &self.wfs[wfs_index];
+        let camera = &self.cameras[wfs.detector_id()];
+        let mut dms = self.dms.lock().unwrap();
+        calibration.calibrate(camera.as_ref(), wfs.as_ref(), &mut dms[dm_index], pattern)
+    }
+
+    /// Replaces the dark, background and flat frames of WFS `wfs_index`.
+    /// Safe to call while the loop is running, it takes effect on the next
+    /// measurement.
+    pub fn set_pixel_calibration(&self, wfs_index: usize, calibration: PixelCalibration) {
+        self.wfs[wfs_index].set_pixel_calibration(calibration);
+    }
+
+    /// Number of camera frames produced that the loop never processed
+    pub fn get_dropped_frames(&self) -> u64 {
+        self.dropped_frames.load(Ordering::Relaxed)
+    }
+
+    /// Number of times a camera stopped delivering frames for longer than
+    /// the frame timeout, each stall counted once however long it lasts
+    pub fn get_camera_stalls(&self) -> u64 {
+        self.camera_stalls.load(Ordering::Relaxed)
+    }
+
+    /// Total number of actuator commands clipped to the DM stroke limits
+    pub fn get_saturated_actuators(&self) -> u64 {
+        self.saturated_actuators.load(Ordering::Relaxed)
+    }
+
     pub fn print_timers(&self) {
         let timer = self.timer.lock().unwrap();
         timer.print();
 
         let iteration_number = self.iteration_number.load(Ordering::Relaxed);
+        info!("Dropped Frames:  {}", self.get_dropped_frames());
+        info!("Camera Stalls:   {}", self.get_camera_stalls());
+        info!("Saturated Actuators: {}", self.get_saturated_actuators());
         info!("\nPer Iteration:");
         info!("Iteration Time:      {:?} ns", timer.total_time.as_nanos() / iteration_number as u128);
         info!("Camera Time:         {:?} ns", timer.cam_time.as_nanos() / iteration_number as u128);
         info!("WFS Time:            {:?} ns", timer.wfs_time.as_nanos() / iteration_number as u128);
         info!("Controller Time:     {:?} ns", timer.ctrl_time.as_nanos() / iteration_number as u128);
         info!("DM Time:             {:?} ns", timer.dm_time.as_nanos() / iteration_number as u128);
+        info!("\nThroughput:          {:.1} frames/s", iteration_number as f64 / timer.total_time.as_secs_f64());
+        info!("Latency (last pixel to DM): {:?} ns mean, {:?} ns max",
+            timer.latency.as_nanos() / iteration_number as u128, timer.max_latency.as_nanos());
+    }
+}
+
+/// Splits `commands` between the DMs, each clipping its own slice to its
+/// stroke, and tells the controller what was applied if anything clipped.
+/// Returns the applied commands and the number clipped.
+fn apply_commands(
+        commands: &Array1<f32>, controller: &mut dyn Controller, dms: &Mutex<Vec<DM>>,
+        command_map: &Mutex<CommandMap>, saturated_actuators: &AtomicU64) -> (Array1<f32>, usize) {
+    let mut dms = dms.lock().unwrap();
+    let mut command_map = command_map.lock().unwrap();
+    let dm_commands = command_map.split(commands);
+    let n_saturated = dms.iter_mut().zip(dm_commands.iter())
+        .map(|(dm, dm_commands)| dm.set_actuators(dm_commands))
+        .sum::<usize>();
+    let applied = command_map.merge(&dms.iter().map(|dm| dm.get_actuators()).collect::<Vec<_>>());
+    if n_saturated > 0 {
+        trace!("{} actuator command(s) clipped", n_saturated);
+        saturated_actuators.fetch_add(n_saturated as u64, Ordering::Relaxed);
+        controller.apply_feedback(&applied);
+    }
+    (applied, n_saturated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stall_counted_once_until_recovered() {
+        let camera_stalls = AtomicU64::new(0);
+        let mut tracker = StallTracker::new(0);
+        tracker.frame_arrived();
+        assert!(!tracker.is_stalled());
+
+        for _ in 0..5 {
+            tracker.timed_out(Duration::from_millis(10), &camera_stalls);
+        }
+        assert!(tracker.is_stalled());
+        assert_eq!(camera_stalls.load(Ordering::Relaxed), 1);
+
+        tracker.frame_arrived();
+        assert!(!tracker.is_stalled());
+        tracker.timed_out(Duration::from_millis(10), &camera_stalls);
+        assert_eq!(camera_stalls.load(Ordering::Relaxed), 2);
     }
 }
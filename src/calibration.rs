@@ -0,0 +1,276 @@
+/// RUST-AO Calibration
+///
+/// Measures how the WFS responds to the DM by applying command patterns
+/// through `DM::set_actuators` and recording `WavefrontSensor::measure`.
+/// Works the same against real hardware and a simulated DM/WFS pair, as
+/// long as the camera keeps producing frames.
+///
+use ndarray::{Array1, Array2, s};
+use std::time::Duration;
+use log::{debug, info, warn};
+
+use crate::detector::Detector;
+use crate::fakedm::DM;
+use crate::linalg::pseudo_inverse;
+use crate::modes::hadamard;
+use crate::wfs::WavefrontSensor;
+
+/// Sets of command patterns the DM is driven through during calibration
+pub enum PokePattern {
+    /// One actuator at a time
+    Zonal,
+    /// Columns of a Hadamard matrix, so every actuator moves in every pattern
+    /// and the measurement noise is averaged over all of them
+    Hadamard,
+    /// Columns of an (n_acts, n_modes) modal basis, e.g. Zernike, KL or DM
+    /// eigenmodes. Each mode is applied scaled by the poke amplitude.
+    Modal(Array2<f32>),
+}
+
+impl PokePattern {
+    /// The patterns as the columns of an (n_acts, n_patterns) matrix
+    fn patterns(&self, n_acts: usize) -> Array2<f32> {
+        match self {
+            PokePattern::Zonal => Array2::<f32>::eye(n_acts),
+            PokePattern::Hadamard => hadamard(n_acts.next_power_of_two()).slice(s![..n_acts, ..]).to_owned(),
+            PokePattern::Modal(basis) => {
+                assert_eq!(basis.nrows(), n_acts, "PokePattern: modal basis has {} rows, DM has {} actuators", basis.nrows(), n_acts);
+                assert!(basis.ncols() > 0, "PokePattern: modal basis has no modes to poke");
+                basis.clone()
+            }
+        }
+    }
+
+    /// Matrix taking the responses to each pattern back to a zonal interaction matrix
+    fn decoder(&self, patterns: &Array2<f32>) -> Array2<f32> {
+        match self {
+            PokePattern::Zonal => Array2::<f32>::eye(patterns.nrows()),
+            // The rows of a Hadamard matrix are orthogonal
+            PokePattern::Hadamard => patterns.t().to_owned() / patterns.ncols() as f32,
+            PokePattern::Modal(_) => pseudo_inverse(&patterns.mapv(|x| x as f64), 1e-6).mapv(|x| x as f32),
+        }
+    }
+}
+
+pub struct CalibrationResult {
+    /// Zonal interaction matrix, (n_measurements, n_acts)
+    pub interaction_matrix: Array2<f32>,
+    /// Response to each pattern, (n_measurements, n_patterns)
+    pub pattern_responses: Array2<f32>,
+    /// Signal to noise ratio of each pattern's response, infinite when only
+    /// one frame is averaged and the noise cannot be estimated
+    pub pattern_snr: Array1<f32>,
+}
+
+pub struct PokeCalibration {
+    amplitude: f32,
+    n_frames: usize,
+    n_settle_frames: usize,
+    frame_timeout: Duration,
+}
+
+impl PokeCalibration {
+    /// Push-pull calibration with pokes of `amplitude`, averaging `n_frames`
+    /// WFS measurements for each push and each pull
+    pub fn new(amplitude: f32, n_frames: usize) -> Self {
+        assert!(n_frames > 0, "PokeCalibration: need at least one frame per poke");
+        Self {
+            amplitude: amplitude,
+            n_frames: n_frames,
+            n_settle_frames: 2,
+            frame_timeout: Duration::from_secs(1),
+        }
+    }
+
+    /// Frames thrown away after each poke while the DM and camera catch up
+    pub fn set_settle_frames(&mut self, n_settle_frames: usize) {
+        self.n_settle_frames = n_settle_frames;
+    }
+
+    pub fn set_frame_timeout(&mut self, frame_timeout: Duration) {
+        self.frame_timeout = frame_timeout;
+    }
+
+    /// Pokes each actuator in turn around the DM's current shape, returning the
+    /// (n_measurements, n_acts) interaction matrix in measurement units per unit
+    /// command. Returns `None` if the camera stops producing frames.
+    pub fn measure_interaction_matrix(&self, camera: &dyn Detector, wfs: &dyn WavefrontSensor, dm: &mut DM) -> Option<Array2<f32>> {
+        Some(self.calibrate(camera, wfs, dm, &PokePattern::Zonal)?.interaction_matrix)
+    }
+
+    /// Drives the DM through each pattern around its current shape and decodes
+    /// the responses into a zonal interaction matrix. Returns `None` if the
+    /// camera stops producing frames.
+    pub fn calibrate(&self, camera: &dyn Detector, wfs: &dyn WavefrontSensor, dm: &mut DM, pattern: &PokePattern) -> Option<CalibrationResult> {
+        let patterns = pattern.patterns(dm.n_acts);
+        let n_patterns = patterns.ncols();
+        info!("PokeCalibration: applying {} patterns to {} actuators, amplitude {}", n_patterns, dm.n_acts, self.amplitude);
+
+        let mut pattern_responses = Array2::<f32>::zeros((wfs.n_measurements(), n_patterns));
+        let mut pattern_snr = Array1::<f32>::zeros(n_patterns);
+        for p in 0..n_patterns {
+            let (response, noise) = self.measure_response_with_noise(camera, wfs, dm, &patterns.column(p).to_owned())?;
+            pattern_snr[p] = if noise > 0.0 { response.dot(&response).sqrt() / noise } else { f32::INFINITY };
+            pattern_responses.column_mut(p).assign(&response);
+            debug!("PokeCalibration: pattern {} SNR {}", p, pattern_snr[p]);
+        }
+
+        let mut sorted_snr = pattern_snr.to_vec();
+        sorted_snr.sort_by(|a, b| a.total_cmp(b));
+        info!("PokeCalibration: pattern SNR min {}, median {}, max {}",
+            sorted_snr[0], sorted_snr[n_patterns / 2], sorted_snr[n_patterns - 1]);
+
+        Some(CalibrationResult {
+            interaction_matrix: pattern_responses.dot(&pattern.decoder(&patterns)),
+            pattern_responses: pattern_responses,
+            pattern_snr: pattern_snr,
+        })
+    }
+
+    /// Push-pull response of the WFS to one command pattern, per unit of the pattern.
+    /// The DM is left in the shape it had before.
+    pub fn measure_response(&self, camera: &dyn Detector, wfs: &dyn WavefrontSensor, dm: &mut DM, pattern: &Array1<f32>) -> Option<Array1<f32>> {
+        Some(self.measure_response_with_noise(camera, wfs, dm, pattern)?.0)
+    }
+
+    /// Push-pull response and the RMS noise on it, estimated from the frame to
+    /// frame scatter of the measurements
+    fn measure_response_with_noise(&self, camera: &dyn Detector, wfs: &dyn WavefrontSensor, dm: &mut DM, pattern: &Array1<f32>) -> Option<(Array1<f32>, f32)> {
+        let flat = dm.get_actuators();
+
+        dm.set_actuators(&(&flat + &(self.amplitude * pattern)));
+        let push = self.average_measurements(camera, wfs);
+
+        dm.set_actuators(&(&flat - &(self.amplitude * pattern)));
+        let pull = self.average_measurements(camera, wfs);
+
+        dm.set_actuators(&flat);
+        let ((push, push_variance), (pull, pull_variance)) = (push?, pull?);
+
+        let scale = 2.0 * self.amplitude;
+        let noise = ((push_variance + pull_variance).sum()).sqrt() / scale.abs();
+        Some(((push - pull) / scale, noise))
+    }
+
+    /// Mean WFS measurement over `n_frames` new frames, after the settling frames,
+    /// and the variance of that mean for each measurement
+    fn average_measurements(&self, camera: &dyn Detector, wfs: &dyn WavefrontSensor) -> Option<(Array1<f32>, Array1<f32>)> {
+        let mut last_frame = camera.get_frame_number();
+        for _ in 0..self.n_settle_frames {
+            last_frame = self.wait_for_frame(camera, last_frame)?;
+        }
+
+        let mut total = Array1::<f32>::zeros(wfs.n_measurements());
+        let mut total_squared = Array1::<f32>::zeros(wfs.n_measurements());
+        for _ in 0..self.n_frames {
+            last_frame = self.wait_for_frame(camera, last_frame)?;
+            let frame = camera.latest_frame();
+            last_frame = last_frame.max(frame.sequence());
+            let measurements = wfs.measure(&frame.view());
+            total_squared += &measurements.mapv(|m| m * m);
+            total += &measurements;
+        }
+
+        let n = self.n_frames as f32;
+        let mean = total / n;
+        let variance = if self.n_frames > 1 {
+            (total_squared / n - mean.mapv(|m| m * m)).mapv(|v| v.max(0.0)) / (n - 1.0)
+        } else {
+            Array1::<f32>::zeros(wfs.n_measurements())
+        };
+        Some((mean, variance))
+    }
+
+    fn wait_for_frame(&self, camera: &dyn Detector, last_frame: u64) -> Option<u64> {
+        let frame_number = camera.wait_for_frame(last_frame, self.frame_timeout);
+        if frame_number.is_none() {
+            warn!("PokeCalibration: camera stalled, no new frame for {:?}", self.frame_timeout);
+        }
+        frame_number
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fakecamera::Camera;
+    use crate::wfs::ShackHartmann;
+    use crate::fakecamera::spotsim::{SpotSimulation, SpotProfile};
+    use crate::simulation::{ClosedLoopSimulation, StaticPhaseScreen};
+    use crate::simulation::influence::InfluenceFunctions;
+
+    /// Noise free camera, WFS and 3x3 actuator DM on a 2x2 subaperture bench,
+    /// and the model interaction matrix the calibration should recover
+    fn simulated_bench() -> (Camera, ShackHartmann, DM, Array2<f32>) {
+        let nx_subaps = 2;
+        let pixels_per_subap = 16;
+        let n_pupil = 16;
+        let n_pixels = nx_subaps * pixels_per_subap;
+        let mut subap_coordinates = Vec::new();
+        for x in 0..nx_subaps {
+            for y in 0..nx_subaps {
+                subap_coordinates.push(vec![
+                    x * pixels_per_subap, (x + 1) * pixels_per_subap,
+                    y * pixels_per_subap, (y + 1) * pixels_per_subap]);
+            }
+        }
+
+        let flat = Box::new(StaticPhaseScreen::new(Array2::<f32>::zeros((n_pupil, n_pupil))));
+        let mut sim = ClosedLoopSimulation::new(flat, n_pupil, nx_subaps, 2.0);
+        let dm_link = sim.add_dm(InfluenceFunctions::gaussian(3, 3, n_pupil, 0.2, 1.0));
+        let expected = sim.synthetic_interaction_matrix(0, 1.0);
+
+        let mut spots = SpotSimulation::new(
+            subap_coordinates.clone(), SpotProfile::Gaussian { fwhm: 2.0 }, 1e5, 0.0, Box::new(sim));
+        spots.set_photon_noise(false);
+        let mut cam = Camera::new(n_pixels, n_pixels, 0.0, 0.0);
+        cam.set_spot_simulation(spots);
+        cam.start_acquisition();
+
+        let wfs = ShackHartmann::new(n_pixels, n_pixels, pixels_per_subap, subap_coordinates, 0);
+        let mut dm = DM::new(9);
+        dm.connect_simulation(dm_link);
+        (cam, wfs, dm, expected)
+    }
+
+    fn assert_close(measured: &Array2<f32>, expected: &Array2<f32>) {
+        let error = (measured - expected).mapv(|x| x.abs()).fold(0.0_f32, |a, &b| a.max(b));
+        let scale = expected.mapv(|x| x.abs()).fold(0.0_f32, |a, &b| a.max(b));
+        assert!(error < 0.05 * scale, "error: {}, scale: {}", error, scale);
+    }
+
+    #[test]
+    fn test_poke_matches_simulation_model() {
+        let (mut cam, wfs, mut dm, expected) = simulated_bench();
+        let calibration = PokeCalibration::new(1.0, 2);
+        let measured = calibration.measure_interaction_matrix(&cam, &wfs, &mut dm).unwrap();
+        cam.stop_acquisition();
+
+        assert_close(&measured, &expected);
+        assert!(dm.get_actuators().iter().all(|&c| c == 0.0));
+    }
+
+    #[test]
+    fn test_hadamard_and_modal_decode_to_zonal() {
+        let (mut cam, wfs, mut dm, expected) = simulated_bench();
+        let calibration = PokeCalibration::new(0.5, 2);
+
+        let hadamard = calibration.calibrate(&cam, &wfs, &mut dm, &PokePattern::Hadamard).unwrap();
+        assert_eq!(hadamard.pattern_responses.ncols(), 16);
+        assert_close(&hadamard.interaction_matrix, &expected);
+
+        // 8 measurements see at most 8 of the 9 actuator modes; the rest are
+        // invisible to the WFS so leaving them out still recovers the matrix
+        let basis = crate::modes::dm_eigenmodes(&expected);
+        let modal = calibration.calibrate(&cam, &wfs, &mut dm, &PokePattern::Modal(basis)).unwrap();
+        cam.stop_acquisition();
+        assert_eq!(modal.pattern_snr.len(), 8);
+        assert_close(&modal.interaction_matrix, &expected);
+    }
+
+    #[test]
+    #[should_panic(expected = "no modes")]
+    fn test_empty_modal_basis() {
+        PokePattern::Modal(Array2::<f32>::zeros((9, 0))).patterns(9);
+    }
+}
@@ -0,0 +1,145 @@
+/// RUST-AO Command Map
+///
+/// Splits the controller's command vector into one slice per DM, in the
+/// order the DMs are given to `AOLoop`. Offloads can move correction from
+/// one DM to another over time, e.g. the low order content of a tweeter to
+/// a woofer, or the tilt of a DM to a tip-tilt mirror. An offload keeps the
+/// total correction unchanged, so the controller does not see it.
+///
+use std::ops::Range;
+use ndarray::{Array1, Array2, s};
+use log::info;
+
+use crate::linalg::pseudo_inverse;
+
+/// Moves correction from DM `from` to DM `to`. `equivalence` is
+/// (n_acts of `from`, n_acts of `to`): the `from` commands that make the same
+/// surface as a unit command on each `to` actuator.
+struct Offload {
+    from: usize,
+    to: usize,
+    equivalence: Array2<f32>,
+    /// Least squares projection of `from` commands onto `to` commands
+    projection: Array2<f32>,
+    gain: f32,
+    /// Correction accumulated on `to`, in `to` commands
+    offloaded: Array1<f32>,
+}
+
+pub struct CommandMap {
+    slices: Vec<Range<usize>>,
+    offloads: Vec<Offload>,
+}
+
+impl CommandMap {
+    /// Consecutive slices of the command vector for DMs with `dm_n_acts` actuators
+    pub fn new(dm_n_acts: &[usize]) -> Self {
+        let mut slices = Vec::new();
+        let mut start = 0;
+        for &n_acts in dm_n_acts {
+            slices.push(start..start + n_acts);
+            start += n_acts;
+        }
+        Self {
+            slices: slices,
+            offloads: Vec::new(),
+        }
+    }
+
+    pub fn n_dms(&self) -> usize {
+        self.slices.len()
+    }
+
+    /// Length of the controller command vector
+    pub fn n_commands(&self) -> usize {
+        self.slices.last().map(|s| s.end).unwrap_or(0)
+    }
+
+    pub fn n_acts(&self, dm_index: usize) -> usize {
+        self.slices[dm_index].len()
+    }
+
+    /// Offloads correction from DM `from` to DM `to` with `gain` per frame.
+    /// See `Offload` for `equivalence`; for a tip-tilt mirror it is the
+    /// `from` DM's tip and tilt patterns.
+    pub fn add_offload(&mut self, from: usize, to: usize, equivalence: Array2<f32>, gain: f32) {
+        assert!(from != to && from < self.n_dms() && to < self.n_dms(), "CommandMap: invalid offload from DM {} to DM {}", from, to);
+        assert_eq!(equivalence.dim(), (self.n_acts(from), self.n_acts(to)), "CommandMap: offload equivalence matrix has the wrong shape");
+        info!("CommandMap: offloading DM {} to DM {} with gain {}", from, to, gain);
+        self.offloads.push(Offload {
+            from: from,
+            to: to,
+            projection: pseudo_inverse(&equivalence.mapv(|x| x as f64), 1e-6).mapv(|x| x as f32),
+            equivalence: equivalence,
+            gain: gain,
+            offloaded: Array1::<f32>::zeros(self.n_acts(to)),
+        });
+    }
+
+    /// Commands for each DM. Each offload first takes a fraction of the
+    /// correction left on its `from` DM and moves it to its `to` DM.
+    pub fn split(&mut self, commands: &Array1<f32>) -> Vec<Array1<f32>> {
+        assert_eq!(commands.len(), self.n_commands(), "CommandMap: wrong number of commands");
+        let mut dm_commands = self.slices.iter().map(|slice| commands.slice(s![slice.clone()]).to_owned()).collect::<Vec<_>>();
+        for offload in self.offloads.iter_mut() {
+            let remaining = &dm_commands[offload.from] - &offload.equivalence.dot(&offload.offloaded);
+            offload.offloaded.scaled_add(offload.gain, &offload.projection.dot(&remaining));
+            dm_commands[offload.to] += &offload.offloaded;
+            dm_commands[offload.from] -= &offload.equivalence.dot(&offload.offloaded);
+        }
+        dm_commands
+    }
+
+    /// Inverse of `split`: the controller commands matching what each DM
+    /// actually applied, for anti-windup
+    pub fn merge(&self, dm_commands: &[Array1<f32>]) -> Array1<f32> {
+        assert_eq!(dm_commands.len(), self.n_dms(), "CommandMap: need commands for every DM");
+        let mut dm_commands = dm_commands.to_vec();
+        for offload in self.offloads.iter().rev() {
+            dm_commands[offload.from] += &offload.equivalence.dot(&offload.offloaded);
+            dm_commands[offload.to] -= &offload.offloaded;
+        }
+        let mut commands = Array1::<f32>::zeros(self.n_commands());
+        for (slice, dm) in self.slices.iter().zip(dm_commands.iter()) {
+            commands.slice_mut(s![slice.clone()]).assign(dm);
+        }
+        commands
+    }
+
+    /// Clears the offloaded correction
+    pub fn reset(&mut self) {
+        self.offloads.iter_mut().for_each(|o| o.offloaded.fill(0.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_split_and_merge() {
+        let mut map = CommandMap::new(&[2, 3]);
+        let commands = array![1.0, 2.0, 3.0, 4.0, 5.0];
+        let dm_commands = map.split(&commands);
+        assert_eq!(dm_commands, vec![array![1.0, 2.0], array![3.0, 4.0, 5.0]]);
+        assert_eq!(map.merge(&dm_commands), commands);
+    }
+
+    #[test]
+    fn test_tilt_offloaded_to_tip_tilt_mirror() {
+        // A 3 actuator DM whose tilt goes to a 1 axis tilt mirror
+        let mut map = CommandMap::new(&[3, 1]);
+        map.add_offload(0, 1, array![[-1.0], [0.0], [1.0]], 0.5);
+
+        let commands = array![-1.0, 0.5, 1.0, 0.0];
+        for _ in 0..50 {
+            map.split(&commands);
+        }
+        let dm_commands = map.split(&commands);
+        // All the tilt is on the tilt mirror and the DM keeps the rest
+        assert!((dm_commands[1][0] - 1.0).abs() < 1e-5);
+        assert!((&dm_commands[0] - &array![0.0, 0.5, 0.0]).iter().all(|x| x.abs() < 1e-5));
+        assert!((map.merge(&dm_commands) - &commands).iter().all(|x| x.abs() < 1e-5));
+    }
+}
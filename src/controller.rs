@@ -3,8 +3,107 @@
 /// Controller is responsible for taking WFS measurements, performing offsets,
 /// converting to the control basis and implementing a temporal control law 
 /// 
+/// Control laws implement the `Controller` trait so `AOLoop` can run any of
+/// them. `IntegratorController` is the default.
+///
+use std::fmt;
+use std::ops::Range;
+use ndarray::{Array1, Array2, s};
 
-use ndarray::{Array1, Array2};
+pub mod leakyintegrator;
+pub mod modal;
+pub mod polc;
+pub mod lqg;
+pub mod vibration;
+
+/// Value of a named controller parameter
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParameterValue {
+    Scalar(f32),
+    Vector(Array1<f32>),
+    Matrix(Array2<f32>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParameterError {
+    /// The controller has no parameter with this name
+    Unknown(String),
+    /// The value has the wrong type or shape for the parameter
+    Invalid(String),
+}
+
+impl fmt::Display for ParameterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParameterError::Unknown(name) => write!(f, "unknown controller parameter '{}'", name),
+            ParameterError::Invalid(reason) => write!(f, "invalid controller parameter: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ParameterError {}
+
+/// A temporal control law turning WFS measurements into DM commands
+pub trait Controller: Send {
+    fn n_measurements(&self) -> usize;
+
+    fn n_commands(&self) -> usize;
+
+    /// Runs one step of the control law, returning the commands to apply
+    fn compute_commands(&mut self, measurements: &Array1<f32>) -> Array1<f32>;
+
+    /// Clears the controller's state, e.g. the integrated commands
+    fn reset(&mut self);
+
+    /// Pipelined operation: starts a frame whose measurements will arrive in
+    /// blocks through `accumulate`, followed by `finish_frame`
+    fn begin_frame(&mut self) {}
+
+    /// Pipelined operation: the measurements in `updated` have arrived.
+    /// Controllers that can fold them into a partial product do it here so
+    /// little is left once the last block arrives.
+    fn accumulate(&mut self, _measurements: &Array1<f32>, _updated: &[Range<usize>]) {}
+
+    /// Pipelined operation: all measurements of the frame have arrived,
+    /// returns the commands as `compute_commands` would
+    fn finish_frame(&mut self, measurements: &Array1<f32>) -> Array1<f32> {
+        self.compute_commands(measurements)
+    }
+
+    /// Anti-windup: tells the controller the commands the mirror actually
+    /// took after clipping, so its state follows the mirror rather than
+    /// integrating past the stroke limits. Controllers without state can
+    /// ignore it.
+    fn apply_feedback(&mut self, _applied_commands: &Array1<f32>) {}
+
+    /// Modal coefficients behind the last commands, for controllers that work
+    /// in a modal basis. Published as telemetry alongside the commands.
+    fn modal_coefficients(&self) -> Option<Array1<f32>> {
+        None
+    }
+
+    /// Sets a named parameter, e.g. "gain" or "control_matrix". Safe to call
+    /// while the loop is running, it takes effect on the next iteration.
+    fn set_parameter(&mut self, name: &str, value: ParameterValue) -> Result<(), ParameterError>;
+
+    fn get_parameter(&self, name: &str) -> Result<ParameterValue, ParameterError>;
+}
+
+/// Checks a matrix parameter has the expected (rows, cols)
+pub fn check_matrix_shape(name: &str, matrix: &Array2<f32>, shape: (usize, usize)) -> Result<(), ParameterError> {
+    if matrix.dim() != shape {
+        return Err(ParameterError::Invalid(format!("{} must be {:?}, got {:?}", name, shape, matrix.dim())));
+    }
+    Ok(())
+}
+
+/// Checks a vector parameter has the expected length
+pub fn check_vector_length(name: &str, vector: &Array1<f32>, length: usize) -> Result<(), ParameterError> {
+    if vector.len() != length {
+        return Err(ParameterError::Invalid(format!("{} must have length {}, got {}", name, length, vector.len())));
+    }
+    Ok(())
+}
 
 pub struct IntegratorController {
     n_measurements: usize,
@@ -12,6 +111,12 @@ pub struct IntegratorController {
     gain: f32,
     control_matrix: Array2<f32>,
     actuator_commands: Array1<f32>,
+    /// Control matrix times the measurements accumulated so far this frame
+    partial_product: Array1<f32>,
+    /// Whether a pipelined frame has begun and not yet finished
+    in_frame: bool,
+    /// Control matrix set during a pipelined frame, used from the next one
+    pending_control_matrix: Option<Array2<f32>>,
 }
 
 impl IntegratorController {
@@ -23,15 +128,32 @@ impl IntegratorController {
             gain: gain,
             control_matrix: control_matrix,
             actuator_commands: Array1::<f32>::zeros(n_commands),
+            partial_product: Array1::<f32>::zeros(n_commands),
+            in_frame: false,
+            pending_control_matrix: None,
         }
     }
 
+    /// Takes effect straight away, or from the next frame if a pipelined
+    /// frame is in progress, so each frame uses a single control matrix
     pub fn set_control_matrix(&mut self, control_matrix: Array2<f32>) {
-        self.control_matrix = control_matrix;
+        if self.in_frame {
+            self.pending_control_matrix = Some(control_matrix);
+        } else {
+            self.control_matrix = control_matrix;
+        }
     }
 
+    /// The control matrix most recently set, even if it is still pending
     pub fn get_control_matrix(&self) -> Array2<f32> {
-        self.control_matrix.clone()
+        self.pending_control_matrix.as_ref().unwrap_or(&self.control_matrix).clone()
+    }
+
+    fn end_frame(&mut self) {
+        self.in_frame = false;
+        if let Some(control_matrix) = self.pending_control_matrix.take() {
+            self.control_matrix = control_matrix;
+        }
     }
 
     pub fn set_gain(&mut self, gain: f32) {
@@ -39,12 +161,142 @@ impl IntegratorController {
     }
 
     pub fn get_gain(&self) -> f32 {
-        return self.gain
+        self.gain
+    }
+}
+
+impl Controller for IntegratorController {
+    fn n_measurements(&self) -> usize {
+        self.n_measurements
     }
 
+    fn n_commands(&self) -> usize {
+        self.n_commands
+    }
 
-    pub fn compute_commands(&mut self, measurements: &Array1<f32>) -> Array1<f32> {
+    fn compute_commands(&mut self, measurements: &Array1<f32>) -> Array1<f32> {
+        // A pipelined frame that never finished leaves nothing to wait for
+        self.end_frame();
         self.actuator_commands = self.actuator_commands.clone() + self.gain * self.control_matrix.dot(measurements);
-        return self.actuator_commands.clone();
+        self.actuator_commands.clone()
+    }
+
+    fn reset(&mut self) {
+        self.actuator_commands.fill(0.0);
+    }
+
+    fn begin_frame(&mut self) {
+        // The previous frame may have been abandoned without finishing
+        self.end_frame();
+        self.in_frame = true;
+        self.partial_product.fill(0.0);
+    }
+
+    fn accumulate(&mut self, measurements: &Array1<f32>, updated: &[Range<usize>]) {
+        for range in updated {
+            let columns = self.control_matrix.slice(s![.., range.clone()]);
+            self.partial_product += &columns.dot(&measurements.slice(s![range.clone()]));
+        }
     }
-}
\ No newline at end of file
+
+    fn finish_frame(&mut self, _measurements: &Array1<f32>) -> Array1<f32> {
+        self.end_frame();
+        self.actuator_commands.scaled_add(self.gain, &self.partial_product);
+        self.actuator_commands.clone()
+    }
+
+    fn apply_feedback(&mut self, applied_commands: &Array1<f32>) {
+        self.actuator_commands.assign(applied_commands);
+    }
+
+    fn set_parameter(&mut self, name: &str, value: ParameterValue) -> Result<(), ParameterError> {
+        match (name, value) {
+            ("gain", ParameterValue::Scalar(gain)) => self.set_gain(gain),
+            ("control_matrix", ParameterValue::Matrix(control_matrix)) => {
+                check_matrix_shape(name, &control_matrix, (self.n_commands, self.n_measurements))?;
+                self.set_control_matrix(control_matrix);
+            }
+            ("gain", _) | ("control_matrix", _) => {
+                return Err(ParameterError::Invalid(format!("wrong type for {}", name)));
+            }
+            _ => return Err(ParameterError::Unknown(name.to_string())),
+        }
+        Ok(())
+    }
+
+    fn get_parameter(&self, name: &str) -> Result<ParameterValue, ParameterError> {
+        match name {
+            "gain" => Ok(ParameterValue::Scalar(self.gain)),
+            "control_matrix" => Ok(ParameterValue::Matrix(self.get_control_matrix())),
+            _ => Err(ParameterError::Unknown(name.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_integrator_parameters_and_reset() {
+        let mut controller: Box<dyn Controller> = Box::new(IntegratorController::new(2, 2, 1.0));
+        controller.set_parameter("control_matrix", ParameterValue::Matrix(Array2::eye(2))).unwrap();
+        controller.set_parameter("gain", ParameterValue::Scalar(0.5)).unwrap();
+        assert_eq!(controller.get_parameter("gain"), Ok(ParameterValue::Scalar(0.5)));
+
+        controller.compute_commands(&array![1.0, 2.0]);
+        assert_eq!(controller.compute_commands(&array![1.0, 2.0]), array![1.0, 2.0]);
+        controller.reset();
+        assert_eq!(controller.compute_commands(&array![0.0, 0.0]), array![0.0, 0.0]);
+
+        // After clipping the integrator continues from what the mirror did
+        controller.compute_commands(&array![4.0, 4.0]);
+        controller.apply_feedback(&array![1.0, 1.0]);
+        assert_eq!(controller.compute_commands(&array![0.0, 0.0]), array![1.0, 1.0]);
+
+        assert!(matches!(controller.set_parameter("leak", ParameterValue::Scalar(0.1)), Err(ParameterError::Unknown(_))));
+        assert!(matches!(controller.set_parameter("gain", ParameterValue::Vector(array![1.0])), Err(ParameterError::Invalid(_))));
+        assert!(matches!(
+            controller.set_parameter("control_matrix", ParameterValue::Matrix(Array2::eye(3))),
+            Err(ParameterError::Invalid(_))));
+    }
+
+    #[test]
+    fn test_pipelined_frame_matches_compute_commands() {
+        let control_matrix = array![[1.0, 2.0, 0.0, -1.0], [0.5, 0.0, 3.0, 1.0]];
+        let measurements = array![0.1, -0.2, 0.3, 0.4];
+        let mut sequential = IntegratorController::new(4, 2, 0.5);
+        let mut pipelined = IntegratorController::new(4, 2, 0.5);
+        sequential.set_control_matrix(control_matrix.clone());
+        pipelined.set_control_matrix(control_matrix);
+
+        for _ in 0..2 {
+            let expected = sequential.compute_commands(&measurements);
+            pipelined.begin_frame();
+            pipelined.accumulate(&measurements, &[2..3, 0..1]);
+            pipelined.accumulate(&measurements, &[1..2, 3..4]);
+            let commands = pipelined.finish_frame(&measurements);
+            assert!((commands - expected).iter().all(|d| d.abs() < 1e-6));
+        }
+    }
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn test_control_matrix_set_mid_frame_waits_for_next_frame() {
+        let measurements = array![1.0, 1.0];
+        let mut controller = IntegratorController::new(2, 1, 1.0);
+        controller.set_control_matrix(array![[1.0, 1.0]]);
+
+        controller.begin_frame();
+        controller.accumulate(&measurements, &[0..1]);
+        controller.set_parameter("control_matrix", ParameterValue::Matrix(array![[10.0, 10.0]])).unwrap();
+        assert_eq!(controller.get_control_matrix(), array![[10.0, 10.0]]);
+        controller.accumulate(&measurements, &[1..2]);
+        assert_eq!(controller.finish_frame(&measurements), array![2.0]);
+
+        controller.begin_frame();
+        controller.accumulate(&measurements, &[0..2]);
+        assert_eq!(controller.finish_frame(&measurements), array![22.0]);
+    }
+}
@@ -0,0 +1,265 @@
+/// RUST-AO Leaky Integrator
+///
+/// Integrator with its own gain and leak for every command, or for every
+/// mode of a modal basis when one is supplied:
+///
+/// ```text
+/// x[k] = (1 - leak) * x[k-1] + gain * B⁺ · CM · s[k]
+/// commands = B · x[k]
+/// ```
+///
+/// The leak pulls modes the WFS cannot see back towards zero instead of
+/// letting them drift until the DM saturates.
+///
+/// B⁺ is expensive to compute, so at runtime it is set first as
+/// `modal_basis_inverse`, computed with `LeakyIntegrator::basis_inverse`
+/// outside the controller lock, and then B as `modal_basis`.
+///
+use ndarray::{Array1, Array2};
+use log::info;
+
+use crate::controller::{Controller, ParameterError, ParameterValue, check_matrix_shape, check_vector_length};
+use crate::linalg::pseudo_inverse;
+
+pub struct LeakyIntegrator {
+    n_measurements: usize,
+    n_commands: usize,
+    default_gain: f32,
+    default_leak: f32,
+    control_matrix: Array2<f32>,
+    /// (n_commands, n_modes) basis and its pseudo-inverse, `None` to control each command
+    modal_basis: Option<(Array2<f32>, Array2<f32>)>,
+    /// Pseudo-inverse set through `set_parameter`, waiting for its basis
+    pending_inverse: Option<Array2<f32>>,
+    gains: Array1<f32>,
+    leaks: Array1<f32>,
+    state: Array1<f32>,
+}
+
+impl LeakyIntegrator {
+    /// Zonal leaky integrator with the same `gain` and `leak` on every command
+    pub fn new(n_measurements: usize, n_commands: usize, gain: f32, leak: f32) -> Self {
+        Self {
+            n_measurements: n_measurements,
+            n_commands: n_commands,
+            default_gain: gain,
+            default_leak: leak,
+            control_matrix: Array2::<f32>::zeros((n_commands, n_measurements)),
+            modal_basis: None,
+            pending_inverse: None,
+            gains: Array1::<f32>::from_elem(n_commands, gain),
+            leaks: Array1::<f32>::from_elem(n_commands, leak),
+            state: Array1::<f32>::zeros(n_commands),
+        }
+    }
+
+    /// Number of independently controlled commands or modes
+    pub fn n_modes(&self) -> usize {
+        self.state.len()
+    }
+
+    pub fn set_control_matrix(&mut self, control_matrix: Array2<f32>) {
+        assert_eq!(control_matrix.dim(), (self.n_commands, self.n_measurements), "LeakyIntegrator: control matrix has the wrong shape");
+        self.control_matrix = control_matrix;
+    }
+
+    /// Controls the columns of `basis`, (n_commands, n_modes), instead of the
+    /// individual commands. Commands outside the span of the basis are not
+    /// corrected. Resets the state and sets every mode back to the gain and
+    /// leak the controller was created with.
+    pub fn set_modal_basis(&mut self, basis: Array2<f32>) {
+        let inverse = Self::basis_inverse(&basis);
+        self.set_modal_basis_with_inverse(basis, inverse);
+    }
+
+    /// As `set_modal_basis`, with `inverse` the pseudo-inverse of `basis`
+    /// from `basis_inverse`, so a running controller need not compute it
+    pub fn set_modal_basis_with_inverse(&mut self, basis: Array2<f32>, inverse: Array2<f32>) {
+        assert_eq!(basis.nrows(), self.n_commands, "LeakyIntegrator: modal basis must have one row per command");
+        let n_modes = basis.ncols();
+        assert_eq!(inverse.dim(), (n_modes, self.n_commands), "LeakyIntegrator: modal basis inverse has the wrong shape");
+        info!("LeakyIntegrator: controlling {} modes", n_modes);
+        self.modal_basis = Some((basis, inverse));
+        self.reset_modes(n_modes);
+    }
+
+    /// Pseudo-inverse of a (n_commands, n_modes) modal basis
+    pub fn basis_inverse(basis: &Array2<f32>) -> Array2<f32> {
+        pseudo_inverse(&basis.mapv(|x| x as f64), 1e-6).mapv(|x| x as f32)
+    }
+
+    /// Goes back to controlling each command independently
+    pub fn clear_modal_basis(&mut self) {
+        self.modal_basis = None;
+        self.reset_modes(self.n_commands);
+    }
+
+    fn reset_modes(&mut self, n_modes: usize) {
+        self.gains = Array1::<f32>::from_elem(n_modes, self.default_gain);
+        self.leaks = Array1::<f32>::from_elem(n_modes, self.default_leak);
+        self.state = Array1::<f32>::zeros(n_modes);
+    }
+
+    /// One gain per mode (or command)
+    pub fn set_gains(&mut self, gains: Array1<f32>) {
+        assert_eq!(gains.len(), self.n_modes(), "LeakyIntegrator: need one gain per mode");
+        self.gains = gains;
+    }
+
+    /// One leak factor per mode (or command), 0 for a pure integrator
+    pub fn set_leaks(&mut self, leaks: Array1<f32>) {
+        assert_eq!(leaks.len(), self.n_modes(), "LeakyIntegrator: need one leak per mode");
+        self.leaks = leaks;
+    }
+
+    pub fn get_gains(&self) -> Array1<f32> {
+        self.gains.clone()
+    }
+
+    pub fn get_leaks(&self) -> Array1<f32> {
+        self.leaks.clone()
+    }
+
+    /// A scalar parameter applies to every mode, a vector must have one value per mode
+    fn per_mode_values(&self, name: &str, value: ParameterValue) -> Result<Array1<f32>, ParameterError> {
+        match value {
+            ParameterValue::Scalar(x) => Ok(Array1::<f32>::from_elem(self.n_modes(), x)),
+            ParameterValue::Vector(v) => {
+                check_vector_length(name, &v, self.n_modes())?;
+                Ok(v)
+            }
+            ParameterValue::Matrix(_) => Err(ParameterError::Invalid(format!("{} must be a scalar or vector", name))),
+        }
+    }
+}
+
+impl Controller for LeakyIntegrator {
+    fn n_measurements(&self) -> usize {
+        self.n_measurements
+    }
+
+    fn n_commands(&self) -> usize {
+        self.n_commands
+    }
+
+    fn compute_commands(&mut self, measurements: &Array1<f32>) -> Array1<f32> {
+        let command_update = self.control_matrix.dot(measurements);
+        let update = match &self.modal_basis {
+            Some((_, inverse)) => inverse.dot(&command_update),
+            None => command_update,
+        };
+        self.state = (1.0 - &self.leaks) * &self.state + &self.gains * &update;
+
+        match &self.modal_basis {
+            Some((basis, _)) => basis.dot(&self.state),
+            None => self.state.clone(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state.fill(0.0);
+    }
+
+    fn modal_coefficients(&self) -> Option<Array1<f32>> {
+        self.modal_basis.as_ref().map(|_| self.state.clone())
+    }
+
+    fn apply_feedback(&mut self, applied_commands: &Array1<f32>) {
+        self.state = match &self.modal_basis {
+            Some((_, inverse)) => inverse.dot(applied_commands),
+            None => applied_commands.clone(),
+        };
+    }
+
+    fn set_parameter(&mut self, name: &str, value: ParameterValue) -> Result<(), ParameterError> {
+        match name {
+            "gain" => self.gains = self.per_mode_values(name, value)?,
+            "leak" => self.leaks = self.per_mode_values(name, value)?,
+            "control_matrix" | "modal_basis" | "modal_basis_inverse" => {
+                let ParameterValue::Matrix(matrix) = value else {
+                    return Err(ParameterError::Invalid(format!("{} must be a matrix", name)));
+                };
+                if name == "control_matrix" {
+                    check_matrix_shape(name, &matrix, (self.n_commands, self.n_measurements))?;
+                    self.set_control_matrix(matrix);
+                } else if name == "modal_basis_inverse" {
+                    if matrix.ncols() != self.n_commands {
+                        return Err(ParameterError::Invalid(format!("modal_basis_inverse must have {} columns, got {}", self.n_commands, matrix.ncols())));
+                    }
+                    self.pending_inverse = Some(matrix);
+                } else {
+                    if matrix.nrows() != self.n_commands {
+                        return Err(ParameterError::Invalid(format!("modal_basis must have {} rows, got {}", self.n_commands, matrix.nrows())));
+                    }
+                    // The pseudo-inverse is too slow to compute here
+                    let Some(inverse) = self.pending_inverse.take() else {
+                        return Err(ParameterError::Invalid("set modal_basis_inverse before modal_basis".to_string()));
+                    };
+                    check_matrix_shape("modal_basis_inverse", &inverse, (matrix.ncols(), self.n_commands))?;
+                    self.set_modal_basis_with_inverse(matrix, inverse);
+                }
+            }
+            _ => return Err(ParameterError::Unknown(name.to_string())),
+        }
+        Ok(())
+    }
+
+    fn get_parameter(&self, name: &str) -> Result<ParameterValue, ParameterError> {
+        match name {
+            "gain" => Ok(ParameterValue::Vector(self.get_gains())),
+            "leak" => Ok(ParameterValue::Vector(self.get_leaks())),
+            "control_matrix" => Ok(ParameterValue::Matrix(self.control_matrix.clone())),
+            "modal_basis" => match &self.modal_basis {
+                Some((basis, _)) => Ok(ParameterValue::Matrix(basis.clone())),
+                None => Ok(ParameterValue::Matrix(Array2::<f32>::eye(self.n_commands))),
+            },
+            "modal_basis_inverse" => match &self.modal_basis {
+                Some((_, inverse)) => Ok(ParameterValue::Matrix(inverse.clone())),
+                None => Ok(ParameterValue::Matrix(Array2::<f32>::eye(self.n_commands))),
+            },
+            _ => Err(ParameterError::Unknown(name.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_per_command_gain_and_leak() {
+        let mut controller = LeakyIntegrator::new(2, 2, 1.0, 0.0);
+        controller.set_control_matrix(Array2::eye(2));
+        controller.set_parameter("gain", ParameterValue::Vector(array![1.0, 0.5])).unwrap();
+        controller.set_parameter("leak", ParameterValue::Vector(array![0.0, 0.5])).unwrap();
+
+        assert_eq!(controller.compute_commands(&array![1.0, 1.0]), array![1.0, 0.5]);
+        // With no signal the first command holds and the second leaks away
+        assert_eq!(controller.compute_commands(&array![0.0, 0.0]), array![1.0, 0.25]);
+        assert_eq!(controller.compute_commands(&array![0.0, 0.0]), array![1.0, 0.125]);
+    }
+
+    #[test]
+    fn test_modal_gains() {
+        // Modes: common and differential motion of two actuators
+        let basis = array![[1.0, 1.0], [1.0, -1.0]];
+        let mut controller = LeakyIntegrator::new(2, 2, 1.0, 0.0);
+        controller.set_control_matrix(Array2::eye(2));
+        assert!(matches!(
+            controller.set_parameter("modal_basis", ParameterValue::Matrix(basis.clone())),
+            Err(ParameterError::Invalid(_))));
+        let inverse = LeakyIntegrator::basis_inverse(&basis);
+        controller.set_parameter("modal_basis_inverse", ParameterValue::Matrix(inverse)).unwrap();
+        controller.set_parameter("modal_basis", ParameterValue::Matrix(basis)).unwrap();
+        controller.set_parameter("gain", ParameterValue::Vector(array![1.0, 0.0])).unwrap();
+
+        // A differential signal is ignored, a common one is integrated
+        let commands = controller.compute_commands(&array![1.0, -1.0]);
+        assert!(commands.iter().all(|c| c.abs() < 1e-6));
+        let commands = controller.compute_commands(&array![1.0, 1.0]);
+        assert!((commands - array![1.0, 1.0]).iter().all(|c| c.abs() < 1e-5));
+
+        assert!(controller.set_parameter("leak", ParameterValue::Vector(array![0.1])).is_err());
+    }
+}
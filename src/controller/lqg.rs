@@ -0,0 +1,371 @@
+/// RUST-AO LQG Controller
+///
+/// Linear-quadratic-Gaussian control in modal space. Each mode's open-loop
+/// turbulence (or vibration) is modelled as an independent AR(2) process
+///
+/// ```text
+/// phi[k] = a1 * phi[k-1] + a2 * phi[k-2] + w[k],    var(w) = q
+/// ```
+///
+/// observed through pseudo-open-loop modal measurements with noise of
+/// variance r. A steady-state Kalman filter estimates the state and the
+/// command for each mode is the state predicted `delay` frames ahead, which
+/// is optimal for minimising the residual variance. AR(1) models are AR(2)
+/// with a2 = 0.
+///
+/// The slopes-to-modes reconstructor follows the sign convention of
+/// `ModalController`: `R · s` is the residual phase in modal coefficients.
+///
+use std::collections::VecDeque;
+use ndarray::{Array1, Array2};
+use log::info;
+
+use crate::controller::{Controller, ParameterError, ParameterValue, check_matrix_shape, check_vector_length};
+use crate::linalg::pseudo_inverse;
+
+/// AR model of one mode's open-loop dynamics
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModeModel {
+    pub a1: f32,
+    pub a2: f32,
+    /// Variance of the driving noise w
+    pub excitation_variance: f32,
+    /// Variance of the modal measurement noise
+    pub noise_variance: f32,
+}
+
+impl ModeModel {
+    pub fn ar1(a: f32, excitation_variance: f32, noise_variance: f32) -> Self {
+        Self { a1: a, a2: 0.0, excitation_variance: excitation_variance, noise_variance: noise_variance }
+    }
+
+    pub fn ar2(a1: f32, a2: f32, excitation_variance: f32, noise_variance: f32) -> Self {
+        Self { a1: a1, a2: a2, excitation_variance: excitation_variance, noise_variance: noise_variance }
+    }
+
+    /// Lightly damped oscillator at `frequency` Hz, for a vibration seen at `frame_rate`
+    pub fn vibration(frequency: f32, damping: f32, frame_rate: f32, excitation_variance: f32, noise_variance: f32) -> Self {
+        let omega = 2.0 * std::f32::consts::PI * frequency / frame_rate;
+        let radius = (-damping * omega).exp();
+        Self::ar2(2.0 * radius * omega.cos(), -radius * radius, excitation_variance, noise_variance)
+    }
+
+    /// Fits an AR(`order`) model, order 1 or 2, to an open-loop modal time
+    /// series with the Yule-Walker equations. The measurement noise variance
+    /// cannot be separated from the series and must be given.
+    pub fn identify(series: &[f32], order: usize, noise_variance: f32) -> Self {
+        assert!(order == 1 || order == 2, "ModeModel: only AR(1) and AR(2) models are supported");
+        assert!(series.len() > order + 1, "ModeModel: series too short to identify");
+        let n = series.len();
+        let mean = series.iter().map(|&x| x as f64).sum::<f64>() / n as f64;
+        let autocovariance = |lag: usize| {
+            (lag..n).map(|i| (series[i] as f64 - mean) * (series[i - lag] as f64 - mean)).sum::<f64>() / n as f64
+        };
+        let (r0, r1, r2) = (autocovariance(0), autocovariance(1), autocovariance(2));
+        if r0 <= 0.0 {
+            return Self::ar1(0.0, 0.0, noise_variance);
+        }
+
+        // With |r1| close to r0, e.g. a drift much slower than the frame
+        // rate, the AR(2) equations are singular and the fit falls back to AR(1)
+        let determinant = r0 * r0 - r1 * r1;
+        let (a1, a2) = if order == 1 || determinant <= 1e-6 * r0 * r0 {
+            (r1 / r0, 0.0)
+        } else {
+            ((r1 * r0 - r1 * r2) / determinant, (r0 * r2 - r1 * r1) / determinant)
+        };
+        let excitation_variance = (r0 - a1 * r1 - a2 * r2).max(0.0);
+        Self::ar2(a1 as f32, a2 as f32, excitation_variance as f32, noise_variance)
+    }
+
+    fn transition(&self) -> [[f64; 2]; 2] {
+        [[self.a1 as f64, self.a2 as f64], [1.0, 0.0]]
+    }
+}
+
+/// Steady-state Kalman gain for a mode, from iterating the discrete
+/// algebraic Riccati equation for the prediction error covariance
+pub fn steady_state_kalman_gain(model: &ModeModel) -> [f32; 2] {
+    let a = model.transition();
+    let q = model.excitation_variance as f64;
+    let r = (model.noise_variance as f64).max(1e-12);
+
+    let mut p = [[q, 0.0], [0.0, 0.0]];
+    let mut gain = [0.0; 2];
+    for _ in 0..10000 {
+        // Measurement update: K = P C^T / (C P C^T + r), with C = [1, 0]
+        let innovation_variance = p[0][0] + r;
+        gain = [p[0][0] / innovation_variance, p[1][0] / innovation_variance];
+        let filtered = [
+            [p[0][0] - gain[0] * p[0][0], p[0][1] - gain[0] * p[0][1]],
+            [p[1][0] - gain[1] * p[0][0], p[1][1] - gain[1] * p[0][1]],
+        ];
+
+        // Time update: P = A P A^T + Q
+        let mut next = [[0.0; 2]; 2];
+        for i in 0..2 {
+            for j in 0..2 {
+                next[i][j] = (0..2).map(|k| (0..2).map(|l| a[i][k] * filtered[k][l] * a[j][l]).sum::<f64>()).sum();
+            }
+        }
+        next[0][0] += q;
+
+        let change = (0..2).flat_map(|i| (0..2).map(move |j| (i, j)))
+            .map(|(i, j)| (next[i][j] - p[i][j]).abs()).fold(0.0, f64::max);
+        p = next;
+        if change < 1e-12 * (1.0 + p[0][0].abs()) {
+            break;
+        }
+    }
+    [gain[0] as f32, gain[1] as f32]
+}
+
+pub struct LqgController {
+    n_measurements: usize,
+    n_commands: usize,
+    modes_to_commands: Array2<f32>,
+    /// Pseudo-inverse of `modes_to_commands`, for anti-windup
+    commands_to_modes: Array2<f32>,
+    slopes_to_modes: Array2<f32>,
+    models: Vec<ModeModel>,
+    kalman_gains: Vec<[f32; 2]>,
+    delay: usize,
+    /// Estimated [phi[k], phi[k-1]] of each mode
+    state: Vec<[f32; 2]>,
+    /// Modal commands returned by previous calls, most recent first
+    command_history: VecDeque<Array1<f32>>,
+}
+
+impl LqgController {
+    /// `modes_to_commands` is (n_commands, n_modes), `slopes_to_modes` is
+    /// (n_modes, n_measurements) and there is one model per mode. `delay` is
+    /// the number of frames between computing commands and seeing them in the
+    /// slopes, at least 1.
+    pub fn new(modes_to_commands: Array2<f32>, slopes_to_modes: Array2<f32>, models: Vec<ModeModel>, delay: usize) -> Self {
+        let (n_commands, n_modes) = modes_to_commands.dim();
+        assert_eq!(slopes_to_modes.nrows(), n_modes, "LqgController: reconstructor and modal basis disagree on the number of modes");
+        assert_eq!(models.len(), n_modes, "LqgController: need one model per mode");
+        assert!(delay >= 1, "LqgController: delay must be at least one frame");
+        let mut controller = Self {
+            n_measurements: slopes_to_modes.ncols(),
+            n_commands: n_commands,
+            commands_to_modes: pseudo_inverse(&modes_to_commands.mapv(|x| x as f64), 1e-6).mapv(|x| x as f32),
+            modes_to_commands: modes_to_commands,
+            slopes_to_modes: slopes_to_modes,
+            models: Vec::new(),
+            kalman_gains: Vec::new(),
+            delay: delay,
+            state: vec![[0.0; 2]; n_modes],
+            command_history: VecDeque::new(),
+        };
+        controller.set_models(models);
+        controller.reset();
+        controller
+    }
+
+    pub fn n_modes(&self) -> usize {
+        self.state.len()
+    }
+
+    /// Replaces the mode models and solves for the new Kalman gains
+    pub fn set_models(&mut self, models: Vec<ModeModel>) {
+        assert_eq!(models.len(), self.n_modes(), "LqgController: need one model per mode");
+        self.kalman_gains = models.iter().map(steady_state_kalman_gain).collect();
+        info!("LqgController: Kalman gains solved for {} modes", models.len());
+        self.models = models;
+    }
+
+    pub fn get_models(&self) -> Vec<ModeModel> {
+        self.models.clone()
+    }
+
+    pub fn kalman_gains(&self) -> Vec<[f32; 2]> {
+        self.kalman_gains.clone()
+    }
+
+    pub fn set_delay(&mut self, delay: usize) {
+        assert!(delay >= 1, "LqgController: delay must be at least one frame");
+        self.delay = delay;
+        self.command_history.resize(delay, Array1::<f32>::zeros(self.n_modes()));
+    }
+
+    fn update_models(&mut self, update: impl Fn(&mut ModeModel, f32), values: &Array1<f32>) {
+        let mut models = self.models.clone();
+        models.iter_mut().zip(values.iter()).for_each(|(model, &value)| update(model, value));
+        self.set_models(models);
+    }
+}
+
+impl Controller for LqgController {
+    fn n_measurements(&self) -> usize {
+        self.n_measurements
+    }
+
+    fn n_commands(&self) -> usize {
+        self.n_commands
+    }
+
+    fn compute_commands(&mut self, measurements: &Array1<f32>) -> Array1<f32> {
+        // Pseudo-open-loop modal measurement: residual plus the correction on the DM
+        let open_loop = self.slopes_to_modes.dot(measurements) + &self.command_history[self.delay - 1];
+
+        let mut modal_commands = Array1::<f32>::zeros(self.n_modes());
+        for mode in 0..self.n_modes() {
+            let model = &self.models[mode];
+            let gain = self.kalman_gains[mode];
+            let state = &mut self.state[mode];
+
+            // Predict from the last estimate, then correct with the measurement
+            let predicted = [model.a1 * state[0] + model.a2 * state[1], state[0]];
+            let innovation = open_loop[mode] - predicted[0];
+            *state = [predicted[0] + gain[0] * innovation, predicted[1] + gain[1] * innovation];
+
+            // The command lands `delay` frames from now
+            let mut ahead = *state;
+            for _ in 0..self.delay {
+                ahead = [model.a1 * ahead[0] + model.a2 * ahead[1], ahead[0]];
+            }
+            modal_commands[mode] = ahead[0];
+        }
+
+        self.command_history.pop_back();
+        self.command_history.push_front(modal_commands.clone());
+        self.modes_to_commands.dot(&modal_commands)
+    }
+
+    fn reset(&mut self) {
+        self.state.iter_mut().for_each(|s| *s = [0.0; 2]);
+        self.command_history.clear();
+        self.command_history.resize(self.delay, Array1::<f32>::zeros(self.n_modes()));
+    }
+
+    fn modal_coefficients(&self) -> Option<Array1<f32>> {
+        self.command_history.front().cloned()
+    }
+
+    fn apply_feedback(&mut self, applied_commands: &Array1<f32>) {
+        // The pseudo-open-loop measurement must add back what the mirror did
+        if let Some(latest) = self.command_history.front_mut() {
+            latest.assign(&self.commands_to_modes.dot(applied_commands));
+        }
+    }
+
+    fn set_parameter(&mut self, name: &str, value: ParameterValue) -> Result<(), ParameterError> {
+        match (name, value) {
+            ("delay", ParameterValue::Scalar(delay)) if delay >= 1.0 && delay.fract() == 0.0 => self.set_delay(delay as usize),
+            ("ar_coefficients", ParameterValue::Matrix(coefficients)) => {
+                check_matrix_shape(name, &coefficients, (self.n_modes(), 2))?;
+                let mut models = self.models.clone();
+                for (model, row) in models.iter_mut().zip(coefficients.rows()) {
+                    model.a1 = row[0];
+                    model.a2 = row[1];
+                }
+                self.set_models(models);
+            }
+            ("excitation_variance", ParameterValue::Vector(values)) => {
+                check_vector_length(name, &values, self.n_modes())?;
+                self.update_models(|model, v| model.excitation_variance = v, &values);
+            }
+            ("noise_variance", ParameterValue::Vector(values)) => {
+                check_vector_length(name, &values, self.n_modes())?;
+                self.update_models(|model, v| model.noise_variance = v, &values);
+            }
+            ("slopes_to_modes", ParameterValue::Matrix(matrix)) => {
+                check_matrix_shape(name, &matrix, (self.n_modes(), self.n_measurements))?;
+                self.slopes_to_modes = matrix;
+            }
+            ("delay" | "ar_coefficients" | "excitation_variance" | "noise_variance" | "slopes_to_modes", value) => {
+                return Err(ParameterError::Invalid(format!("{:?} is not a valid {}", value, name)));
+            }
+            _ => return Err(ParameterError::Unknown(name.to_string())),
+        }
+        Ok(())
+    }
+
+    fn get_parameter(&self, name: &str) -> Result<ParameterValue, ParameterError> {
+        match name {
+            "delay" => Ok(ParameterValue::Scalar(self.delay as f32)),
+            "ar_coefficients" => Ok(ParameterValue::Matrix(Array2::from_shape_fn((self.n_modes(), 2), |(mode, i)| {
+                if i == 0 { self.models[mode].a1 } else { self.models[mode].a2 }
+            }))),
+            "excitation_variance" => Ok(ParameterValue::Vector(self.models.iter().map(|m| m.excitation_variance).collect())),
+            "noise_variance" => Ok(ParameterValue::Vector(self.models.iter().map(|m| m.noise_variance).collect())),
+            "slopes_to_modes" => Ok(ParameterValue::Matrix(self.slopes_to_modes.clone())),
+            _ => Err(ParameterError::Unknown(name.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use rand_distr::{Distribution, Normal};
+    use crate::controller::IntegratorController;
+
+    fn simulate_ar2(model: &ModeModel, n: usize, seed: u64) -> Vec<f32> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let excitation = Normal::new(0.0, model.excitation_variance.sqrt()).unwrap();
+        let mut series = vec![0.0_f32; n];
+        for k in 2..n {
+            series[k] = model.a1 * series[k - 1] + model.a2 * series[k - 2] + excitation.sample(&mut rng);
+        }
+        series
+    }
+
+    /// Residual variance of a single mode in closed loop with `delay` frames of latency
+    fn closed_loop_variance(controller: &mut dyn Controller, disturbance: &[f32], delay: usize) -> f32 {
+        let mut on_dm = VecDeque::from(vec![0.0_f32; delay]);
+        let residuals = disturbance.iter().map(|&phi| {
+            let residual = phi - on_dm.back().unwrap();
+            on_dm.pop_back();
+            on_dm.push_front(controller.compute_commands(&array![residual])[0]);
+            residual
+        }).collect::<Vec<_>>();
+        let settled = &residuals[residuals.len() / 10..];
+        settled.iter().map(|r| r * r).sum::<f32>() / settled.len() as f32
+    }
+
+    #[test]
+    fn test_white_noise_kalman_gain() {
+        let gain = steady_state_kalman_gain(&ModeModel::ar1(0.0, 1.0, 0.25));
+        assert!((gain[0] - 0.8).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_yule_walker_identification() {
+        let model = ModeModel::vibration(20.0, 0.02, 500.0, 1.0, 0.0);
+        let identified = ModeModel::identify(&simulate_ar2(&model, 50000, 1), 2, 0.0);
+        assert!((identified.a1 - model.a1).abs() < 0.02, "{:?} vs {:?}", identified, model);
+        assert!((identified.a2 - model.a2).abs() < 0.02, "{:?} vs {:?}", identified, model);
+        assert!((identified.excitation_variance - 1.0).abs() < 0.05);
+    }
+
+    #[test]
+    fn test_slow_drift_falls_back_to_ar1() {
+        let series = (0..20000).map(|i| (2.0 * std::f32::consts::PI * i as f32 / 10000.0).sin()).collect::<Vec<_>>();
+        let identified = ModeModel::identify(&series, 2, 0.0);
+        assert_eq!(identified.a2, 0.0);
+        assert!((identified.a1 - 1.0).abs() < 1e-3, "{:?}", identified);
+    }
+
+    #[test]
+    fn test_lqg_rejects_vibration_better_than_integrator() {
+        let delay = 2;
+        let model = ModeModel::vibration(40.0, 0.01, 1000.0, 1e-3, 1e-6);
+        let disturbance = simulate_ar2(&model, 20000, 2);
+        let open_loop = disturbance.iter().map(|x| x * x).sum::<f32>() / disturbance.len() as f32;
+
+        let mut lqg = LqgController::new(Array2::eye(1), Array2::eye(1), vec![model], delay);
+        let lqg_variance = closed_loop_variance(&mut lqg, &disturbance, delay);
+
+        let mut integrator = IntegratorController::new(1, 1, 0.4);
+        integrator.set_control_matrix(Array2::eye(1));
+        let integrator_variance = closed_loop_variance(&mut integrator, &disturbance, delay);
+
+        assert!(lqg_variance < 0.1 * open_loop, "LQG {} vs open loop {}", lqg_variance, open_loop);
+        assert!(lqg_variance < 0.5 * integrator_variance, "LQG {} vs integrator {}", lqg_variance, integrator_variance);
+    }
+}
@@ -0,0 +1,221 @@
+/// RUST-AO Modal Controller
+///
+/// Integrator that runs in modal space. A slopes-to-modes reconstructor R
+/// gives the modal error each frame, each mode is integrated with its own
+/// gain, and a modes-to-commands matrix M projects the result onto the DM:
+///
+/// ```text
+/// modes[k] = modes[k-1] + gain * filter * R · s[k]
+/// commands = M · modes[k]
+/// ```
+///
+/// R follows the sign convention of `Reconstructor::control_matrix`, so the
+/// control matrix of the modal interaction matrix can be used directly.
+///
+/// The pseudo-inverse of M is expensive to compute, so at runtime it is set
+/// first as `commands_to_modes`, computed with `ModalController::basis_inverse`
+/// outside the controller lock, and then M as `modes_to_commands`.
+///
+use ndarray::{Array1, Array2};
+
+use crate::controller::{Controller, ParameterError, ParameterValue, check_matrix_shape, check_vector_length};
+use crate::linalg::pseudo_inverse;
+
+pub struct ModalController {
+    n_measurements: usize,
+    n_commands: usize,
+    modes_to_commands: Array2<f32>,
+    /// Pseudo-inverse of `modes_to_commands`, for anti-windup
+    commands_to_modes: Array2<f32>,
+    /// Pseudo-inverse set through `set_parameter`, waiting for its basis
+    pending_commands_to_modes: Option<Array2<f32>>,
+    slopes_to_modes: Array2<f32>,
+    gains: Array1<f32>,
+    /// 1 for controlled modes, 0 for filtered modes
+    mode_filter: Array1<f32>,
+    modal_coefficients: Array1<f32>,
+}
+
+impl ModalController {
+    /// `modes_to_commands` is (n_commands, n_modes) and `slopes_to_modes`
+    /// (n_modes, n_measurements). Every mode starts controlled with `gain`.
+    pub fn new(modes_to_commands: Array2<f32>, slopes_to_modes: Array2<f32>, gain: f32) -> Self {
+        let (n_commands, n_modes) = modes_to_commands.dim();
+        assert_eq!(slopes_to_modes.nrows(), n_modes, "ModalController: reconstructor and modal basis disagree on the number of modes");
+        Self {
+            n_measurements: slopes_to_modes.ncols(),
+            n_commands: n_commands,
+            commands_to_modes: Self::basis_inverse(&modes_to_commands),
+            pending_commands_to_modes: None,
+            modes_to_commands: modes_to_commands,
+            slopes_to_modes: slopes_to_modes,
+            gains: Array1::<f32>::from_elem(n_modes, gain),
+            mode_filter: Array1::<f32>::ones(n_modes),
+            modal_coefficients: Array1::<f32>::zeros(n_modes),
+        }
+    }
+
+    pub fn n_modes(&self) -> usize {
+        self.modal_coefficients.len()
+    }
+
+    /// Pseudo-inverse of a (n_commands, n_modes) modes-to-commands matrix
+    pub fn basis_inverse(modes_to_commands: &Array2<f32>) -> Array2<f32> {
+        pseudo_inverse(&modes_to_commands.mapv(|x| x as f64), 1e-6).mapv(|x| x as f32)
+    }
+
+    /// Replaces the modal basis, keeping the modal state. `commands_to_modes`
+    /// is its pseudo-inverse from `basis_inverse`, so a running controller
+    /// need not compute it.
+    pub fn set_modes_to_commands(&mut self, modes_to_commands: Array2<f32>, commands_to_modes: Array2<f32>) {
+        assert_eq!(modes_to_commands.dim(), (self.n_commands, self.n_modes()), "ModalController: modal basis has the wrong shape");
+        assert_eq!(commands_to_modes.dim(), (self.n_modes(), self.n_commands), "ModalController: modal basis inverse has the wrong shape");
+        self.modes_to_commands = modes_to_commands;
+        self.commands_to_modes = commands_to_modes;
+    }
+
+    pub fn set_gains(&mut self, gains: Array1<f32>) {
+        assert_eq!(gains.len(), self.n_modes(), "ModalController: need one gain per mode");
+        self.gains = gains;
+    }
+
+    pub fn get_gains(&self) -> Array1<f32> {
+        self.gains.clone()
+    }
+
+    /// Enables or disables control of each mode. A disabled mode is zeroed,
+    /// so it is removed from the DM straight away rather than left frozen.
+    pub fn set_mode_filter(&mut self, enabled: &[bool]) {
+        assert_eq!(enabled.len(), self.n_modes(), "ModalController: need one filter flag per mode");
+        self.mode_filter = Array1::from_iter(enabled.iter().map(|&e| if e { 1.0 } else { 0.0 }));
+        self.modal_coefficients *= &self.mode_filter;
+    }
+
+    /// Controls only the first `n_modes` modes
+    pub fn keep_first_modes(&mut self, n_modes: usize) {
+        let enabled = (0..self.n_modes()).map(|mode| mode < n_modes).collect::<Vec<_>>();
+        self.set_mode_filter(&enabled);
+    }
+
+    /// Replaces the reconstructor, e.g. after a new calibration, keeping the modal state
+    pub fn set_slopes_to_modes(&mut self, slopes_to_modes: Array2<f32>) {
+        assert_eq!(slopes_to_modes.dim(), (self.n_modes(), self.n_measurements), "ModalController: reconstructor has the wrong shape");
+        self.slopes_to_modes = slopes_to_modes;
+    }
+}
+
+impl Controller for ModalController {
+    fn n_measurements(&self) -> usize {
+        self.n_measurements
+    }
+
+    fn n_commands(&self) -> usize {
+        self.n_commands
+    }
+
+    fn compute_commands(&mut self, measurements: &Array1<f32>) -> Array1<f32> {
+        let modal_error = self.slopes_to_modes.dot(measurements);
+        self.modal_coefficients += &(&self.gains * &self.mode_filter * &modal_error);
+        self.modes_to_commands.dot(&self.modal_coefficients)
+    }
+
+    fn reset(&mut self) {
+        self.modal_coefficients.fill(0.0);
+    }
+
+    fn modal_coefficients(&self) -> Option<Array1<f32>> {
+        Some(self.modal_coefficients.clone())
+    }
+
+    fn apply_feedback(&mut self, applied_commands: &Array1<f32>) {
+        self.modal_coefficients = self.commands_to_modes.dot(applied_commands) * &self.mode_filter;
+    }
+
+    fn set_parameter(&mut self, name: &str, value: ParameterValue) -> Result<(), ParameterError> {
+        match (name, value) {
+            ("gain", ParameterValue::Scalar(gain)) => self.gains.fill(gain),
+            ("gain", ParameterValue::Vector(gains)) => {
+                check_vector_length(name, &gains, self.n_modes())?;
+                self.set_gains(gains);
+            }
+            ("mode_filter", ParameterValue::Vector(filter)) => {
+                check_vector_length(name, &filter, self.n_modes())?;
+                self.set_mode_filter(&filter.iter().map(|&f| f != 0.0).collect::<Vec<_>>());
+            }
+            ("slopes_to_modes", ParameterValue::Matrix(matrix)) => {
+                check_matrix_shape(name, &matrix, (self.n_modes(), self.n_measurements))?;
+                self.set_slopes_to_modes(matrix);
+            }
+            ("commands_to_modes", ParameterValue::Matrix(matrix)) => {
+                check_matrix_shape(name, &matrix, (self.n_modes(), self.n_commands))?;
+                self.pending_commands_to_modes = Some(matrix);
+            }
+            ("modes_to_commands", ParameterValue::Matrix(matrix)) => {
+                check_matrix_shape(name, &matrix, (self.n_commands, self.n_modes()))?;
+                // The pseudo-inverse is too slow to compute here
+                let Some(commands_to_modes) = self.pending_commands_to_modes.take() else {
+                    return Err(ParameterError::Invalid("set commands_to_modes before modes_to_commands".to_string()));
+                };
+                self.set_modes_to_commands(matrix, commands_to_modes);
+            }
+            ("gain", _) | ("mode_filter", _) | ("slopes_to_modes", _) | ("commands_to_modes", _) | ("modes_to_commands", _) => {
+                return Err(ParameterError::Invalid(format!("wrong type for {}", name)));
+            }
+            _ => return Err(ParameterError::Unknown(name.to_string())),
+        }
+        Ok(())
+    }
+
+    fn get_parameter(&self, name: &str) -> Result<ParameterValue, ParameterError> {
+        match name {
+            "gain" => Ok(ParameterValue::Vector(self.get_gains())),
+            "mode_filter" => Ok(ParameterValue::Vector(self.mode_filter.clone())),
+            "slopes_to_modes" => Ok(ParameterValue::Matrix(self.slopes_to_modes.clone())),
+            "modes_to_commands" => Ok(ParameterValue::Matrix(self.modes_to_commands.clone())),
+            "commands_to_modes" => Ok(ParameterValue::Matrix(self.commands_to_modes.clone())),
+            _ => Err(ParameterError::Unknown(name.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_modal_gains_and_filter() {
+        // Two actuators, common and differential modes, measured directly
+        let modes_to_commands = array![[1.0, 1.0], [1.0, -1.0]];
+        let mut controller = ModalController::new(modes_to_commands, Array2::eye(2), 1.0);
+        controller.set_gains(array![0.5, 1.0]);
+
+        let commands = controller.compute_commands(&array![2.0, 1.0]);
+        assert_eq!(controller.modal_coefficients(), Some(array![1.0, 1.0]));
+        assert_eq!(commands, array![2.0, 0.0]);
+
+        // Filtering the differential mode removes it from the DM at once
+        controller.keep_first_modes(1);
+        assert_eq!(controller.compute_commands(&array![0.0, 1.0]), array![1.0, 1.0]);
+
+        controller.set_parameter("mode_filter", ParameterValue::Vector(array![1.0, 1.0])).unwrap();
+        assert!(controller.set_parameter("gain", ParameterValue::Vector(array![1.0])).is_err());
+    }
+
+    #[test]
+    fn test_modes_to_commands_needs_its_inverse() {
+        let mut controller = ModalController::new(Array2::eye(2), Array2::eye(2), 1.0);
+        let modes_to_commands = array![[2.0, 0.0], [0.0, 4.0]];
+        assert!(matches!(
+            controller.set_parameter("modes_to_commands", ParameterValue::Matrix(modes_to_commands.clone())),
+            Err(ParameterError::Invalid(_))));
+
+        let commands_to_modes = ModalController::basis_inverse(&modes_to_commands);
+        controller.set_parameter("commands_to_modes", ParameterValue::Matrix(commands_to_modes)).unwrap();
+        controller.set_parameter("modes_to_commands", ParameterValue::Matrix(modes_to_commands)).unwrap();
+
+        // Anti-windup maps the applied commands back through the new inverse
+        controller.apply_feedback(&array![2.0, 4.0]);
+        assert!((controller.modal_coefficients().unwrap() - array![1.0, 1.0]).iter().all(|d| d.abs() < 1e-5));
+    }
+}
@@ -0,0 +1,235 @@
+/// RUST-AO Pseudo-Open-Loop Controller
+///
+/// Rebuilds the slopes the WFS would see without correction from the
+/// measured slopes and the commands that were on the DM when the frame was
+/// taken. The interaction matrix includes the sign of the DM, so
+///
+/// ```text
+/// s_pol[k] = s[k] - D · c[k - delay]
+/// ```
+///
+/// The open-loop correction `CM · s_pol` is then passed through an IIR
+/// temporal filter
+///
+/// ```text
+/// c[k] = Σ b[i] * (CM · s_pol)[k - i] + Σ a[j] * c[k - 1 - j]
+/// ```
+///
+/// which with b = [g] and a = [1 - g] is the usual integrator.
+///
+use std::collections::VecDeque;
+use ndarray::{Array1, Array2};
+
+use crate::controller::{Controller, IntegratorController, ParameterError, ParameterValue, check_matrix_shape};
+
+pub struct PolcController {
+    n_measurements: usize,
+    n_commands: usize,
+    /// (n_measurements, n_commands), slopes per unit command
+    interaction_matrix: Array2<f32>,
+    /// (n_commands, n_measurements), as from `Reconstructor::control_matrix`
+    control_matrix: Array2<f32>,
+    /// Frames between computing commands and seeing them in the slopes, at least 1
+    delay: usize,
+    filter_b: Vec<f32>,
+    filter_a: Vec<f32>,
+    /// Commands returned by previous calls, most recent first
+    command_history: VecDeque<Array1<f32>>,
+    /// Open-loop corrections of previous calls, most recent first
+    correction_history: VecDeque<Array1<f32>>,
+    pseudo_open_loop_slopes: Array1<f32>,
+}
+
+impl PolcController {
+    /// POLC integrator with `gain` and a one frame delay
+    pub fn new(interaction_matrix: Array2<f32>, control_matrix: Array2<f32>, gain: f32) -> Self {
+        let (n_measurements, n_commands) = interaction_matrix.dim();
+        assert_eq!(control_matrix.dim(), (n_commands, n_measurements), "PolcController: control matrix does not match interaction matrix");
+        let mut controller = Self {
+            n_measurements: n_measurements,
+            n_commands: n_commands,
+            interaction_matrix: interaction_matrix,
+            control_matrix: control_matrix,
+            delay: 1,
+            filter_b: vec![gain],
+            filter_a: vec![1.0 - gain],
+            command_history: VecDeque::new(),
+            correction_history: VecDeque::new(),
+            pseudo_open_loop_slopes: Array1::<f32>::zeros(n_measurements),
+        };
+        controller.reset();
+        controller
+    }
+
+    /// POLC around an existing integrator's control matrix and gain
+    pub fn from_integrator(integrator: &IntegratorController, interaction_matrix: Array2<f32>) -> Self {
+        Self::new(interaction_matrix, integrator.get_control_matrix(), integrator.get_gain())
+    }
+
+    pub fn set_interaction_matrix(&mut self, interaction_matrix: Array2<f32>) {
+        assert_eq!(interaction_matrix.dim(), (self.n_measurements, self.n_commands), "PolcController: interaction matrix has the wrong shape");
+        self.interaction_matrix = interaction_matrix;
+    }
+
+    pub fn set_control_matrix(&mut self, control_matrix: Array2<f32>) {
+        assert_eq!(control_matrix.dim(), (self.n_commands, self.n_measurements), "PolcController: control matrix has the wrong shape");
+        self.control_matrix = control_matrix;
+    }
+
+    /// Loop delay in frames. 1 means commands computed from frame k are on
+    /// the DM for frame k + 1.
+    pub fn set_delay(&mut self, delay: usize) {
+        assert!(delay >= 1, "PolcController: delay must be at least one frame");
+        self.delay = delay;
+        self.resize_histories();
+    }
+
+    /// IIR filter coefficients: `b` on the current and past open-loop
+    /// corrections, `a` on past commands
+    pub fn set_temporal_filter(&mut self, b: Vec<f32>, a: Vec<f32>) {
+        assert!(!b.is_empty(), "PolcController: filter needs at least one b coefficient");
+        self.filter_b = b;
+        self.filter_a = a;
+        self.resize_histories();
+    }
+
+    /// Pseudo-open-loop slopes reconstructed on the last call
+    pub fn pseudo_open_loop_slopes(&self) -> Array1<f32> {
+        self.pseudo_open_loop_slopes.clone()
+    }
+
+    fn resize_histories(&mut self) {
+        let n_commands = self.delay.max(self.filter_a.len());
+        self.command_history.resize(n_commands, Array1::<f32>::zeros(self.n_commands));
+        self.correction_history.resize(self.filter_b.len() - 1, Array1::<f32>::zeros(self.n_commands));
+    }
+}
+
+impl Controller for PolcController {
+    fn n_measurements(&self) -> usize {
+        self.n_measurements
+    }
+
+    fn n_commands(&self) -> usize {
+        self.n_commands
+    }
+
+    fn compute_commands(&mut self, measurements: &Array1<f32>) -> Array1<f32> {
+        let applied = &self.command_history[self.delay - 1];
+        self.pseudo_open_loop_slopes = measurements - &self.interaction_matrix.dot(applied);
+        let correction = self.control_matrix.dot(&self.pseudo_open_loop_slopes);
+
+        let mut commands = self.filter_b[0] * &correction;
+        for (b, past) in self.filter_b[1..].iter().zip(self.correction_history.iter()) {
+            commands.scaled_add(*b, past);
+        }
+        for (a, past) in self.filter_a.iter().zip(self.command_history.iter()) {
+            commands.scaled_add(*a, past);
+        }
+
+        if !self.correction_history.is_empty() {
+            self.correction_history.pop_back();
+            self.correction_history.push_front(correction);
+        }
+        self.command_history.pop_back();
+        self.command_history.push_front(commands.clone());
+        commands
+    }
+
+    fn apply_feedback(&mut self, applied_commands: &Array1<f32>) {
+        // Both the pseudo-open-loop slopes and the filter need what the mirror did
+        if let Some(latest) = self.command_history.front_mut() {
+            latest.assign(applied_commands);
+        }
+    }
+
+    fn reset(&mut self) {
+        self.command_history.clear();
+        self.correction_history.clear();
+        self.resize_histories();
+    }
+
+    fn set_parameter(&mut self, name: &str, value: ParameterValue) -> Result<(), ParameterError> {
+        match (name, value) {
+            ("gain", ParameterValue::Scalar(gain)) => self.set_temporal_filter(vec![gain], vec![1.0 - gain]),
+            ("delay", ParameterValue::Scalar(delay)) if delay >= 1.0 && delay.fract() == 0.0 => self.set_delay(delay as usize),
+            ("filter_b", ParameterValue::Vector(b)) if !b.is_empty() => self.set_temporal_filter(b.to_vec(), self.filter_a.clone()),
+            ("filter_a", ParameterValue::Vector(a)) => self.set_temporal_filter(self.filter_b.clone(), a.to_vec()),
+            ("interaction_matrix", ParameterValue::Matrix(matrix)) => {
+                check_matrix_shape(name, &matrix, (self.n_measurements, self.n_commands))?;
+                self.set_interaction_matrix(matrix);
+            }
+            ("control_matrix", ParameterValue::Matrix(matrix)) => {
+                check_matrix_shape(name, &matrix, (self.n_commands, self.n_measurements))?;
+                self.set_control_matrix(matrix);
+            }
+            ("gain" | "delay" | "filter_b" | "filter_a" | "interaction_matrix" | "control_matrix", value) => {
+                return Err(ParameterError::Invalid(format!("{:?} is not a valid {}", value, name)));
+            }
+            _ => return Err(ParameterError::Unknown(name.to_string())),
+        }
+        Ok(())
+    }
+
+    fn get_parameter(&self, name: &str) -> Result<ParameterValue, ParameterError> {
+        match name {
+            "delay" => Ok(ParameterValue::Scalar(self.delay as f32)),
+            "filter_b" => Ok(ParameterValue::Vector(Array1::from_vec(self.filter_b.clone()))),
+            "filter_a" => Ok(ParameterValue::Vector(Array1::from_vec(self.filter_a.clone()))),
+            "interaction_matrix" => Ok(ParameterValue::Matrix(self.interaction_matrix.clone())),
+            "control_matrix" => Ok(ParameterValue::Matrix(self.control_matrix.clone())),
+            _ => Err(ParameterError::Unknown(name.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    /// Runs a controller against a DM that subtracts its commands from a
+    /// static open-loop slope field, `delay` frames after they are computed
+    fn run(controller: &mut dyn Controller, open_loop: &Array1<f32>, delay: usize, n_frames: usize) -> Vec<Array1<f32>> {
+        let mut on_dm = VecDeque::from(vec![Array1::<f32>::zeros(open_loop.len()); delay]);
+        let mut residuals = Vec::new();
+        for _ in 0..n_frames {
+            let slopes = open_loop - on_dm.back().unwrap();
+            residuals.push(slopes.clone());
+            on_dm.push_front(controller.compute_commands(&slopes));
+            on_dm.pop_back();
+        }
+        residuals
+    }
+
+    #[test]
+    fn test_polc_rebuilds_open_loop_slopes() {
+        let interaction_matrix = -Array2::<f32>::eye(2);
+        let control_matrix = Array2::<f32>::eye(2);
+        let open_loop = array![1.0, -2.0];
+
+        // At gain 1 with a two frame delay, POLC corrects fully once the first
+        // commands reach the DM, where a plain integrator would oscillate
+        let mut polc = PolcController::new(interaction_matrix, control_matrix, 1.0);
+        polc.set_delay(2);
+        let residuals = run(&mut polc, &open_loop, 2, 6);
+        assert_eq!(residuals[0], open_loop);
+        assert!(residuals[2..].iter().all(|r| r.iter().all(|x| x.abs() < 1e-6)));
+        assert_eq!(polc.pseudo_open_loop_slopes(), open_loop);
+    }
+
+    #[test]
+    fn test_polc_filter_matches_integrator() {
+        let interaction_matrix = -Array2::<f32>::eye(2);
+        let mut integrator = IntegratorController::new(2, 2, 0.3);
+        integrator.set_control_matrix(Array2::eye(2));
+        let mut polc = PolcController::from_integrator(&integrator, interaction_matrix);
+
+        let open_loop = array![0.5, 1.0];
+        let polc_residuals = run(&mut polc, &open_loop, 1, 10);
+        let integrator_residuals = run(&mut integrator, &open_loop, 1, 10);
+        for (p, i) in polc_residuals.iter().zip(integrator_residuals.iter()) {
+            assert!((p - i).iter().all(|x| x.abs() < 1e-5));
+        }
+    }
+}
@@ -0,0 +1,384 @@
+/// RUST-AO Vibration Rejection
+///
+/// Wraps another controller and adds narrow-band resonant filters for
+/// vibrations on selected modes, typically tip and tilt. The residual of each
+/// monitored mode is kept in a history buffer so its PSD can be estimated
+/// (Welch) and vibration peaks picked out while the loop runs. A resonant
+/// filter at each peak then adds a correction at that frequency, which puts a
+/// notch in the rejection transfer function an integrator alone cannot.
+///
+/// Everything is driven through `set_parameter`, so identification and
+/// tuning happen between loop iterations without stopping the loop. Parameter
+/// names this controller does not know are passed to the inner controller.
+///
+use std::collections::VecDeque;
+use std::f32::consts::PI;
+use ndarray::{Array1, Array2};
+use rustfft::FftPlanner;
+use rustfft::num_complex::Complex;
+use log::info;
+
+use crate::controller::{Controller, ParameterError, ParameterValue};
+
+const HISTORY_LENGTH: usize = 4096;
+const SEGMENT_LENGTH: usize = 512;
+
+/// One-sided power spectral density by Welch's method with a Hann window and
+/// 50% overlapping segments. Returns (frequencies in Hz, PSD in units²/Hz).
+pub fn welch_psd(series: &[f32], segment_length: usize, sample_rate: f32) -> (Array1<f32>, Array1<f32>) {
+    assert!(series.len() >= segment_length, "welch_psd: series shorter than one segment");
+    let window = (0..segment_length)
+        .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / segment_length as f32).cos())
+        .collect::<Vec<_>>();
+    let window_power = window.iter().map(|w| w * w).sum::<f32>();
+    let fft = FftPlanner::<f32>::new().plan_fft_forward(segment_length);
+
+    let n_freqs = segment_length / 2 + 1;
+    let mut psd = Array1::<f32>::zeros(n_freqs);
+    let mut n_segments = 0;
+    let mut buffer = vec![Complex::new(0.0, 0.0); segment_length];
+    for start in (0..=series.len() - segment_length).step_by(segment_length / 2) {
+        let segment = &series[start..start + segment_length];
+        let mean = segment.iter().sum::<f32>() / segment_length as f32;
+        buffer.iter_mut().zip(segment.iter().zip(window.iter()))
+            .for_each(|(b, (&x, &w))| *b = Complex::new((x - mean) * w, 0.0));
+        fft.process(&mut buffer);
+        psd.iter_mut().zip(buffer.iter()).for_each(|(p, b)| *p += b.norm_sqr());
+        n_segments += 1;
+    }
+
+    // Double everything but DC and Nyquist to fold in the negative frequencies
+    psd /= n_segments as f32 * sample_rate * window_power;
+    psd.slice_mut(ndarray::s![1..n_freqs - 1]).mapv_inplace(|p| 2.0 * p);
+    let frequencies = Array1::from_shape_fn(n_freqs, |i| i as f32 * sample_rate / segment_length as f32);
+    (frequencies, psd)
+}
+
+/// Frequencies of the strongest local maxima of a PSD that stand more than
+/// `threshold` times above its median, at most `max_peaks` of them, refined
+/// by parabolic interpolation of the log PSD
+pub fn detect_peaks(frequencies: &Array1<f32>, psd: &Array1<f32>, threshold: f32, max_peaks: usize) -> Vec<f32> {
+    let n = psd.len();
+    if n < 3 {
+        return Vec::new();
+    }
+    let mut sorted = psd.to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let median = sorted[n / 2];
+
+    let mut peaks = (1..n - 1)
+        .filter(|&i| psd[i] > psd[i - 1] && psd[i] >= psd[i + 1] && psd[i] > threshold * median)
+        .collect::<Vec<_>>();
+    peaks.sort_by(|&a, &b| psd[b].total_cmp(&psd[a]));
+    peaks.truncate(max_peaks);
+
+    let bin_width = frequencies[1] - frequencies[0];
+    peaks.iter().map(|&i| {
+        let (left, centre, right) = (psd[i - 1].max(1e-30).ln(), psd[i].ln(), psd[i + 1].max(1e-30).ln());
+        let curvature = left - 2.0 * centre + right;
+        let offset = if curvature < 0.0 { 0.5 * (left - right) / curvature } else { 0.0 };
+        frequencies[i] + offset.clamp(-0.5, 0.5) * bin_width
+    }).collect()
+}
+
+/// Resonant filter with very high gain in a narrow band around `frequency`
+///
+/// ```text
+/// u[k] = 2 r cos(w) u[k-1] - r² u[k-2] + gain (cos(phi) e[k] - r cos(w - phi) e[k-1])
+/// ```
+///
+/// with w the normalised frequency and r = exp(-pi * bandwidth / frame_rate).
+/// The phase lead phi makes up for the loop delay at that frequency.
+#[derive(Debug, Clone)]
+pub struct ResonantFilter {
+    mode: usize,
+    frequency: f32,
+    gain: f32,
+    bandwidth: f32,
+    coefficients: [f32; 4],
+    inputs: [f32; 1],
+    outputs: [f32; 2],
+}
+
+impl ResonantFilter {
+    pub fn new(mode: usize, frequency: f32, gain: f32, bandwidth: f32, frame_rate: f32, delay: usize) -> Self {
+        let omega = 2.0 * PI * frequency / frame_rate;
+        let r = (-PI * bandwidth / frame_rate).exp();
+        let phase = omega * delay as f32;
+        Self {
+            mode: mode,
+            frequency: frequency,
+            gain: gain,
+            bandwidth: bandwidth,
+            coefficients: [2.0 * r * omega.cos(), -r * r, gain * phase.cos(), -gain * r * (omega - phase).cos()],
+            inputs: [0.0],
+            outputs: [0.0; 2],
+        }
+    }
+
+    fn step(&mut self, error: f32) -> f32 {
+        let [a1, a2, b0, b1] = self.coefficients;
+        let output = a1 * self.outputs[0] + a2 * self.outputs[1] + b0 * error + b1 * self.inputs[0];
+        self.inputs = [error];
+        self.outputs = [output, self.outputs[0]];
+        output
+    }
+
+    fn reset(&mut self) {
+        self.inputs = [0.0];
+        self.outputs = [0.0; 2];
+    }
+}
+
+/// A monitored mode: how to read it from the measurements and how to correct it
+struct VibrationMode {
+    /// Row vector giving the mode's residual from the measurements
+    projection: Array1<f32>,
+    /// Commands adding one unit of correction to the mode, with the same sign
+    /// convention as the inner controller
+    command_pattern: Array1<f32>,
+    history: VecDeque<f32>,
+}
+
+pub struct VibrationController {
+    inner: Box<dyn Controller>,
+    frame_rate: f32,
+    delay: usize,
+    modes: Vec<VibrationMode>,
+    filters: Vec<ResonantFilter>,
+    /// Commands the resonant filters added on the last call
+    last_correction: Array1<f32>,
+    filter_gain: f32,
+    filter_bandwidth: f32,
+    peak_threshold: f32,
+}
+
+impl VibrationController {
+    /// Adds vibration rejection to `inner`. `delay` is the loop delay in
+    /// frames, used to phase the resonant filters.
+    pub fn new(inner: Box<dyn Controller>, frame_rate: f32, delay: usize) -> Self {
+        Self {
+            last_correction: Array1::<f32>::zeros(inner.n_commands()),
+            inner: inner,
+            frame_rate: frame_rate,
+            delay: delay,
+            modes: Vec::new(),
+            filters: Vec::new(),
+            filter_gain: 0.05,
+            filter_bandwidth: 1.0,
+            peak_threshold: 20.0,
+        }
+    }
+
+    /// Monitors a mode, returning its index. `projection` has one entry per
+    /// measurement and `command_pattern` one per command.
+    pub fn add_mode(&mut self, projection: Array1<f32>, command_pattern: Array1<f32>) -> usize {
+        assert_eq!(projection.len(), self.inner.n_measurements(), "VibrationController: projection must have one entry per measurement");
+        assert_eq!(command_pattern.len(), self.inner.n_commands(), "VibrationController: command pattern must have one entry per command");
+        self.modes.push(VibrationMode {
+            projection: projection,
+            command_pattern: command_pattern,
+            history: VecDeque::with_capacity(HISTORY_LENGTH),
+        });
+        self.modes.len() - 1
+    }
+
+    pub fn n_modes(&self) -> usize {
+        self.modes.len()
+    }
+
+    /// PSD of the recent residual of `mode`, or `None` until enough frames are buffered
+    pub fn mode_psd(&self, mode: usize) -> Option<(Array1<f32>, Array1<f32>)> {
+        let history = &self.modes[mode].history;
+        if history.len() < SEGMENT_LENGTH {
+            return None;
+        }
+        let series = history.iter().copied().collect::<Vec<_>>();
+        Some(welch_psd(&series, SEGMENT_LENGTH, self.frame_rate))
+    }
+
+    /// Finds up to `max_peaks` vibrations on each monitored mode and replaces
+    /// the resonant filters with one per peak. Returns the number of filters.
+    pub fn identify_vibrations(&mut self, max_peaks: usize) -> usize {
+        let mut filters = Vec::new();
+        for mode in 0..self.n_modes() {
+            let Some((frequencies, psd)) = self.mode_psd(mode) else {
+                continue;
+            };
+            for frequency in detect_peaks(&frequencies, &psd, self.peak_threshold, max_peaks) {
+                info!("VibrationController: mode {} vibration at {:.2} Hz", mode, frequency);
+                filters.push(ResonantFilter::new(mode, frequency, self.filter_gain, self.filter_bandwidth, self.frame_rate, self.delay));
+            }
+        }
+        self.filters = filters;
+        self.filters.len()
+    }
+
+    pub fn add_filter(&mut self, mode: usize, frequency: f32, gain: f32, bandwidth: f32) {
+        assert!(mode < self.n_modes(), "VibrationController: no mode {}", mode);
+        self.filters.push(ResonantFilter::new(mode, frequency, gain, bandwidth, self.frame_rate, self.delay));
+    }
+
+    pub fn clear_filters(&mut self) {
+        self.filters.clear();
+    }
+
+    pub fn filters(&self) -> Vec<ResonantFilter> {
+        self.filters.clone()
+    }
+
+    /// Filters as rows of (mode, frequency, gain, bandwidth)
+    fn filter_table(&self) -> Array2<f32> {
+        Array2::from_shape_fn((self.filters.len(), 4), |(i, j)| {
+            let f = &self.filters[i];
+            [f.mode as f32, f.frequency, f.gain, f.bandwidth][j]
+        })
+    }
+}
+
+impl Controller for VibrationController {
+    fn n_measurements(&self) -> usize {
+        self.inner.n_measurements()
+    }
+
+    fn n_commands(&self) -> usize {
+        self.inner.n_commands()
+    }
+
+    fn compute_commands(&mut self, measurements: &Array1<f32>) -> Array1<f32> {
+        let mut commands = self.inner.compute_commands(measurements);
+
+        let errors = self.modes.iter_mut().map(|mode| {
+            let error = mode.projection.dot(measurements);
+            if mode.history.len() == HISTORY_LENGTH {
+                mode.history.pop_front();
+            }
+            mode.history.push_back(error);
+            error
+        }).collect::<Vec<_>>();
+
+        self.last_correction.fill(0.0);
+        for filter in self.filters.iter_mut() {
+            let correction = filter.step(errors[filter.mode]);
+            self.last_correction.scaled_add(correction, &self.modes[filter.mode].command_pattern);
+        }
+        commands += &self.last_correction;
+        commands
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset();
+        self.filters.iter_mut().for_each(|f| f.reset());
+        self.last_correction.fill(0.0);
+    }
+
+    fn modal_coefficients(&self) -> Option<Array1<f32>> {
+        self.inner.modal_coefficients()
+    }
+
+    fn apply_feedback(&mut self, applied_commands: &Array1<f32>) {
+        // The resonant filters are stable on their own, only the inner
+        // controller's state needs pulling back
+        self.inner.apply_feedback(&(applied_commands - &self.last_correction));
+    }
+
+    fn set_parameter(&mut self, name: &str, value: ParameterValue) -> Result<(), ParameterError> {
+        match (name, value) {
+            ("identify_vibrations", ParameterValue::Scalar(max_peaks)) if max_peaks >= 0.0 => {
+                self.identify_vibrations(max_peaks as usize);
+            }
+            ("vibration_gain", ParameterValue::Scalar(gain)) => self.filter_gain = gain,
+            ("vibration_bandwidth", ParameterValue::Scalar(bandwidth)) if bandwidth > 0.0 => self.filter_bandwidth = bandwidth,
+            ("vibration_threshold", ParameterValue::Scalar(threshold)) => self.peak_threshold = threshold,
+            ("vibration_filters", ParameterValue::Matrix(table)) => {
+                if table.ncols() != 4 || table.column(0).iter().any(|&m| m < 0.0 || m as usize >= self.n_modes()) {
+                    return Err(ParameterError::Invalid("vibration_filters rows must be (mode, frequency, gain, bandwidth)".to_string()));
+                }
+                self.clear_filters();
+                for row in table.rows() {
+                    self.add_filter(row[0] as usize, row[1], row[2], row[3]);
+                }
+            }
+            ("identify_vibrations" | "vibration_gain" | "vibration_bandwidth" | "vibration_threshold" | "vibration_filters", value) => {
+                return Err(ParameterError::Invalid(format!("{:?} is not a valid {}", value, name)));
+            }
+            (_, value) => return self.inner.set_parameter(name, value),
+        }
+        Ok(())
+    }
+
+    fn get_parameter(&self, name: &str) -> Result<ParameterValue, ParameterError> {
+        match name {
+            "vibration_gain" => Ok(ParameterValue::Scalar(self.filter_gain)),
+            "vibration_bandwidth" => Ok(ParameterValue::Scalar(self.filter_bandwidth)),
+            "vibration_threshold" => Ok(ParameterValue::Scalar(self.peak_threshold)),
+            "vibration_filters" => Ok(ParameterValue::Matrix(self.filter_table())),
+            _ => self.inner.get_parameter(name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+    use rand_distr::{Distribution, Normal};
+    use crate::controller::IntegratorController;
+
+    #[test]
+    fn test_welch_finds_sine() {
+        let frame_rate = 1000.0;
+        let mut rng = StdRng::seed_from_u64(4);
+        let noise = Normal::new(0.0, 0.01).unwrap();
+        let series = (0..4096)
+            .map(|k| (2.0 * PI * 123.0 * k as f32 / frame_rate).sin() + noise.sample(&mut rng))
+            .collect::<Vec<_>>();
+        let (frequencies, psd) = welch_psd(&series, 512, frame_rate);
+        let peaks = detect_peaks(&frequencies, &psd, 10.0, 3);
+        assert_eq!(peaks.len(), 1);
+        assert!((peaks[0] - 123.0).abs() < 0.5, "peak at {}", peaks[0]);
+
+        // A unit sine has a variance of 0.5, the noise adds 1e-4
+        let variance = psd.sum() * (frequencies[1] - frequencies[0]);
+        assert!((variance - 0.5).abs() < 0.05, "variance {}", variance);
+    }
+
+    #[test]
+    fn test_vibration_rejected_alongside_integrator() {
+        let frame_rate = 1000.0;
+        let delay = 1;
+        let mut integrator = IntegratorController::new(1, 1, 0.3);
+        integrator.set_control_matrix(Array2::eye(1));
+        let mut controller = VibrationController::new(Box::new(integrator), frame_rate, delay);
+        controller.add_mode(array![1.0], array![1.0]);
+
+        let mut rng = StdRng::seed_from_u64(3);
+        let noise = Normal::new(0.0, 0.05).unwrap();
+        let mut on_dm = 0.0;
+        let mut run = |controller: &mut VibrationController, start: usize, n: usize| {
+            let residuals = (start..start + n).map(|k| {
+                let disturbance = (2.0 * PI * 47.0 * k as f32 / frame_rate).sin() + noise.sample(&mut rng);
+                let residual = disturbance - on_dm;
+                on_dm = controller.compute_commands(&array![residual])[0];
+                residual
+            }).collect::<Vec<_>>();
+            let settled = &residuals[n / 2..];
+            settled.iter().map(|r| r * r).sum::<f32>() / settled.len() as f32
+        };
+
+        let before = run(&mut controller, 0, HISTORY_LENGTH);
+        controller.set_parameter("identify_vibrations", ParameterValue::Scalar(1.0)).unwrap();
+        let ParameterValue::Matrix(filters) = controller.get_parameter("vibration_filters").unwrap() else { panic!() };
+        assert_eq!(filters.nrows(), 1);
+        assert!((filters[[0, 1]] - 47.0).abs() < 1.0, "vibration at {}", filters[[0, 1]]);
+
+        let after = run(&mut controller, HISTORY_LENGTH, 8192);
+        assert!(after < 0.2 * before, "residual variance {} before, {} after", before, after);
+
+        // Unknown names reach the integrator
+        controller.set_parameter("gain", ParameterValue::Scalar(0.2)).unwrap();
+        assert_eq!(controller.get_parameter("gain"), Ok(ParameterValue::Scalar(0.2)));
+    }
+}
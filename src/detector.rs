@@ -0,0 +1,56 @@
+/// RUST-AO Detectors
+///
+/// Common interface for anything that produces detector frames for the
+/// AO loop: real cameras, simulators, file playback etc.
+/// `fakecamera::Camera` is one implementation.
+///
+use ndarray::Array2;
+use std::time::Duration;
+
+use crate::framering::{FrameRef, PartialFrameRef};
+
+/// The native pixel format of a detector. Frames are always delivered
+/// to the loop as `u16`, this records what the hardware actually produces.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelType {
+    UInt8,
+    UInt16,
+}
+
+pub trait Detector: Send + Sync {
+    /// Start producing frames
+    fn start_acquisition(&mut self);
+
+    /// Stop producing frames
+    fn stop_acquisition(&mut self);
+
+    /// Returns a copy of the most recent frame
+    fn get_frame(&self) -> Array2<u16>;
+
+    /// Borrows the most recent frame without copying it. Its sequence number
+    /// is the frame number, so gaps between calls show dropped frames.
+    fn latest_frame(&self) -> FrameRef<'_>;
+
+    /// Returns the number of frames produced since acquisition started
+    fn get_frame_number(&self) -> u64;
+
+    /// Blocks until a frame newer than `last_frame` is available and returns
+    /// its frame number, or `None` if nothing arrived within `timeout`
+    fn wait_for_frame(&self, last_frame: u64, timeout: Duration) -> Option<u64>;
+
+    /// Borrows the newest frame after `last_frame` as soon as it starts
+    /// reading out, so its top rows can be used before the rest arrive.
+    /// Detectors that deliver whole frames return the next complete frame.
+    fn wait_for_partial_frame(&self, last_frame: u64, timeout: Duration) -> Option<PartialFrameRef<'_>> {
+        self.wait_for_frame(last_frame, timeout)?;
+        Some(self.latest_frame().into())
+    }
+
+    /// Number of pixel rows in each frame
+    fn n_rows(&self) -> usize;
+
+    /// Number of pixel columns in each frame
+    fn n_cols(&self) -> usize;
+
+    fn pixel_type(&self) -> PixelType;
+}
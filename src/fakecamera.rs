@@ -1,99 +1,249 @@
-use ndarray::Array2;
+use ndarray::{Array2, s};
 use core::time;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::thread;
 use std::option;
+use std::time::Duration;
 use rand_distr::{Distribution, Normal};
 use rand::rng;
+use log::warn;
+
+pub mod spotsim;
+use spotsim::SpotSimulation;
+
+use crate::detector::{Detector, PixelType};
+use crate::framering::{FrameRef, FrameRing, PartialFrameRef};
+
+/// Number of frames the camera keeps in its ring buffer
+const FRAME_RING_SLOTS: usize = 4;
 
 pub struct Camera {
     pub n_rows: usize,
     pub n_cols: usize,
     frame_number: Arc<AtomicU64>,
+    /// Frames that could not be stored because every ring slot was borrowed
+    dropped_frames: Arc<AtomicU64>,
     acquiring: Arc<AtomicBool>,
-    thread_handle: option::Option<thread::JoinHandle<()>>,
-    frame_buffer: Arc<Mutex<Array2<u16>>>,
+    thread_handle: option::Option<thread::JoinHandle<Option<SpotSimulation>>>,
+    frame_ring: Arc<FrameRing>,
+    frame_ready: Arc<(Mutex<()>, Condvar)>,
     e_read_noise: f32,
     frame_rate: f32,
+    readout_rows: usize,
+    spot_simulation: Option<SpotSimulation>,
 }
 
 impl Camera {
     pub fn new(n_rows: usize, n_cols: usize, e_read_noise: f32, frame_rate: f32) -> Self {
-        // let frame_shape: Dim::<u32>::
-        let frame_shape = (n_rows, n_cols);
-        let frame_buffer = Arc::new(Mutex::new(
-            Array2::<u16>::zeros(frame_shape)
-        ));
+        let frame_ring = Arc::new(FrameRing::new(FRAME_RING_SLOTS, n_rows, n_cols));
         Self{
             n_rows: n_rows,
             n_cols: n_cols,
             frame_number: Arc::new(AtomicU64::new(0)),
+            dropped_frames: Arc::new(AtomicU64::new(0)),
             acquiring: Arc::new(AtomicBool::new(false)),
             thread_handle: None,
-            frame_buffer: frame_buffer,
+            frame_ring: frame_ring,
+            frame_ready: Arc::new((Mutex::new(()), Condvar::new())),
             e_read_noise: e_read_noise,
             frame_rate: frame_rate,
+            readout_rows: 0,
+            spot_simulation: None,
         }
     }
 
-    pub fn start_acquisition(&mut self) {
+    /// Render Shack-Hartmann spots, with photon noise, under the read noise.
+    /// Takes effect the next time acquisition is started.
+    pub fn set_spot_simulation(&mut self, spot_simulation: SpotSimulation) {
+        self.spot_simulation = Some(spot_simulation);
+    }
+
+    /// Read frames out `rows` rows at a time, spread over the frame period,
+    /// so rows can be processed before the frame completes. 0 delivers whole
+    /// frames. Takes effect the next time acquisition is started.
+    pub fn set_readout_rows(&mut self, rows: usize) {
+        self.readout_rows = rows;
+    }
+
+    /// Number of frames the camera dropped because the loop was holding
+    /// every slot of the frame ring. Dropped frames do not take a frame number.
+    pub fn get_dropped_frames(&self) -> u64 {
+        self.dropped_frames.load(Ordering::Relaxed)
+    }
+}
+
+impl Detector for Camera {
+    fn start_acquisition(&mut self) {
         println!("Start Acquisition");
         
         // Get some references to data in self
         let fn_ref = Arc::clone(&self.frame_number);
+        let dropped_ref = Arc::clone(&self.dropped_frames);
         let acq_ref = Arc::clone(&self.acquiring);
-        let fb_ref = Arc::clone(&self.frame_buffer);
+        let mut frame_writer = FrameRing::writer(&self.frame_ring)
+            .expect("Camera acquisition is already running");
+        let ready_ref = Arc::clone(&self.frame_ready);
         
-        let n_rows = self.n_rows;
-        let n_cols = self.n_cols;
-
         // Set acquiring to True until its set otherwise
         acq_ref.store(true, Ordering::Relaxed);
 
+        let (n_rows, n_cols) = (self.n_rows, self.n_cols);
         let frame_rate = self.frame_rate;
+        let readout_rows = self.readout_rows.min(n_rows);
+        let band_period = if frame_rate != 0.0 && readout_rows > 0 {
+            Duration::from_secs_f32(1.0 / frame_rate / n_rows.div_ceil(readout_rows) as f32)
+        } else {
+            Duration::ZERO
+        };
         let e_read_noise = self.e_read_noise;
+        let mut spot_simulation = self.spot_simulation.take();
 
         self.thread_handle = option::Option::Some(std::thread::spawn(move ||{
             let mut e_read_rng = rng();
             let normal = Normal::new(0.0, e_read_noise).unwrap();
-
-            let mut frame_buffer_tmp = Array2::<u16>::zeros((n_rows, n_cols));
+            let mut expected = Array2::<f32>::zeros((n_rows, n_cols));
             while acq_ref.load(Ordering::Relaxed) {
-                if frame_rate != 0.0 {
+                if frame_rate != 0.0 && readout_rows == 0 {
                     thread::sleep(time::Duration::from_millis((1000.0 / frame_rate) as u64));
                 }
 
-                let fr = fn_ref.load(Ordering::Relaxed);
-                fn_ref.store(fr+1, Ordering::Relaxed);
-
-                // Add read noise
-                for i in frame_buffer_tmp.iter_mut() {
-                    *i = normal.sample(&mut e_read_rng) as u16;
+                // Render straight into the next free slot of the ring
+                let frame_number = fn_ref.load(Ordering::Relaxed) + 1;
+                let written = if readout_rows > 0 {
+                    if let Some(sim) = spot_simulation.as_mut() {
+                        sim.render_expected(frame_number, &mut expected);
+                    }
+                    let sim = spot_simulation.as_ref();
+                    frame_writer.write_rows(frame_number, readout_rows, |row, mut band| {
+                        // Pace the bands over the frame period like a rolling readout
+                        thread::sleep(band_period);
+                        let expected = expected.slice(s![row..row + band.nrows(), ..]);
+                        band.iter_mut().zip(expected.iter()).for_each(|(i, &e)| {
+                            let signal = sim.map_or(0.0, |sim| sim.sample_pixel(e, &mut e_read_rng));
+                            *i = (signal + normal.sample(&mut e_read_rng)) as u16;
+                        });
+                    })
+                } else if let Some(sim) = spot_simulation.as_mut() {
+                    sim.render_expected(frame_number, &mut expected);
+                    frame_writer.write(frame_number, |mut frame| {
+                        frame.iter_mut().zip(expected.iter()).for_each(|(i, &e)| {
+                            *i = (sim.sample_pixel(e, &mut e_read_rng) + normal.sample(&mut e_read_rng)) as u16;
+                        });
+                    })
+                } else {
+                    frame_writer.write(frame_number, |mut frame| {
+                        // Read noise only
+                        for i in frame.iter_mut() {
+                            *i = normal.sample(&mut e_read_rng) as u16;
+                        }
+                    })
+                };
+                if !written {
+                    warn!("Camera: all frame slots in use, dropped frame {}", frame_number);
+                    dropped_ref.fetch_add(1, Ordering::Relaxed);
+                    // A rolling readout paces itself in the bands it skipped
+                    if frame_rate != 0.0 && readout_rows > 0 {
+                        thread::sleep(time::Duration::from_millis((1000.0 / frame_rate) as u64));
+                    }
+                    continue;
                 }
 
-                // copy tmp buffer to shared buffer
-                let mut frame_buffer = fb_ref.lock().unwrap();
-                frame_buffer.assign(&frame_buffer_tmp)
-
+                // Only bump the frame number once the frame is in place, then
+                // wake anyone waiting on it
+                let (lock, cvar) = &*ready_ref;
+                let _guard = lock.lock().unwrap();
+                fn_ref.store(frame_number, Ordering::Release);
+                cvar.notify_all();
             }
+            spot_simulation
         }));
     }
 
-    pub fn stop_acquisition(&mut self) {
+    fn stop_acquisition(&mut self) {
         println!("Stopping Acquisition...");
         self.acquiring.store(false, Ordering::Relaxed);
+        if let Some(handle) = self.thread_handle.take() {
+            self.spot_simulation = handle.join().unwrap();
+        }
         println!("Stopping Acquisition...Done");
     }
 
-    pub fn get_frame(&self) -> Array2<u16> {
-        let fb_ref = self.frame_buffer.clone();
-        let frame_buffer = fb_ref.lock().unwrap();
-        frame_buffer.clone()
-    }   
+    fn get_frame(&self) -> Array2<u16> {
+        self.frame_ring.latest().to_owned()
+    }
+
+    fn latest_frame(&self) -> FrameRef<'_> {
+        self.frame_ring.latest()
+    }
 
-    pub fn get_frame_number(&self) -> u64 {
+    fn get_frame_number(&self) -> u64 {
         self.frame_number.load(Ordering::Relaxed)
     }
 
+    fn wait_for_frame(&self, last_frame: u64, timeout: Duration) -> Option<u64> {
+        let (lock, cvar) = &*self.frame_ready;
+        let guard = lock.lock().unwrap();
+        let (_guard, result) = cvar.wait_timeout_while(guard, timeout, |_| {
+            self.frame_number.load(Ordering::Acquire) <= last_frame
+        }).unwrap();
+
+        if result.timed_out() {
+            None
+        } else {
+            Some(self.frame_number.load(Ordering::Acquire))
+        }
+    }
+
+    fn wait_for_partial_frame(&self, last_frame: u64, timeout: Duration) -> Option<PartialFrameRef<'_>> {
+        self.frame_ring.wait_newer(last_frame, timeout)
+    }
+
+    fn n_rows(&self) -> usize {
+        self.n_rows
+    }
+
+    fn n_cols(&self) -> usize {
+        self.n_cols
+    }
+
+    fn pixel_type(&self) -> PixelType {
+        PixelType::UInt16
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_no_frame_advertised_while_every_slot_is_held() {
+        let mut camera = Camera::new(4, 4, 1.0, 1000.0);
+        camera.start_acquisition();
+        {
+            // Pin one frame in every slot of the ring
+            let mut held = Vec::new();
+            let mut last_frame = camera.get_frame_number();
+            while held.len() < FRAME_RING_SLOTS {
+                last_frame = camera.wait_for_frame(last_frame, Duration::from_secs(1)).unwrap();
+                let frame = camera.latest_frame();
+                if held.iter().all(|held: &FrameRef| held.sequence() != frame.sequence()) {
+                    held.push(frame);
+                }
+            }
+
+            let frame_number = camera.get_frame_number();
+            assert_eq!(camera.wait_for_frame(frame_number, Duration::from_millis(50)), None);
+            assert_eq!(camera.get_frame_number(), frame_number);
+            assert_eq!(camera.latest_frame().sequence(), frame_number);
+            assert!(camera.get_dropped_frames() > 0);
+
+            // Frames resume once a slot is free
+            held.clear();
+            let next_frame = camera.wait_for_frame(frame_number, Duration::from_secs(1)).unwrap();
+            assert!(camera.latest_frame().sequence() >= next_frame);
+        }
+        camera.stop_acquisition();
+    }
 }
@@ -0,0 +1,249 @@
+/// Simulated Shack-Hartmann spot images for the fake camera
+///
+/// Renders one spot per subaperture, displaced from the subaperture centre by
+/// a slope vector in pixels, with a configurable flux and sky background. The
+/// slope vector uses the same layout as `ShackHartmann` measurements, all the x
+/// (row) slopes followed by all the y (column) slopes.
+///
+use ndarray::{Array1, Array2};
+use std::sync::{Arc, Mutex};
+use rand::Rng;
+use rand_distr::{Distribution, Poisson};
+
+/// Shape of the spot formed by each lenslet
+#[derive(Debug, Clone, Copy)]
+pub enum SpotProfile {
+    /// Gaussian spot with the given full width at half maximum in pixels
+    Gaussian { fwhm: f32 },
+    /// Airy pattern of a circular lenslet, `lambda_over_d` in pixels
+    Airy { lambda_over_d: f32 },
+}
+
+impl SpotProfile {
+    /// Unnormalised intensity at a distance `r` pixels from the spot centre
+    fn intensity(&self, r: f32) -> f32 {
+        match *self {
+            SpotProfile::Gaussian { fwhm } => {
+                let sigma = fwhm / 2.354_82;
+                (-r * r / (2.0 * sigma * sigma)).exp()
+            }
+            SpotProfile::Airy { lambda_over_d } => {
+                let x = std::f32::consts::PI * r / lambda_over_d;
+                if x.abs() < 1e-6 {
+                    1.0
+                } else {
+                    let a = 2.0 * bessel_j1(x) / x;
+                    a * a
+                }
+            }
+        }
+    }
+
+    /// Integral of `intensity` over the whole plane, in square pixels, so
+    /// a spot cut off by the subaperture edge keeps only its share of the flux
+    fn integral(&self) -> f32 {
+        match *self {
+            SpotProfile::Gaussian { fwhm } => {
+                let sigma = fwhm / 2.354_82;
+                2.0 * std::f32::consts::PI * sigma * sigma
+            }
+            // (2 J1(x) / x)^2 with x = pi r / (lambda / D) integrates to 4 (lambda / D)^2 / pi
+            SpotProfile::Airy { lambda_over_d } => 4.0 * lambda_over_d * lambda_over_d / std::f32::consts::PI,
+        }
+    }
+}
+
+/// Something that decides where the spots are for each frame
+pub trait SlopeSource: Send + Sync {
+    /// Returns the spot displacements, in pixels, for the given frame
+    fn next_slopes(&mut self, frame_number: u64) -> Array1<f32>;
+}
+
+/// Slopes set from outside the camera, e.g. by a test or a simulation.
+/// Clones share the same slope vector.
+#[derive(Clone)]
+pub struct InjectedSlopes {
+    slopes: Arc<Mutex<Array1<f32>>>,
+}
+
+impl InjectedSlopes {
+    pub fn new(n_subaps: usize) -> Self {
+        Self {
+            slopes: Arc::new(Mutex::new(Array1::<f32>::zeros(2 * n_subaps))),
+        }
+    }
+
+    pub fn set_slopes(&self, slopes: &Array1<f32>) {
+        self.slopes.lock().unwrap().assign(slopes);
+    }
+
+    pub fn get_slopes(&self) -> Array1<f32> {
+        self.slopes.lock().unwrap().clone()
+    }
+}
+
+impl SlopeSource for InjectedSlopes {
+    fn next_slopes(&mut self, _frame_number: u64) -> Array1<f32> {
+        self.get_slopes()
+    }
+}
+
+pub struct SpotSimulation {
+    subap_coordinates: Vec<Vec<usize>>,
+    profile: SpotProfile,
+    flux: f32,
+    background: f32,
+    photon_noise: bool,
+    slope_source: Box<dyn SlopeSource>,
+}
+
+impl SpotSimulation {
+    /// `subap_coordinates` are in the same `[row_start, row_end, col_start, col_end]`
+    /// form as `ShackHartmann`. `flux` is the photo-electrons per spot per frame,
+    /// of which a subaperture gets the part of the spot that falls on it, and
+    /// `background` the photo-electrons per pixel per frame.
+    pub fn new(
+            subap_coordinates: Vec<Vec<usize>>, profile: SpotProfile,
+            flux: f32, background: f32, slope_source: Box<dyn SlopeSource>) -> Self {
+        Self {
+            subap_coordinates: subap_coordinates,
+            profile: profile,
+            flux: flux,
+            background: background,
+            photon_noise: true,
+            slope_source: slope_source,
+        }
+    }
+
+    pub fn n_subaps(&self) -> usize {
+        self.subap_coordinates.len()
+    }
+
+    pub fn set_photon_noise(&mut self, photon_noise: bool) {
+        self.photon_noise = photon_noise;
+    }
+
+    pub fn set_flux(&mut self, flux: f32) {
+        self.flux = flux;
+    }
+
+    pub fn set_background(&mut self, background: f32) {
+        self.background = background;
+    }
+
+    /// Fills `expected` with the mean number of photo-electrons in each pixel
+    pub fn render_expected(&mut self, frame_number: u64, expected: &mut Array2<f32>) {
+        let n_subaps = self.n_subaps();
+        let slopes = self.slope_source.next_slopes(frame_number);
+        assert_eq!(slopes.len(), 2 * n_subaps, "SpotSimulation: expected {} slopes, got {}", 2 * n_subaps, slopes.len());
+
+        expected.fill(self.background);
+
+        for (i, coords) in self.subap_coordinates.iter().enumerate() {
+            let centre_row = (coords[0] + coords[1] - 1) as f32 / 2.0 + slopes[i];
+            let centre_col = (coords[2] + coords[3] - 1) as f32 / 2.0 + slopes[i + n_subaps];
+
+            // Nothing to draw if the slopes are not finite
+            if !(centre_row.is_finite() && centre_col.is_finite()) {
+                continue;
+            }
+
+            // Pixels sample the profile at their centres, normalised by the
+            // whole profile so only the light falling on the subaperture is drawn
+            let scale = self.flux / self.profile.integral();
+            for row in coords[0]..coords[1] {
+                for col in coords[2]..coords[3] {
+                    let r = ((row as f32 - centre_row).powi(2) + (col as f32 - centre_col).powi(2)).sqrt();
+                    expected[[row, col]] += scale * self.profile.intensity(r);
+                }
+            }
+        }
+    }
+
+    /// Draws the photo-electrons detected in a pixel given its expected value
+    pub fn sample_pixel<R: Rng>(&self, expected: f32, rng: &mut R) -> f32 {
+        if expected.is_nan() || expected <= 0.0 {
+            return 0.0;
+        }
+        if !self.photon_noise {
+            return expected;
+        }
+        Poisson::new(expected).map(|poisson| poisson.sample(rng)).unwrap_or(expected)
+    }
+}
+
+/// Bessel function of the first kind, order one. Polynomial approximation
+/// from Numerical Recipes, accurate to ~1e-8.
+fn bessel_j1(x: f32) -> f32 {
+    let x = x as f64;
+    let ax = x.abs();
+    let result = if ax < 8.0 {
+        let y = x * x;
+        let num = x * (72362614232.0 + y * (-7895059235.0 + y * (242396853.1
+            + y * (-2972611.439 + y * (15704.48260 + y * (-30.16036606))))));
+        let den = 144725228442.0 + y * (2300535178.0 + y * (18583304.74
+            + y * (99447.43394 + y * (376.9991397 + y))));
+        num / den
+    } else {
+        let z = 8.0 / ax;
+        let y = z * z;
+        let xx = ax - 2.356194491;
+        let p = 1.0 + y * (0.183105e-2 + y * (-0.3516396496e-4
+            + y * (0.2457520174e-5 + y * (-0.240337019e-6))));
+        let q = 0.04687499995 + y * (-0.2002690873e-3
+            + y * (0.8449199096e-5 + y * (-0.88228987e-6 + y * 0.105787412e-6)));
+        let ans = (std::f64::consts::FRAC_2_PI / ax).sqrt() * (xx.cos() * p - z * xx.sin() * q);
+        if x < 0.0 { -ans } else { ans }
+    };
+    result as f32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::{array, s};
+    use crate::wfs::centreofgravity::simple_centre_of_gravity;
+
+    #[test]
+    fn test_spot_position_follows_slopes() {
+        let subap_coordinates = vec![vec![0, 16, 0, 16], vec![0, 16, 16, 32]];
+        let slopes = InjectedSlopes::new(2);
+        slopes.set_slopes(&array![1.5, -2.0, 0.5, 1.0]);
+
+        let mut sim = SpotSimulation::new(
+            subap_coordinates, SpotProfile::Gaussian { fwhm: 2.0 }, 1000.0, 0.0, Box::new(slopes));
+        let mut expected = Array2::<f32>::zeros((16, 32));
+        sim.render_expected(0, &mut expected);
+
+        let (x, y) = simple_centre_of_gravity(&expected.slice(s![.., 0..16]).to_owned());
+        assert!((x - 1.5).abs() < 0.01, "x: {}", x);
+        assert!((y - 0.5).abs() < 0.01, "y: {}", y);
+
+        let (x, y) = simple_centre_of_gravity(&expected.slice(s![.., 16..32]).to_owned());
+        assert!((x + 2.0).abs() < 0.01, "x: {}", x);
+        assert!((y - 1.0).abs() < 0.01, "y: {}", y);
+    }
+
+    #[test]
+    fn test_spot_flux() {
+        let slopes = InjectedSlopes::new(1);
+        let mut sim = SpotSimulation::new(
+            vec![vec![0, 16, 0, 16]], SpotProfile::Gaussian { fwhm: 2.0 }, 500.0, 2.0, Box::new(slopes.clone()));
+        let mut expected = Array2::<f32>::zeros((16, 16));
+        sim.render_expected(0, &mut expected);
+        assert!((expected.sum() - (500.0 + 256.0 * 2.0)).abs() < 0.5, "{}", expected.sum());
+
+        // A spot centred on the subaperture edge puts half its light outside
+        slopes.set_slopes(&array![8.0, 0.0]);
+        sim.set_background(0.0);
+        sim.render_expected(0, &mut expected);
+        assert!((expected.sum() - 250.0).abs() < 1.0, "{}", expected.sum());
+
+        // The Airy rings reach past a small subaperture
+        let mut sim = SpotSimulation::new(
+            vec![vec![0, 8, 0, 8]], SpotProfile::Airy { lambda_over_d: 2.0 }, 500.0, 0.0, Box::new(InjectedSlopes::new(1)));
+        let mut expected = Array2::<f32>::zeros((8, 8));
+        sim.render_expected(0, &mut expected);
+        assert!(expected.sum() > 400.0 && expected.sum() < 495.0, "{}", expected.sum());
+    }
+}
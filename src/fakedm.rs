@@ -1,8 +1,14 @@
 use ndarray::Array1;
+use std::sync::{Arc, Mutex};
 
 pub struct DM {
     pub n_acts: usize,
     act_buffer: Array1<f32>,
+    simulation_link: Option<Arc<Mutex<Array1<f32>>>>,
+    /// Lowest and highest command each actuator accepts
+    stroke_min: Array1<f32>,
+    stroke_max: Array1<f32>,
+    n_saturated: usize,
 }
 
 impl DM {
@@ -12,14 +18,96 @@ impl DM {
         Self{
             n_acts: n_acts,
             act_buffer: act_buffer,
+            simulation_link: None,
+            stroke_min: Array1::<f32>::from_elem(n_acts, f32::NEG_INFINITY),
+            stroke_max: Array1::<f32>::from_elem(n_acts, f32::INFINITY),
+            n_saturated: 0,
         }
     }
 
-    pub fn set_actuators(&mut self, actuator_values: &Array1<f32>) {
-        actuator_values.clone_into(&mut self.act_buffer);
+    /// Per actuator stroke limits. Commands outside them are clipped before
+    /// they reach the mirror.
+    pub fn set_stroke_limits(&mut self, stroke_min: Array1<f32>, stroke_max: Array1<f32>) {
+        assert_eq!(stroke_min.len(), self.n_acts, "DM: need one lower stroke limit per actuator");
+        assert_eq!(stroke_max.len(), self.n_acts, "DM: need one upper stroke limit per actuator");
+        assert!(stroke_min.iter().zip(stroke_max.iter()).all(|(lo, hi)| lo <= hi), "DM: lower stroke limit above upper limit");
+        self.stroke_min = stroke_min;
+        self.stroke_max = stroke_max;
+    }
+
+    /// Same symmetric limit of +/- `stroke` on every actuator
+    pub fn set_uniform_stroke_limit(&mut self, stroke: f32) {
+        self.set_stroke_limits(
+            Array1::<f32>::from_elem(self.n_acts, -stroke.abs()),
+            Array1::<f32>::from_elem(self.n_acts, stroke.abs()));
+    }
+
+    pub fn get_stroke_limits(&self) -> (Array1<f32>, Array1<f32>) {
+        (self.stroke_min.clone(), self.stroke_max.clone())
+    }
+
+    /// Commands clipped to the stroke limits and the number of actuators that
+    /// had to be clipped. A NaN command counts as saturated and is replaced
+    /// by 0, clipped into range.
+    pub fn clip(&self, actuator_values: &Array1<f32>) -> (Array1<f32>, usize) {
+        assert_eq!(actuator_values.len(), self.n_acts, "DM: wrong number of commands");
+        let mut n_saturated = 0;
+        let clipped = Array1::from_iter(actuator_values.iter().zip(self.stroke_min.iter().zip(self.stroke_max.iter()))
+            .map(|(&value, (&lo, &hi))| {
+                if value.is_nan() {
+                    n_saturated += 1;
+                    0.0_f32.clamp(lo, hi)
+                } else if value < lo || value > hi {
+                    n_saturated += 1;
+                    value.clamp(lo, hi)
+                } else {
+                    value
+                }
+            }));
+        (clipped, n_saturated)
+    }
+
+    /// Forward every command set on this DM to a simulated mirror, see
+    /// `ClosedLoopSimulation::add_dm`
+    pub fn connect_simulation(&mut self, simulation_link: Arc<Mutex<Array1<f32>>>) {
+        assert_eq!(simulation_link.lock().unwrap().len(), self.n_acts, "DM: simulated mirror has a different number of actuators");
+        self.simulation_link = Some(simulation_link);
+    }
+
+    /// Sends commands to the mirror, clipped to the stroke limits. Returns the
+    /// number of actuators that were clipped.
+    pub fn set_actuators(&mut self, actuator_values: &Array1<f32>) -> usize {
+        let (clipped, n_saturated) = self.clip(actuator_values);
+        self.act_buffer = clipped;
+        self.n_saturated = n_saturated;
+        if let Some(link) = &self.simulation_link {
+            link.lock().unwrap().assign(&self.act_buffer);
+        }
+        n_saturated
+    }
+
+    /// Number of actuators clipped by the last `set_actuators`
+    pub fn get_n_saturated(&self) -> usize {
+        self.n_saturated
     }
 
     pub fn get_actuators(&self) -> Array1<f32> {
         self.act_buffer.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_stroke_limits_clip_commands() {
+        let mut dm = DM::new(3);
+        dm.set_stroke_limits(array![-1.0, -1.0, 0.0], array![1.0, 1.0, 0.5]);
+        assert_eq!(dm.set_actuators(&array![0.5, -3.0, f32::NAN]), 2);
+        assert_eq!(dm.get_actuators(), array![0.5, -1.0, 0.0]);
+        assert_eq!(dm.get_n_saturated(), 2);
+        assert_eq!(dm.set_actuators(&array![0.0, 0.0, 0.25]), 0);
+    }
+}
@@ -0,0 +1,54 @@
+/// FFT helpers for ndarray arrays, built on rustfft.
+///
+/// Transforms are unnormalised in both directions, as in rustfft, so an
+/// `fft2` followed by an `ifft2` scales the data by the number of elements.
+///
+use std::cell::RefCell;
+use ndarray::Array2;
+use rustfft::{FftDirection, FftPlanner};
+use rustfft::num_complex::Complex;
+
+thread_local! {
+    /// Plans are cached by the planner, so keep one per thread
+    static FFT_PLANNER: RefCell<FftPlanner<f32>> = RefCell::new(FftPlanner::new());
+}
+
+pub fn fft2(data: &mut Array2<Complex<f32>>) {
+    fft2_direction(data, FftDirection::Forward);
+}
+
+pub fn ifft2(data: &mut Array2<Complex<f32>>) {
+    fft2_direction(data, FftDirection::Inverse);
+}
+
+fn fft2_direction(data: &mut Array2<Complex<f32>>, direction: FftDirection) {
+    let (n_rows, n_cols) = data.dim();
+    let (row_fft, col_fft) = FFT_PLANNER.with(|planner| {
+        let mut planner = planner.borrow_mut();
+        (planner.plan_fft(n_cols, direction), planner.plan_fft(n_rows, direction))
+    });
+
+    // Rows
+    let mut buffer = vec![Complex::new(0.0, 0.0); n_cols];
+    for mut row in data.rows_mut() {
+        buffer.iter_mut().zip(row.iter()).for_each(|(b, &x)| *b = x);
+        row_fft.process(&mut buffer);
+        row.iter_mut().zip(buffer.iter()).for_each(|(x, &b)| *x = b);
+    }
+
+    // Columns
+    let mut buffer = vec![Complex::new(0.0, 0.0); n_rows];
+    for mut col in data.columns_mut() {
+        buffer.iter_mut().zip(col.iter()).for_each(|(b, &x)| *b = x);
+        col_fft.process(&mut buffer);
+        col.iter_mut().zip(buffer.iter()).for_each(|(x, &b)| *x = b);
+    }
+}
+
+/// Frequency of each FFT bin, in cycles per sample, in FFT order
+pub fn fft_frequencies(n: usize) -> Vec<f32> {
+    (0..n).map(|i| {
+        let k = if i < n.div_ceil(2) { i as f32 } else { i as f32 - n as f32 };
+        k / n as f32
+    }).collect()
+}
@@ -0,0 +1,469 @@
+/// RUST-AO Frame Ring
+///
+/// A fixed set of preallocated frames shared between a single producer
+/// (the acquisition thread) and any number of consumers, without a mutex.
+///
+/// Each slot carries the sequence number of the frame it holds and a count of
+/// readers currently borrowing it. The writer never touches the slot holding
+/// the latest frame or any slot that is borrowed, so consumers can read the
+/// latest frame in place for as long as they hold a `FrameRef`. Gaps in the
+/// sequence numbers seen by a consumer are frames it never looked at.
+///
+/// Frames can also be written a band of rows at a time, as a camera reads
+/// out. Each slot publishes how many rows of which frame are complete, so a
+/// consumer can start on the top of a frame before the bottom has arrived.
+///
+use ndarray::{Array2, ArrayView2, ArrayViewMut2};
+use std::cell::UnsafeCell;
+use std::ops::{Deref, Range};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Sequence value marking a slot that is being written
+const WRITING: u64 = u64::MAX;
+
+/// Progress value marking a slot whose rows must not be read
+const INVALID: u64 = u64::MAX;
+
+/// Progress packs the frame's sequence number above the count of complete rows
+const ROW_BITS: u32 = 16;
+
+fn pack_progress(sequence: u64, rows: usize) -> u64 {
+    (sequence << ROW_BITS) | rows as u64
+}
+
+fn unpack_progress(progress: u64) -> (u64, usize) {
+    (progress >> ROW_BITS, (progress & ((1 << ROW_BITS) - 1)) as usize)
+}
+
+struct Slot {
+    sequence: AtomicU64,
+    /// Sequence and complete rows of the frame in this slot, or `INVALID`
+    progress: AtomicU64,
+    readers: AtomicUsize,
+    frame: UnsafeCell<Array2<u16>>,
+    /// Start of the frame's pixels, for row views while other rows are written
+    data: *mut u16,
+}
+
+pub struct FrameRing {
+    slots: Vec<Slot>,
+    latest: AtomicUsize,
+    /// Slot most recently claimed by the writer
+    writing: AtomicUsize,
+    has_writer: AtomicBool,
+    n_rows: usize,
+    n_cols: usize,
+}
+
+// Access to the frames is coordinated through the `sequence`, `progress` and
+// `readers` atomics of each slot, see `FrameWriter::claim_slot`,
+// `FrameRing::latest` and `FrameRing::newest_after`
+unsafe impl Sync for FrameRing {}
+unsafe impl Send for FrameRing {}
+
+impl FrameRing {
+    pub fn new(n_slots: usize, n_rows: usize, n_cols: usize) -> Self {
+        assert!(n_slots >= 2, "FrameRing needs at least 2 slots, got {}", n_slots);
+        assert!(n_rows < (1 << ROW_BITS), "FrameRing supports at most {} rows", (1 << ROW_BITS) - 1);
+        let slots = (0..n_slots).map(|_| {
+            let mut frame = Array2::<u16>::zeros((n_rows, n_cols));
+            let data = frame.as_mut_ptr();
+            Slot {
+                sequence: AtomicU64::new(0),
+                progress: AtomicU64::new(pack_progress(0, n_rows)),
+                readers: AtomicUsize::new(0),
+                frame: UnsafeCell::new(frame),
+                data: data,
+            }
+        }).collect();
+
+        Self {
+            slots: slots,
+            latest: AtomicUsize::new(0),
+            writing: AtomicUsize::new(0),
+            has_writer: AtomicBool::new(false),
+            n_rows: n_rows,
+            n_cols: n_cols,
+        }
+    }
+
+    pub fn n_slots(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Claims the producer side of the ring. Returns `None` if another
+    /// `FrameWriter` for this ring is still alive.
+    pub fn writer(ring: &Arc<FrameRing>) -> Option<FrameWriter> {
+        ring.has_writer.compare_exchange(false, true, Ordering::AcqRel, Ordering::Acquire).ok()?;
+        Some(FrameWriter {
+            ring: Arc::clone(ring),
+            next_slot: 1,
+        })
+    }
+
+    /// Sequence number of the most recently completed frame
+    pub fn latest_sequence(&self) -> u64 {
+        self.latest().sequence()
+    }
+
+    /// Borrows the most recently completed frame without copying it. The
+    /// writer will not reuse that slot until the returned `FrameRef` is dropped.
+    pub fn latest(&self) -> FrameRef<'_> {
+        loop {
+            let slot = &self.slots[self.latest.load(Ordering::Acquire)];
+            slot.readers.fetch_add(1, Ordering::SeqCst);
+            let sequence = slot.sequence.load(Ordering::SeqCst);
+            if sequence != WRITING {
+                return FrameRef { slot: slot, sequence: sequence };
+            }
+            // The writer claimed this slot after a newer frame was published,
+            // back off and pick up the new latest slot
+            slot.readers.fetch_sub(1, Ordering::SeqCst);
+            std::hint::spin_loop();
+        }
+    }
+
+    /// Borrows the newest frame with a sequence number above `last_sequence`,
+    /// which may still be being written, or `None` if there is none yet
+    pub fn newest_after(&self, last_sequence: u64) -> Option<PartialFrameRef<'_>> {
+        let slot = &self.slots[self.writing.load(Ordering::Acquire)];
+        slot.readers.fetch_add(1, Ordering::SeqCst);
+        // The writer invalidates progress before checking for readers, so a
+        // valid value here belongs to a frame that will not be overwritten
+        // while the slot is pinned
+        let progress = slot.progress.load(Ordering::SeqCst);
+        if progress != INVALID {
+            let (sequence, rows) = unpack_progress(progress);
+            if sequence > last_sequence {
+                return Some(PartialFrameRef { slot: slot, sequence: sequence, n_rows: self.n_rows, n_cols: self.n_cols, rows_seen: AtomicUsize::new(rows) });
+            }
+        }
+        slot.readers.fetch_sub(1, Ordering::SeqCst);
+
+        let latest = self.latest();
+        if latest.sequence() > last_sequence {
+            Some(latest.into())
+        } else {
+            None
+        }
+    }
+
+    /// Spins until a frame newer than `last_sequence` has started, see `newest_after`
+    pub fn wait_newer(&self, last_sequence: u64, timeout: Duration) -> Option<PartialFrameRef<'_>> {
+        let start = Instant::now();
+        loop {
+            if let Some(frame) = self.newest_after(last_sequence) {
+                return Some(frame);
+            }
+            if start.elapsed() > timeout {
+                return None;
+            }
+            std::thread::yield_now();
+        }
+    }
+}
+
+/// Producer handle for a `FrameRing`, there is at most one per ring
+pub struct FrameWriter {
+    ring: Arc<FrameRing>,
+    next_slot: usize,
+}
+
+impl FrameWriter {
+    /// Fills a free slot in place with `fill` and publishes it as the latest
+    /// frame with the given sequence number. Returns `false`, without calling
+    /// `fill`, if every slot other than the latest is currently borrowed.
+    /// `fill` gets a view, so it cannot reallocate the slot's pixels.
+    pub fn write<F>(&mut self, sequence: u64, fill: F) -> bool
+    where
+        F: FnOnce(ArrayViewMut2<u16>),
+    {
+        let Some(index) = self.claim_slot(sequence) else {
+            return false;
+        };
+        let slot = &self.ring.slots[index];
+        let (n_rows, n_cols) = (self.ring.n_rows, self.ring.n_cols);
+
+        // Safety: the slot is marked WRITING with no readers and its progress
+        // shows no complete rows, and there is only one writer, so nothing
+        // else can access the frame
+        fill(unsafe { ArrayViewMut2::from_shape_ptr((n_rows, n_cols), slot.data) });
+
+        self.publish(index, sequence);
+        true
+    }
+
+    /// As `write`, but fills the frame `rows_per_band` rows at a time, making
+    /// each band readable through `FrameRing::newest_after` as soon as `fill`
+    /// returns for it. `fill` gets the first row of the band and the band.
+    pub fn write_rows<F>(&mut self, sequence: u64, rows_per_band: usize, mut fill: F) -> bool
+    where
+        F: FnMut(usize, ArrayViewMut2<u16>),
+    {
+        assert!(rows_per_band > 0, "FrameWriter: bands need at least one row");
+        let Some(index) = self.claim_slot(sequence) else {
+            return false;
+        };
+        let slot = &self.ring.slots[index];
+        let (n_rows, n_cols) = (self.ring.n_rows, self.ring.n_cols);
+
+        let mut row = 0;
+        while row < n_rows {
+            let band_rows = rows_per_band.min(n_rows - row);
+            // Safety: rows at or past the published progress are only ever
+            // accessed by the writer, and readers only view rows before it
+            let band = unsafe { ArrayViewMut2::from_shape_ptr((band_rows, n_cols), slot.data.add(row * n_cols)) };
+            fill(row, band);
+            row += band_rows;
+            slot.progress.store(pack_progress(sequence, row), Ordering::Release);
+        }
+
+        self.publish(index, sequence);
+        true
+    }
+
+    /// Finds a free slot and marks it as holding `sequence` with no rows
+    fn claim_slot(&mut self, sequence: u64) -> Option<usize> {
+        let ring = &self.ring;
+        let n_slots = ring.slots.len();
+        let latest = ring.latest.load(Ordering::Acquire);
+
+        for attempt in 0..n_slots {
+            let index = (self.next_slot + attempt) % n_slots;
+            if index == latest {
+                continue;
+            }
+
+            // Mark the slot as being written, then make sure no reader got in
+            // first. Readers do the opposite (pin, then check the sequence or
+            // progress), so with SeqCst at most one side proceeds.
+            let slot = &ring.slots[index];
+            let previous_progress = slot.progress.swap(INVALID, Ordering::SeqCst);
+            let previous = slot.sequence.swap(WRITING, Ordering::SeqCst);
+            if slot.readers.load(Ordering::SeqCst) != 0 {
+                slot.sequence.store(previous, Ordering::SeqCst);
+                slot.progress.store(previous_progress, Ordering::SeqCst);
+                continue;
+            }
+
+            slot.progress.store(pack_progress(sequence, 0), Ordering::SeqCst);
+            ring.writing.store(index, Ordering::Release);
+            return Some(index);
+        }
+        None
+    }
+
+    fn publish(&mut self, index: usize, sequence: u64) {
+        let ring = &self.ring;
+        let slot = &ring.slots[index];
+        slot.progress.store(pack_progress(sequence, ring.n_rows), Ordering::SeqCst);
+        slot.sequence.store(sequence, Ordering::SeqCst);
+        ring.latest.store(index, Ordering::Release);
+        self.next_slot = (index + 1) % ring.slots.len();
+    }
+}
+
+impl Drop for FrameWriter {
+    fn drop(&mut self) {
+        self.ring.has_writer.store(false, Ordering::Release);
+    }
+}
+
+/// A borrowed frame from a `FrameRing`
+pub struct FrameRef<'a> {
+    slot: &'a Slot,
+    sequence: u64,
+}
+
+impl FrameRef<'_> {
+    /// Sequence number of this frame
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+}
+
+impl Deref for FrameRef<'_> {
+    type Target = Array2<u16>;
+
+    fn deref(&self) -> &Array2<u16> {
+        // Safety: the writer skips slots with readers, so the frame cannot
+        // change while this reference holds the slot
+        unsafe { &*self.slot.frame.get() }
+    }
+}
+
+impl Drop for FrameRef<'_> {
+    fn drop(&mut self) {
+        self.slot.readers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A borrowed frame from a `FrameRing` that may still be being written. Rows
+/// can be read once they are reported ready.
+pub struct PartialFrameRef<'a> {
+    slot: &'a Slot,
+    sequence: u64,
+    n_rows: usize,
+    n_cols: usize,
+    /// Most rows seen complete so far, which can never go back down
+    rows_seen: AtomicUsize,
+}
+
+impl PartialFrameRef<'_> {
+    /// Sequence number of this frame
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    pub fn n_rows(&self) -> usize {
+        self.n_rows
+    }
+
+    /// Number of rows, from the top, that are complete
+    pub fn rows_ready(&self) -> usize {
+        // A pinned slot cannot be reclaimed, but the writer briefly marks its
+        // progress invalid while finding out that it is pinned, so fall back
+        // on the rows already seen whenever the sequence does not match
+        let (sequence, rows) = unpack_progress(self.slot.progress.load(Ordering::Acquire));
+        if sequence == self.sequence {
+            self.rows_seen.fetch_max(rows, Ordering::Relaxed).max(rows)
+        } else {
+            self.rows_seen.load(Ordering::Relaxed)
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.rows_ready() == self.n_rows()
+    }
+
+    /// Spins until at least `n_rows` rows are complete or `timeout` passes,
+    /// returning the number of complete rows
+    pub fn wait_for_rows(&self, n_rows: usize, timeout: Duration) -> usize {
+        let start = Instant::now();
+        loop {
+            let ready = self.rows_ready();
+            if ready >= n_rows || start.elapsed() > timeout {
+                return ready;
+            }
+            std::thread::yield_now();
+        }
+    }
+
+    /// View of complete rows. Panics if any of them is not ready.
+    pub fn rows(&self, rows: Range<usize>) -> ArrayView2<'_, u16> {
+        assert!(rows.start <= rows.end && rows.end <= self.rows_ready(), "PartialFrameRef: rows {:?} are not ready", rows);
+        // Safety: the writer never goes back to rows it has published, and
+        // cannot reclaim the slot while it is pinned
+        unsafe { ArrayView2::from_shape_ptr((rows.len(), self.n_cols), self.slot.data.add(rows.start * self.n_cols)) }
+    }
+}
+
+impl<'a> From<FrameRef<'a>> for PartialFrameRef<'a> {
+    fn from(frame: FrameRef<'a>) -> Self {
+        // The pin moves over to the partial reference
+        let frame = std::mem::ManuallyDrop::new(frame);
+        let (n_rows, n_cols) = frame.dim();
+        PartialFrameRef { slot: frame.slot, sequence: frame.sequence, n_rows: n_rows, n_cols: n_cols, rows_seen: AtomicUsize::new(n_rows) }
+    }
+}
+
+impl Drop for PartialFrameRef<'_> {
+    fn drop(&mut self) {
+        self.slot.readers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latest_frame_follows_writes() {
+        let ring = Arc::new(FrameRing::new(3, 2, 2));
+        let mut writer = FrameRing::writer(&ring).unwrap();
+        for sequence in 1..10 {
+            assert!(writer.write(sequence, |mut frame| frame.fill(sequence as u16)));
+            let frame = ring.latest();
+            assert_eq!(frame.sequence(), sequence);
+            assert!(frame.iter().all(|&p| p == sequence as u16));
+        }
+    }
+
+    #[test]
+    fn test_borrowed_frame_is_not_overwritten() {
+        let ring = Arc::new(FrameRing::new(2, 2, 2));
+        let mut writer = FrameRing::writer(&ring).unwrap();
+        writer.write(1, |mut frame| frame.fill(1));
+
+        let held = ring.latest();
+        assert!(writer.write(2, |mut frame| frame.fill(2)));
+        // Only two slots: one is latest, the other is borrowed
+        assert!(!writer.write(3, |mut frame| frame.fill(3)));
+        assert!(held.iter().all(|&p| p == 1));
+        drop(held);
+
+        assert!(writer.write(3, |mut frame| frame.fill(3)));
+        assert_eq!(ring.latest_sequence(), 3);
+    }
+
+    #[test]
+    fn test_rows_readable_before_frame_completes() {
+        let ring = Arc::new(FrameRing::new(3, 4, 2));
+        let mut writer = FrameRing::writer(&ring).unwrap();
+        writer.write(1, |mut frame| frame.fill(1));
+
+        let reader = ring.clone();
+        assert!(writer.write_rows(2, 1, |row, mut band| {
+            band.fill(10 + row as u16);
+            let frame = reader.newest_after(1).unwrap();
+            assert_eq!(frame.sequence(), 2);
+            // This band is published once the closure returns
+            assert_eq!(frame.rows_ready(), row);
+            assert!(frame.rows(0..row).indexed_iter().all(|((r, _), &p)| p == 10 + r as u16));
+        }));
+
+        // The complete frame is also the latest one
+        assert_eq!(ring.latest_sequence(), 2);
+        let frame = ring.newest_after(1).unwrap();
+        assert!(frame.is_complete());
+        assert!(ring.newest_after(2).is_none());
+    }
+
+    #[test]
+    fn test_pinned_partial_frame_stays_complete() {
+        let ring = Arc::new(FrameRing::new(3, 4, 2));
+        let mut writer = FrameRing::writer(&ring).unwrap();
+        writer.write(1, |mut frame| frame.fill(1));
+        let frame = ring.newest_after(0).unwrap();
+        assert!(writer.write(2, |mut frame| frame.fill(2)));
+
+        // The writer keeps trying to claim the pinned slot, which is no
+        // longer the latest, while the reader polls it
+        let done = AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for sequence in 3..200_000 {
+                    writer.write(sequence, |mut frame| frame.fill(sequence as u16));
+                }
+                done.store(true, Ordering::Release);
+            });
+            while !done.load(Ordering::Acquire) {
+                assert_eq!(frame.rows_ready(), 4);
+                assert!(frame.rows(0..4).iter().all(|&p| p == 1));
+            }
+        });
+        assert_eq!(frame.sequence(), 1);
+    }
+
+    #[test]
+    fn test_single_writer() {
+        let ring = Arc::new(FrameRing::new(2, 2, 2));
+        let writer = FrameRing::writer(&ring);
+        assert!(writer.is_some());
+        assert!(FrameRing::writer(&ring).is_none());
+        drop(writer);
+        assert!(FrameRing::writer(&ring).is_some());
+    }
+}
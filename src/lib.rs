@@ -0,0 +1,20 @@
+//! RUST-AO: adaptive optics real-time control, with simulated cameras,
+//! DMs and atmosphere to run it against. The demos are in `main.rs`.
+#![allow(clippy::redundant_field_names)]
+
+pub mod detector;
+pub mod framering;
+pub mod fft;
+pub mod fakecamera;
+pub mod fakedm;
+pub mod wfs;
+pub mod controller;
+pub mod shmupdater;
+pub mod commandmap;
+pub mod measurementfusion;
+pub mod aoloop;
+pub mod calibration;
+pub mod linalg;
+pub mod modes;
+pub mod reconstructor;
+pub mod simulation;
@@ -0,0 +1,177 @@
+/// Dense linear algebra for calibration and control
+///
+/// Small, dependency free routines on ndarray matrices, done in f64 for
+/// accuracy. These run offline (calibration, reconstructor computation),
+/// never in the loop itself.
+///
+use ndarray::{Array1, Array2, Axis};
+
+/// Singular value decomposition `a = u * diag(s) * vt`, singular values descending
+pub struct Svd {
+    pub u: Array2<f64>,
+    pub s: Array1<f64>,
+    pub vt: Array2<f64>,
+}
+
+/// Thin SVD of an (m, n) matrix: `u` is (m, k), `s` has k values and `vt` is
+/// (k, n), with k = min(m, n).
+///
+/// Computed by one-sided Jacobi rotations of the columns of `a` itself, so
+/// small singular values keep their relative accuracy instead of being lost
+/// as they would be from the eigenvalues of a^T a. Only exactly zero singular
+/// values get a zero column in `u`.
+pub fn svd(a: &Array2<f64>) -> Svd {
+    let (m, n) = a.dim();
+    if m < n {
+        let transposed = svd(&a.t().to_owned());
+        return Svd {
+            u: transposed.vt.t().to_owned(),
+            s: transposed.s,
+            vt: transposed.u.t().to_owned(),
+        };
+    }
+
+    // Rotate pairs of columns until all are orthogonal: a v = w, with the
+    // column norms of w the singular values
+    let mut w = a.clone();
+    let mut v = Array2::<f64>::eye(n);
+    let mut sweeps = 0;
+    loop {
+        let mut rotated = false;
+        for p in 0..n {
+            for q in (p + 1)..n {
+                let alpha = w.column(p).dot(&w.column(p));
+                let beta = w.column(q).dot(&w.column(q));
+                let gamma = w.column(p).dot(&w.column(q));
+                if gamma == 0.0 || gamma.abs() <= f64::EPSILON * (alpha * beta).sqrt() {
+                    continue;
+                }
+                rotated = true;
+                let zeta = (beta - alpha) / (2.0 * gamma);
+                let t = zeta.signum() / (zeta.abs() + zeta.hypot(1.0));
+                let c = 1.0 / t.hypot(1.0);
+                let s = c * t;
+                rotate_columns(&mut w, p, q, c, s);
+                rotate_columns(&mut v, p, q, c, s);
+            }
+        }
+        if !rotated {
+            break;
+        }
+        sweeps += 1;
+        assert!(sweeps < 60, "svd: Jacobi sweeps did not converge");
+    }
+
+    let norms = Array1::from_iter(w.axis_iter(Axis(1)).map(|col| col.dot(&col).sqrt()));
+    let mut order = (0..n).collect::<Vec<_>>();
+    order.sort_by(|&i, &j| norms[j].total_cmp(&norms[i]));
+    let s = Array1::from_iter(order.iter().map(|&i| norms[i]));
+    let mut u = w.select(Axis(1), &order);
+    for (mut col, &s) in u.axis_iter_mut(Axis(1)).zip(s.iter()) {
+        if s > 0.0 {
+            col /= s;
+        }
+    }
+    let vt = v.select(Axis(1), &order).t().to_owned();
+
+    Svd { u: u, s: s, vt: vt }
+}
+
+/// Applies the rotation [c s; -s c] to columns `p` and `q`
+fn rotate_columns(a: &mut Array2<f64>, p: usize, q: usize, c: f64, s: f64) {
+    for mut row in a.rows_mut() {
+        let (x, y) = (row[p], row[q]);
+        row[p] = c * x - s * y;
+        row[q] = s * x + c * y;
+    }
+}
+
+/// Moore-Penrose pseudo-inverse, discarding singular values smaller than
+/// `threshold` times the largest
+pub fn pseudo_inverse(a: &Array2<f64>, threshold: f64) -> Array2<f64> {
+    let svd = svd(a);
+    let s_max = svd.s.first().copied().unwrap_or(0.0);
+    let inverse = svd.s.mapv(|s| if s > 0.0 && s >= threshold * s_max { 1.0 / s } else { 0.0 });
+    (&svd.vt.t() * &inverse).dot(&svd.u.t())
+}
+
+/// Inverse of a square matrix by Gauss-Jordan elimination with partial
+/// pivoting. Returns `None` if the matrix is singular.
+pub fn inverse(a: &Array2<f64>) -> Option<Array2<f64>> {
+    let n = a.nrows();
+    assert_eq!(n, a.ncols(), "inverse: matrix must be square");
+    let mut m = a.clone();
+    let mut inv = Array2::<f64>::eye(n);
+
+    for col in 0..n {
+        let pivot = (col..n).max_by(|&i, &j| m[[i, col]].abs().partial_cmp(&m[[j, col]].abs()).unwrap())?;
+        if m[[pivot, col]].abs() < f64::EPSILON {
+            return None;
+        }
+        for k in 0..n {
+            m.swap([col, k], [pivot, k]);
+            inv.swap([col, k], [pivot, k]);
+        }
+
+        let scale = 1.0 / m[[col, col]];
+        m.row_mut(col).mapv_inplace(|x| x * scale);
+        inv.row_mut(col).mapv_inplace(|x| x * scale);
+
+        for row in 0..n {
+            if row != col {
+                let factor = m[[row, col]];
+                if factor != 0.0 {
+                    let m_col = m.row(col).to_owned();
+                    let inv_col = inv.row(col).to_owned();
+                    m.row_mut(row).scaled_add(-factor, &m_col);
+                    inv.row_mut(row).scaled_add(-factor, &inv_col);
+                }
+            }
+        }
+    }
+    Some(inv)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    fn max_abs(a: &Array2<f64>) -> f64 {
+        a.iter().fold(0.0, |m, x| m.max(x.abs()))
+    }
+
+    #[test]
+    fn test_svd_tall_and_wide() {
+        let a = Array2::from_shape_fn((6, 4), |(i, j)| ((i * 3 + j * 5) % 7) as f64 - 3.0 + (i == j) as i32 as f64);
+        for m in [a.clone(), a.t().to_owned()] {
+            let svd = svd(&m);
+            let reconstructed = svd.u.dot(&Array2::from_diag(&svd.s)).dot(&svd.vt);
+            assert!(max_abs(&(reconstructed - &m)) < 1e-9);
+            assert_eq!(svd.s.len(), 4);
+        }
+    }
+
+    #[test]
+    fn test_svd_resolves_small_singular_values() {
+        // Orthogonal factors from the SVD of a well conditioned matrix
+        let q = svd(&Array2::from_shape_fn((4, 4), |(i, j)| ((i * 5 + j * 3) % 7) as f64 + 4.0 * (i == j) as i32 as f64));
+        let singular_values = array![1.0, 1e-3, 1e-7, 1e-11];
+        let a = (&q.u * &singular_values).dot(&q.vt);
+
+        let svd = svd(&a);
+        for (s, expected) in svd.s.iter().zip(singular_values.iter()) {
+            assert!((s - expected).abs() < 1e-4 * expected + 1e-15, "{} != {}", s, expected);
+        }
+        let reconstructed = (&svd.u * &svd.s).dot(&svd.vt);
+        assert!(max_abs(&(reconstructed - &a)) < 1e-14);
+    }
+
+    #[test]
+    fn test_inverse() {
+        let a = array![[2.0, 1.0, 0.0], [1.0, 3.0, 1.0], [0.0, 1.0, 4.0]];
+        let inv = inverse(&a).unwrap();
+        assert!(max_abs(&(a.dot(&inv) - Array2::<f64>::eye(3))) < 1e-12);
+        assert!(inverse(&array![[1.0, 2.0], [2.0, 4.0]]).is_none());
+    }
+}
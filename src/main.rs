@@ -1,30 +1,25 @@
 use std::thread;
 use std::time;
-use ndarray::array;
-use std::vec::Vec;
+use std::sync::atomic::Ordering;
 // extern crate intel_mkl_src;
 // extern crate blas_src;
 use simple_logger::SimpleLogger;
 
-mod fakecamera;
-use fakecamera::Camera;
-
-mod fakedm;
-use fakedm::DM;
-
-mod wfs;
-use wfs::{WFS, ShackHartmann};
-
-mod controller;
-use controller::IntegratorController;
-
-mod shmupdater;
-
-mod aoloop;
-use aoloop::AOLoop;
-
-// mod centreofgravity;
-use wfs::centreofgravity::{simple_centre_of_gravity, threshold_centre_of_gravity, test_cog};
+use rustycam::detector::Detector;
+use rustycam::fakecamera::Camera;
+use rustycam::fakecamera::spotsim::{SpotSimulation, SpotProfile, InjectedSlopes};
+use rustycam::fakedm::DM;
+use rustycam::wfs;
+use rustycam::wfs::ShackHartmann;
+use rustycam::wfs::centreofgravity::test_cog;
+use rustycam::controller::IntegratorController;
+use rustycam::controller::leakyintegrator::LeakyIntegrator;
+use rustycam::aoloop::AOLoop;
+use rustycam::calibration::{PokeCalibration, PokePattern};
+use rustycam::reconstructor::{Reconstructor, Regularisation};
+use rustycam::simulation::ClosedLoopSimulation;
+use rustycam::simulation::influence::InfluenceFunctions;
+use rustycam::simulation::atmosphere::{Atmosphere, TurbulenceParameters, LayerProfile};
 
 
 fn test_camera() {
@@ -33,8 +28,8 @@ fn test_camera() {
 
     let rows = 128;
     let cols = 128;
-    let frame_rate = 0.0 as f32;
-    let e_read_noise = 10 as f32;
+    let frame_rate: f32 = 0.0;
+    let e_read_noise: f32 = 10.0;
 
 
     let mut cam = Camera::new(rows, cols, e_read_noise, frame_rate);
@@ -68,11 +63,11 @@ fn test_dm() {
 
     let n_acts = 140;
     let mut dm = DM::new(n_acts);
-    let mut actuator_values = ndarray::Array1::<f32>::zeros((n_acts));
+    let mut actuator_values = ndarray::Array1::<f32>::zeros(n_acts);
     let mut n: f32 = 0.0;
     for i in actuator_values.iter_mut() {
         *i = n;
-        n = n + 1.0;
+        n += 1.0;
     }
 
     for i in 0..n_acts {
@@ -92,10 +87,10 @@ fn  test_aoloop() {
     println!("Init AO Loop...");
     let n_rows = 320;
     let n_cols = 320;
-    let frame_rate = 0.0 as f32;
-    let e_read_noise = 10 as f32;
+    let frame_rate: f32 = 0.0;
+    let e_read_noise: f32 = 10.0;
     let pixels_per_subap = 8;
-    let nx_subaps = (n_rows / pixels_per_subap);
+    let nx_subaps = n_rows / pixels_per_subap;
     let n_subaps = nx_subaps * nx_subaps;
     let n_actuators = 1024;
     let run_secs = 10;
@@ -118,13 +113,17 @@ fn  test_aoloop() {
     }
 
     let mut cam = Camera::new(n_rows, n_cols, e_read_noise, frame_rate);
+    let spot_simulation = SpotSimulation::new(
+        subap_coordinates.clone(), SpotProfile::Gaussian { fwhm: 2.0 }, 1000.0, 5.0,
+        Box::new(InjectedSlopes::new(n_subaps)));
+    cam.set_spot_simulation(spot_simulation);
     cam.start_acquisition();
     let sh = ShackHartmann::new(
         n_rows, n_cols, pixels_per_subap, subap_coordinates, 0);
 
-    let dm = DM::new(2);
+    let dm = DM::new(n_actuators);
     let controller = IntegratorController::new(2*n_subaps, n_actuators, 1.0);
-    let mut aoloop = AOLoop::new(vec![cam], vec![sh], controller, vec![dm]);
+    let mut aoloop = AOLoop::new(vec![Box::new(cam)], vec![Box::new(sh)], Box::new(controller), vec![dm]);
 
     println!("Init AO Loop...Done");
 
@@ -149,15 +148,142 @@ fn  test_aoloop() {
 
 }
 
+/// Runs the same rolling-readout camera through the sequential and the
+/// pipelined loop to compare the latency from last pixel to DM command
+fn test_pipelined_aoloop() {
+    println!("Hello, Pipelined AO Loop!");
+    let n_rows = 320;
+    let n_cols = 320;
+    let frame_rate = 500.0;
+    let readout_rows = 8;
+    let pixels_per_subap = 8;
+    let nx_subaps = n_rows / pixels_per_subap;
+    let n_subaps = nx_subaps * nx_subaps;
+    let n_actuators = 1024;
+    let run_secs = 5;
+
+    let mut subap_coordinates: Vec<Vec<usize>> = Vec::new();
+    for x in 0..nx_subaps{
+        for y in 0..nx_subaps {
+            subap_coordinates.push(vec![
+                x * pixels_per_subap,
+                (x + 1) * pixels_per_subap,
+                y * pixels_per_subap,
+                (y + 1) * pixels_per_subap
+                ]);
+        }
+    }
+
+    for pipelined in [false, true] {
+        println!("Pipelined: {}", pipelined);
+        let mut cam = Camera::new(n_rows, n_cols, 10.0, frame_rate);
+        cam.set_readout_rows(readout_rows);
+        let spot_simulation = SpotSimulation::new(
+            subap_coordinates.clone(), SpotProfile::Gaussian { fwhm: 2.0 }, 1000.0, 5.0,
+            Box::new(InjectedSlopes::new(n_subaps)));
+        cam.set_spot_simulation(spot_simulation);
+        cam.start_acquisition();
+        let sh = ShackHartmann::new(n_rows, n_cols, pixels_per_subap, subap_coordinates.clone(), 0);
+
+        let controller = IntegratorController::new(2*n_subaps, n_actuators, 1.0);
+        let mut aoloop = AOLoop::new(vec![Box::new(cam)], vec![Box::new(sh)], Box::new(controller), vec![DM::new(n_actuators)]);
+        aoloop.set_pipelined(pipelined);
+        aoloop.start_loop();
+        thread::sleep(time::Duration::from_secs(run_secs));
+        aoloop.stop_loop();
+        aoloop.print_timers();
+    }
+}
+
+fn test_closed_loop_simulation() {
+    println!("Hello, Closed Loop Simulation!");
+    println!("Init Simulation...");
+    let nx_subaps = 8;
+    let pixels_per_subap = 8;
+    let n_pupil = 64;
+    let n_rows = nx_subaps * pixels_per_subap;
+    let n_cols = n_rows;
+    let n_subaps = nx_subaps * nx_subaps;
+    let nx_actuators = nx_subaps + 1;
+    let n_actuators = nx_actuators * nx_actuators;
+    let telescope_diameter = 1.0;
+    let frame_rate = 100.0;
+    let run_secs = 10;
+
+    let mut subap_coordinates: Vec<Vec<usize>> = Vec::new();
+    for x in 0..nx_subaps{
+        for y in 0..nx_subaps {
+            subap_coordinates.push(vec![
+                x * pixels_per_subap,
+                (x + 1) * pixels_per_subap,
+                y * pixels_per_subap,
+                (y + 1) * pixels_per_subap
+                ]);
+        }
+    }
+
+    let turbulence = TurbulenceParameters { r0: 0.3, outer_scale: 25.0, inner_scale: 0.0 };
+    let profile = [
+        LayerProfile { cn2_weight: 0.7, wind_speed: 2.0, wind_direction: 0.0 },
+        LayerProfile { cn2_weight: 0.3, wind_speed: 5.0, wind_direction: 60.0 },
+    ];
+    let atmosphere = Atmosphere::new(
+        turbulence, &profile, n_pupil, telescope_diameter / n_pupil as f32, n_pupil + 1, frame_rate, 0);
+
+    let mut sim = ClosedLoopSimulation::new(Box::new(atmosphere), n_pupil, nx_subaps, 2.0);
+    let dm_link = sim.add_dm(InfluenceFunctions::gaussian(nx_actuators, nx_actuators, n_pupil, 0.15, 1.0));
+    let residual_rms = sim.residual_rms_handle();
+    let atmosphere_enabled = sim.atmosphere_enabled_handle();
+
+    let mut cam = Camera::new(n_rows, n_cols, 1.0, frame_rate);
+    let spot_simulation = SpotSimulation::new(
+        subap_coordinates.clone(), SpotProfile::Gaussian { fwhm: 2.0 }, 5000.0, 0.0, Box::new(sim));
+    cam.set_spot_simulation(spot_simulation);
+    cam.start_acquisition();
+
+    let sh = ShackHartmann::new(n_rows, n_cols, pixels_per_subap, subap_coordinates, 0);
+    let mut dm = DM::new(n_actuators);
+    dm.connect_simulation(dm_link);
+    let controller = LeakyIntegrator::new(2*n_subaps, n_actuators, 0.5, 0.002);
+    let mut aoloop = AOLoop::new(vec![Box::new(cam)], vec![Box::new(sh)], Box::new(controller), vec![dm]);
+    println!("Init Simulation...Done");
+
+    println!("Calibrating...");
+    atmosphere_enabled.store(false, Ordering::Relaxed);
+    // Every actuator moves in every Hadamard pattern, so keep the pokes small
+    // enough that the spots stay in their linear range
+    let calibration = PokeCalibration::new(0.3, 4);
+    let calibration_result = aoloop.calibrate(&calibration, &PokePattern::Hadamard, 0, 0).unwrap();
+    let interaction_matrix = calibration_result.interaction_matrix;
+    let reconstructor = Reconstructor::new(&interaction_matrix);
+    println!("Singular values: {}", reconstructor.singular_values());
+    reconstructor.install(Regularisation::DropModes(1), &mut **aoloop.controller().lock().unwrap()).unwrap();
+    atmosphere_enabled.store(true, Ordering::Relaxed);
+    println!("Calibrating...Done");
+
+    aoloop.start_loop();
+    for _ in 0..run_secs {
+        thread::sleep(time::Duration::from_secs(1));
+        println!("Iteration: {:6}  Residual RMS: {:.4} rad", aoloop.get_iteration_number(), residual_rms.lock().unwrap());
+    }
+    aoloop.stop_loop();
+    aoloop.print_timers();
+}
+
 
 fn main() {
     SimpleLogger::new().init().unwrap();
     log::set_max_level(log::LevelFilter::Info);
     log::info!("Running Rust AO!");
-    // test_camera();
-    // test_dm();
-    // test_cog();
-    // wfs::test_shackhartmann();
-    test_aoloop();
+    // Pick a demo by name, the AO loop by default
+    match std::env::args().nth(1).as_deref() {
+        Some("camera") => test_camera(),
+        Some("dm") => test_dm(),
+        Some("cog") => test_cog(),
+        Some("shackhartmann") => wfs::test_shackhartmann(),
+        Some("closed-loop") => test_closed_loop_simulation(),
+        Some("pipelined") => test_pipelined_aoloop(),
+        _ => test_aoloop(),
+    }
 
 }
@@ -0,0 +1,196 @@
+/// RUST-AO Measurement Fusion
+///
+/// Combines the measurements of every WFS into the single vector the
+/// controller works on. Each WFS has a reference offset subtracted, is
+/// scaled by a weight and can be switched off, which zeroes its part of the
+/// vector so the controller's input keeps the same layout. The result is
+/// either the WFS vectors concatenated in order, or that concatenation
+/// multiplied by a fusion matrix, e.g. a tomographic reconstructor.
+///
+use std::ops::Range;
+use ndarray::{Array1, Array2, s};
+use log::info;
+
+#[derive(Clone)]
+pub struct MeasurementFusion {
+    slices: Vec<Range<usize>>,
+    offsets: Vec<Array1<f32>>,
+    weights: Vec<f32>,
+    enabled: Vec<bool>,
+    /// (n_outputs, n_concatenated), `None` to concatenate only
+    fusion_matrix: Option<Array2<f32>>,
+}
+
+impl MeasurementFusion {
+    /// Concatenation of WFSs with `wfs_n_measurements` measurements each,
+    /// with no offsets and unit weights
+    pub fn new(wfs_n_measurements: &[usize]) -> Self {
+        let mut slices = Vec::new();
+        let mut start = 0;
+        for &n_measurements in wfs_n_measurements {
+            slices.push(start..start + n_measurements);
+            start += n_measurements;
+        }
+        Self {
+            offsets: wfs_n_measurements.iter().map(|&n| Array1::<f32>::zeros(n)).collect(),
+            weights: vec![1.0; wfs_n_measurements.len()],
+            enabled: vec![true; wfs_n_measurements.len()],
+            slices: slices,
+            fusion_matrix: None,
+        }
+    }
+
+    pub fn n_wfs(&self) -> usize {
+        self.slices.len()
+    }
+
+    /// Length of the concatenated WFS measurements
+    pub fn n_concatenated(&self) -> usize {
+        self.slices.last().map(|s| s.end).unwrap_or(0)
+    }
+
+    /// Length of the vector passed to the controller
+    pub fn n_outputs(&self) -> usize {
+        match &self.fusion_matrix {
+            Some(matrix) => matrix.nrows(),
+            None => self.n_concatenated(),
+        }
+    }
+
+    /// Reference measurements subtracted from WFS `wfs_index`, e.g. non-common path offsets
+    pub fn set_offsets(&mut self, wfs_index: usize, offsets: Array1<f32>) {
+        assert_eq!(offsets.len(), self.slices[wfs_index].len(), "MeasurementFusion: offsets for WFS {} have the wrong length", wfs_index);
+        self.offsets[wfs_index] = offsets;
+    }
+
+    pub fn set_weight(&mut self, wfs_index: usize, weight: f32) {
+        self.weights[wfs_index] = weight;
+    }
+
+    pub fn set_enabled(&mut self, wfs_index: usize, enabled: bool) {
+        info!("MeasurementFusion: WFS {} {}", wfs_index, if enabled { "enabled" } else { "disabled" });
+        self.enabled[wfs_index] = enabled;
+    }
+
+    pub fn is_enabled(&self, wfs_index: usize) -> bool {
+        self.enabled[wfs_index]
+    }
+
+    /// Multiplies the concatenated measurements by `fusion_matrix`, which is
+    /// (n_outputs, n_concatenated). The number of outputs must then match the
+    /// controller.
+    pub fn set_fusion_matrix(&mut self, fusion_matrix: Array2<f32>) {
+        assert_eq!(fusion_matrix.ncols(), self.n_concatenated(), "MeasurementFusion: fusion matrix needs one column per WFS measurement");
+        self.fusion_matrix = Some(fusion_matrix);
+    }
+
+    pub fn clear_fusion_matrix(&mut self) {
+        self.fusion_matrix = None;
+    }
+
+    /// Concatenated measurements with offsets, weights and enable flags applied
+    pub fn concatenate(&self, measurements: &[Array1<f32>]) -> Array1<f32> {
+        self.concatenate_available(measurements, &vec![true; self.n_wfs()])
+    }
+
+    /// As `concatenate`, with the WFSs not `available` this frame zeroed as
+    /// if they were disabled, e.g. when their camera has stalled
+    pub fn concatenate_available(&self, measurements: &[Array1<f32>], available: &[bool]) -> Array1<f32> {
+        assert_eq!(measurements.len(), self.n_wfs(), "MeasurementFusion: need measurements from every WFS");
+        assert_eq!(available.len(), self.n_wfs(), "MeasurementFusion: need availability of every WFS");
+        let mut concatenated = Array1::<f32>::zeros(self.n_concatenated());
+        for (i, slice) in self.slices.iter().enumerate() {
+            if self.enabled[i] && available[i] {
+                let mut part = concatenated.slice_mut(s![slice.clone()]);
+                part.assign(&(&measurements[i] - &self.offsets[i]));
+                part *= self.weights[i];
+            }
+        }
+        concatenated
+    }
+
+    /// Controller input from the measurements of every WFS
+    pub fn fuse(&self, measurements: &[Array1<f32>]) -> Array1<f32> {
+        self.fuse_available(measurements, &vec![true; self.n_wfs()])
+    }
+
+    /// Controller input from the measurements of the `available` WFSs, see
+    /// `concatenate_available`
+    pub fn fuse_available(&self, measurements: &[Array1<f32>], available: &[bool]) -> Array1<f32> {
+        let concatenated = self.concatenate_available(measurements, available);
+        match &self.fusion_matrix {
+            Some(matrix) => matrix.dot(&concatenated),
+            None => concatenated,
+        }
+    }
+
+    /// Updates only `ranges` of WFS `wfs_index`'s part of an already fused
+    /// vector from its latest `measurements`, returning the ranges of
+    /// `fused` that changed. For pipelined loops, which get measurements a
+    /// block at a time; not possible with a fusion matrix, where every
+    /// output depends on every measurement.
+    pub fn fuse_ranges(&self, wfs_index: usize, measurements: &Array1<f32>, ranges: &[Range<usize>], fused: &mut Array1<f32>) -> Vec<Range<usize>> {
+        assert!(self.fusion_matrix.is_none(), "MeasurementFusion: cannot fuse ranges through a fusion matrix");
+        let start = self.slices[wfs_index].start;
+        ranges.iter().map(|range| {
+            let fused_range = start + range.start..start + range.end;
+            let mut part = fused.slice_mut(s![fused_range.clone()]);
+            if self.enabled[wfs_index] {
+                part.assign(&(&measurements.slice(s![range.clone()]) - &self.offsets[wfs_index].slice(s![range.clone()])));
+                part *= self.weights[wfs_index];
+            } else {
+                part.fill(0.0);
+            }
+            fused_range
+        }).collect()
+    }
+
+    pub fn has_fusion_matrix(&self) -> bool {
+        self.fusion_matrix.is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_offsets_weights_and_enable() {
+        let mut fusion = MeasurementFusion::new(&[2, 1]);
+        fusion.set_offsets(0, array![1.0, 1.0]);
+        fusion.set_weight(1, 0.5);
+        let measurements = vec![array![2.0, 3.0], array![4.0]];
+        assert_eq!(fusion.fuse(&measurements), array![1.0, 2.0, 2.0]);
+
+        fusion.set_enabled(0, false);
+        assert_eq!(fusion.fuse(&measurements), array![0.0, 0.0, 2.0]);
+        // A WFS without a new frame is left out like a disabled one
+        fusion.set_enabled(0, true);
+        assert_eq!(fusion.fuse_available(&measurements, &[true, false]), array![1.0, 2.0, 0.0]);
+    }
+
+    #[test]
+    fn test_fusion_matrix() {
+        let mut fusion = MeasurementFusion::new(&[1, 1]);
+        // Average of the two WFSs
+        fusion.set_fusion_matrix(array![[0.5, 0.5]]);
+        assert_eq!(fusion.n_outputs(), 1);
+        assert_eq!(fusion.fuse(&[array![1.0], array![3.0]]), array![2.0]);
+    }
+
+    #[test]
+    #[allow(clippy::single_range_in_vec_init)]
+    fn test_fuse_ranges_matches_fuse() {
+        let mut fusion = MeasurementFusion::new(&[1, 3]);
+        fusion.set_offsets(1, array![1.0, 1.0, 1.0]);
+        fusion.set_weight(1, 2.0);
+        let measurements = vec![array![5.0], array![2.0, 3.0, 4.0]];
+
+        let mut fused = Array1::<f32>::zeros(4);
+        fusion.fuse_ranges(0, &measurements[0], &[0..1], &mut fused);
+        assert_eq!(fusion.fuse_ranges(1, &measurements[1], &[2..3, 0..1], &mut fused), vec![3..4, 1..2]);
+        fusion.fuse_ranges(1, &measurements[1], &[1..2], &mut fused);
+        assert_eq!(fused, fusion.fuse(&measurements));
+    }
+}
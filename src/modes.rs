@@ -0,0 +1,110 @@
+/// RUST-AO Modal Bases
+///
+/// Command space bases used for calibration and modal control. Every basis
+/// is an (n_acts, n_modes) matrix with one mode per column.
+///
+use ndarray::{Array1, Array2, s};
+
+use crate::linalg::svd;
+
+/// Normalised pupil coordinates (x, y) in [-1, 1] of each actuator of an
+/// `n_rows` x `n_cols` grid, in row by row actuator order
+pub fn actuator_grid_positions(n_rows: usize, n_cols: usize) -> (Array1<f32>, Array1<f32>) {
+    let coordinate = |i: usize, n: usize| if n > 1 { 2.0 * i as f32 / (n - 1) as f32 - 1.0 } else { 0.0 };
+    let x = Array1::from_shape_fn(n_rows * n_cols, |a| coordinate(a % n_cols, n_cols));
+    let y = Array1::from_shape_fn(n_rows * n_cols, |a| coordinate(a / n_cols, n_rows));
+    (x, y)
+}
+
+/// Zernike polynomials, Noll ordered and normalised to unit RMS over the unit
+/// disk, sampled at the actuator positions. Piston is left out as the WFS
+/// cannot see it, so the first mode is tip (Noll index 2).
+pub fn zernike_basis(x: &Array1<f32>, y: &Array1<f32>, n_modes: usize) -> Array2<f32> {
+    assert_eq!(x.len(), y.len(), "zernike_basis: x and y must be the same length");
+    let mut basis = Array2::<f32>::zeros((x.len(), n_modes));
+    for mode in 0..n_modes {
+        let (n, m) = noll_to_nm(mode + 2);
+        for a in 0..x.len() {
+            let r = (x[a].powi(2) + y[a].powi(2)).sqrt();
+            let theta = y[a].atan2(x[a]);
+            basis[[a, mode]] = zernike(n, m, r, theta);
+        }
+    }
+    basis
+}
+
+/// Eigenmodes of the DM as seen by the WFS: the right singular vectors of
+/// the (n_measurements, n_acts) interaction matrix, best seen first
+pub fn dm_eigenmodes(interaction_matrix: &Array2<f32>) -> Array2<f32> {
+    let svd = svd(&interaction_matrix.mapv(|x| x as f64));
+    svd.vt.t().mapv(|x| x as f32)
+}
+
+/// Sylvester Hadamard matrix of order `n`, which must be a power of two
+pub fn hadamard(n: usize) -> Array2<f32> {
+    assert!(n.is_power_of_two(), "hadamard: order must be a power of two, got {}", n);
+    let mut h = Array2::<f32>::ones((1, 1));
+    while h.nrows() < n {
+        let size = h.nrows();
+        let mut next = Array2::<f32>::zeros((2 * size, 2 * size));
+        next.slice_mut(s![..size, ..size]).assign(&h);
+        next.slice_mut(s![..size, size..]).assign(&h);
+        next.slice_mut(s![size.., ..size]).assign(&h);
+        next.slice_mut(s![size.., size..]).assign(&-&h);
+        h = next;
+    }
+    h
+}
+
+/// Radial and azimuthal order of the Zernike with Noll index `j` (from 1)
+fn noll_to_nm(j: usize) -> (usize, i64) {
+    let mut n = 0;
+    while (n + 1) * (n + 2) / 2 < j {
+        n += 1;
+    }
+    // Position within radial order n, and the |m| values that order allows
+    let k = j - n * (n + 1) / 2 - 1;
+    let m_abs = if n % 2 == 0 { 2 * k.div_ceil(2) } else { 2 * (k / 2) + 1 };
+    let m = if m_abs == 0 || j.is_multiple_of(2) { m_abs as i64 } else { -(m_abs as i64) };
+    (n, m)
+}
+
+fn zernike(n: usize, m: i64, r: f32, theta: f32) -> f32 {
+    let m_abs = m.unsigned_abs() as usize;
+    let mut radial = 0.0;
+    for k in 0..=(n - m_abs) / 2 {
+        let sign = if k % 2 == 0 { 1.0 } else { -1.0 };
+        let coefficient = factorial(n - k) / (factorial(k) * factorial((n + m_abs) / 2 - k) * factorial((n - m_abs) / 2 - k));
+        radial += sign * coefficient * r.powi((n - 2 * k) as i32);
+    }
+    if m == 0 {
+        ((n + 1) as f32).sqrt() * radial
+    } else if m > 0 {
+        (2.0 * (n + 1) as f32).sqrt() * radial * (m_abs as f32 * theta).cos()
+    } else {
+        (2.0 * (n + 1) as f32).sqrt() * radial * (m_abs as f32 * theta).sin()
+    }
+}
+
+fn factorial(n: usize) -> f32 {
+    (1..=n).map(|i| i as f32).product()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_noll_ordering() {
+        let expected = [(0, 0), (1, 1), (1, -1), (2, 0), (2, -2), (2, 2), (3, -1), (3, 1), (3, -3), (3, 3), (4, 0)];
+        for (j, &nm) in expected.iter().enumerate() {
+            assert_eq!(noll_to_nm(j + 1), nm, "Noll index {}", j + 1);
+        }
+    }
+
+    #[test]
+    fn test_hadamard_is_orthogonal() {
+        let h = hadamard(8);
+        assert_eq!(h.t().dot(&h), Array2::<f32>::eye(8) * 8.0);
+    }
+}
@@ -0,0 +1,164 @@
+/// RUST-AO Reconstructor
+///
+/// Turns a measured interaction matrix into a control matrix with a
+/// regularised pseudo-inverse, and reports the singular values so the
+/// filtering can be chosen.
+///
+use ndarray::{Array1, Array2};
+use log::info;
+
+use crate::controller::{Controller, ParameterError, ParameterValue};
+use crate::linalg::{svd, Svd};
+
+/// How the poorly seen modes of the interaction matrix are filtered
+#[derive(Debug, Clone, Copy)]
+pub enum Regularisation {
+    /// Discard singular values smaller than this fraction of the largest
+    ConditionThreshold(f32),
+    /// Discard this many of the smallest singular values
+    DropModes(usize),
+    /// Tikhonov, inverting each singular value s as s / (s² + alpha²)
+    Tikhonov(f32),
+}
+
+pub struct Reconstructor {
+    svd: Svd,
+}
+
+impl Reconstructor {
+    /// `interaction_matrix` is (n_measurements, n_commands)
+    pub fn new(interaction_matrix: &Array2<f32>) -> Self {
+        let svd = svd(&interaction_matrix.mapv(|x| x as f64));
+        let reconstructor = Self { svd: svd };
+        info!("Reconstructor: {} singular values, condition number {:e}",
+            reconstructor.svd.s.len(), reconstructor.condition_number());
+        reconstructor
+    }
+
+    /// Singular values of the interaction matrix, largest first
+    pub fn singular_values(&self) -> Array1<f32> {
+        self.svd.s.mapv(|s| s as f32)
+    }
+
+    /// Ratio of the largest to the smallest singular value. Infinite if the
+    /// interaction matrix is rank deficient.
+    pub fn condition_number(&self) -> f32 {
+        let s = &self.svd.s;
+        match (s.first(), s.last()) {
+            (Some(&max), Some(&min)) if min > 0.0 => (max / min) as f32,
+            (Some(_), Some(_)) => f32::INFINITY,
+            _ => 1.0,
+        }
+    }
+
+    /// Inverse applied to each singular value by the regularisation
+    fn filtered_inverse(&self, regularisation: Regularisation) -> Array1<f64> {
+        let s = &self.svd.s;
+        let s_max = s.first().copied().unwrap_or(0.0);
+        let n_modes = s.len();
+        Array1::from_iter(s.iter().enumerate().map(|(i, &s)| {
+            let keep = s > 0.0 && match regularisation {
+                Regularisation::ConditionThreshold(threshold) => s >= threshold as f64 * s_max,
+                Regularisation::DropModes(n_drop) => i + n_drop < n_modes,
+                Regularisation::Tikhonov(_) => true,
+            };
+            match regularisation {
+                _ if !keep => 0.0,
+                Regularisation::Tikhonov(alpha) => s / (s * s + (alpha as f64).powi(2)),
+                _ => 1.0 / s,
+            }
+        }))
+    }
+
+    /// Number of modes that survive the regularisation
+    pub fn n_modes_kept(&self, regularisation: Regularisation) -> usize {
+        self.filtered_inverse(regularisation).iter().filter(|&&x| x != 0.0).count()
+    }
+
+    /// Condition number of the interaction matrix restricted to the kept modes
+    pub fn filtered_condition_number(&self, regularisation: Regularisation) -> f32 {
+        let inverse = self.filtered_inverse(regularisation);
+        let kept = self.svd.s.iter().zip(inverse.iter()).filter(|(_, inv)| **inv != 0.0).map(|(&s, _)| s);
+        let (max, min) = kept.fold((0.0_f64, f64::INFINITY), |(max, min), s| (max.max(s), min.min(s)));
+        if max == 0.0 { 1.0 } else { (max / min) as f32 }
+    }
+
+    /// Regularised pseudo-inverse, (n_commands, n_measurements), giving the
+    /// commands that reproduce a set of measurements
+    pub fn pseudo_inverse(&self, regularisation: Regularisation) -> Array2<f32> {
+        let inverse = self.filtered_inverse(regularisation);
+        let v_scaled = &self.svd.vt.t() * &inverse;
+        v_scaled.dot(&self.svd.u.t()).mapv(|x| x as f32)
+    }
+
+    /// Control matrix for integrating controllers. The interaction matrix
+    /// includes the sign of the DM, so this is the negated pseudo-inverse:
+    /// `commands += gain * CM · s` then drives the measurements to zero.
+    pub fn control_matrix(&self, regularisation: Regularisation) -> Array2<f32> {
+        -self.pseudo_inverse(regularisation)
+    }
+
+    /// Computes the control matrix and installs it as the controller's
+    /// "control_matrix" parameter
+    pub fn install(&self, regularisation: Regularisation, controller: &mut dyn Controller) -> Result<(), ParameterError> {
+        info!("Reconstructor: installing control matrix with {:?}, {} of {} modes kept, condition number {:e}",
+            regularisation, self.n_modes_kept(regularisation), self.svd.s.len(),
+            self.filtered_condition_number(regularisation));
+        controller.set_parameter("control_matrix", ParameterValue::Matrix(self.control_matrix(regularisation)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+    use crate::controller::IntegratorController;
+
+    #[test]
+    fn test_pseudo_inverse_of_full_rank_matrix() {
+        let interaction_matrix = array![[1.0, 0.5], [0.0, 2.0], [1.0, -1.0]];
+        let reconstructor = Reconstructor::new(&interaction_matrix);
+        let pinv = reconstructor.pseudo_inverse(Regularisation::ConditionThreshold(0.0));
+        let identity = pinv.dot(&interaction_matrix);
+        assert!((identity - Array2::<f32>::eye(2)).iter().all(|x| x.abs() < 1e-5));
+    }
+
+    #[test]
+    fn test_mode_filtering() {
+        // Singular values 4, 1 and 0.01
+        let interaction_matrix = Array2::from_diag(&array![4.0_f32, 1.0, 0.01]);
+        let reconstructor = Reconstructor::new(&interaction_matrix);
+        assert!((reconstructor.condition_number() - 400.0).abs() < 1e-2);
+
+        assert_eq!(reconstructor.n_modes_kept(Regularisation::ConditionThreshold(0.1)), 2);
+        assert_eq!(reconstructor.n_modes_kept(Regularisation::DropModes(1)), 2);
+        assert!((reconstructor.filtered_condition_number(Regularisation::DropModes(1)) - 4.0).abs() < 1e-4);
+
+        let pinv = reconstructor.pseudo_inverse(Regularisation::DropModes(1));
+        assert!((pinv[[0, 0]] - 0.25).abs() < 1e-6);
+        assert!((pinv[[1, 1]] - 1.0).abs() < 1e-6);
+        assert_eq!(pinv[[2, 2]], 0.0);
+
+        let pinv = reconstructor.pseudo_inverse(Regularisation::Tikhonov(0.1));
+        assert!((pinv[[2, 2]] - 0.01 / (0.0001 + 0.01)).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_weak_modes_are_resolved() {
+        let interaction_matrix = array![[1.0, 1.0], [1.0, 1.0 + 1e-6], [0.0, 0.0]];
+        let reconstructor = Reconstructor::new(&interaction_matrix);
+        let condition_number = reconstructor.condition_number();
+        assert!(condition_number.is_finite() && condition_number > 1e6, "{}", condition_number);
+        assert_eq!(reconstructor.n_modes_kept(Regularisation::ConditionThreshold(1e-8)), 2);
+        assert_eq!(reconstructor.n_modes_kept(Regularisation::ConditionThreshold(1e-4)), 1);
+    }
+
+    #[test]
+    fn test_control_matrix_installed() {
+        let interaction_matrix = array![[2.0, 0.0], [0.0, 4.0]];
+        let mut controller = IntegratorController::new(2, 2, 1.0);
+        Reconstructor::new(&interaction_matrix).install(Regularisation::DropModes(0), &mut controller).unwrap();
+        let commands = controller.compute_commands(&array![2.0, 4.0]);
+        assert!((commands - array![-1.0, -1.0]).iter().all(|x| x.abs() < 1e-6));
+    }
+}
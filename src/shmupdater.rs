@@ -7,6 +7,9 @@ pub struct ShmUpdater {
     wfs_measurements_shm_writer: AoShmWriter,
     actuator_shm_writer: AoShmWriter,
     camera_shm_writer: AoShmWriter,
+    saturation_shm_writer: AoShmWriter,
+    /// Created on first use, as the number of modes depends on the controller
+    modal_coefficients_shm_writer: Option<(usize, AoShmWriter)>,
 }
 
 impl ShmUpdater {
@@ -16,26 +19,33 @@ impl ShmUpdater {
                 "detector_frames",
                 vec![n_measurements as u64],
                 AO_DTYPE::UINT16,
-                8
+                fifo_size
             );
         let wfs_measurements_shm_writer = AoShmWriter::new(
                 "wfs_measurements",
                 vec![cam_n_rows as u64, cam_n_cols as u64],
                 AO_DTYPE::FLOAT32,
-                8
+                fifo_size
             );
         let actuator_shm_writer = AoShmWriter::new(
                 "actuator_commands",
                 vec![n_actuators as u64],
                 AO_DTYPE::FLOAT32,
-                8
+                fifo_size
+            );
+        let saturation_shm_writer = AoShmWriter::new(
+                "actuator_saturation",
+                vec![1],
+                AO_DTYPE::FLOAT32,
+                fifo_size
             );
-
 
         Self {
             wfs_measurements_shm_writer,
             actuator_shm_writer,
             camera_shm_writer,
+            saturation_shm_writer,
+            modal_coefficients_shm_writer: None,
         }
     }
 
@@ -49,6 +59,27 @@ impl ShmUpdater {
         self.actuator_shm_writer.set_next_frame(datau8_vec, iter_num);
     }
 
+    /// Number of actuator commands clipped this iteration
+    pub fn update_saturation(&mut self, n_saturated: usize, iter_num: u64) {
+        let datau8_vec: Vec<u8> = (n_saturated as f32).to_ne_bytes().to_vec();
+        self.saturation_shm_writer.set_next_frame(datau8_vec, iter_num);
+    }
+
+    pub fn update_modal_coefficients(&mut self, coefficients: &Array1<f32>, iter_num: u64) {
+        let n_modes = coefficients.len();
+        if self.modal_coefficients_shm_writer.as_ref().map(|(n, _)| *n) != Some(n_modes) {
+            let writer = AoShmWriter::new(
+                    "modal_coefficients",
+                    vec![n_modes as u64],
+                    AO_DTYPE::FLOAT32,
+                    8
+                );
+            self.modal_coefficients_shm_writer = Some((n_modes, writer));
+        }
+        let datau8_vec: Vec<u8> = coefficients.iter().flat_map(|&x| x.to_ne_bytes().to_vec()).collect();
+        self.modal_coefficients_shm_writer.as_mut().unwrap().1.set_next_frame(datau8_vec, iter_num);
+    }
+
     pub fn update_camera_frames(&mut self, frame: &Array2<u16>, iter_num: u64) {
         let datau8_vec: Vec<u8> = frame.iter().flat_map(|&x| x.to_ne_bytes().to_vec()).collect();
         self.camera_shm_writer.set_next_frame(datau8_vec, iter_num);
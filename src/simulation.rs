@@ -0,0 +1,221 @@
+/// RUST-AO Closed Loop Simulation
+///
+/// Couples a turbulent phase screen, simulated DMs and the fake camera's
+/// Shack-Hartmann spots so that the commands `AOLoop` sends to the DMs
+/// change what the camera sees. Each camera frame the residual phase
+/// (atmosphere minus DM surfaces) is turned into subaperture slopes that
+/// drive the simulated spots.
+///
+use ndarray::{Array1, Array2, s};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use log::trace;
+
+use crate::fakecamera::spotsim::SlopeSource;
+
+pub mod influence;
+use influence::InfluenceFunctions;
+
+pub mod atmosphere;
+
+/// Source of the incoming (uncorrected) phase on the pupil grid, in radians
+pub trait PhaseScreen: Send + Sync {
+    fn phase(&mut self, frame_number: u64) -> Array2<f32>;
+}
+
+/// A phase screen that never changes, e.g. a fixed aberration
+pub struct StaticPhaseScreen {
+    phase: Array2<f32>,
+}
+
+impl StaticPhaseScreen {
+    pub fn new(phase: Array2<f32>) -> Self {
+        Self { phase: phase }
+    }
+}
+
+impl PhaseScreen for StaticPhaseScreen {
+    fn phase(&mut self, _frame_number: u64) -> Array2<f32> {
+        self.phase.clone()
+    }
+}
+
+/// A simulated DM: its influence functions and the commands last applied to it.
+struct SimulatedDM {
+    influence: InfluenceFunctions,
+    commands: Arc<Mutex<Array1<f32>>>,
+}
+
+pub struct ClosedLoopSimulation {
+    atmosphere: Box<dyn PhaseScreen>,
+    atmosphere_enabled: Arc<AtomicBool>,
+    dms: Vec<SimulatedDM>,
+    n_pupil: usize,
+    /// [row_start, row_end, col_start, col_end] of each subaperture on the pupil grid
+    pupil_subaps: Vec<[usize; 4]>,
+    pixels_per_lambda_over_d: f32,
+    residual_rms: Arc<Mutex<f32>>,
+}
+
+impl ClosedLoopSimulation {
+    /// Simulation of an `nx_subaps` x `nx_subaps` Shack-Hartmann on an
+    /// `n_pupil` x `n_pupil` pupil grid. Subapertures are ordered as in
+    /// `main.rs`, row-major with the row index outermost, and spots move by
+    /// `pixels_per_lambda_over_d` detector pixels per wave of tilt across a
+    /// subaperture.
+    pub fn new(atmosphere: Box<dyn PhaseScreen>, n_pupil: usize, nx_subaps: usize, pixels_per_lambda_over_d: f32) -> Self {
+        assert!(n_pupil >= 2 * nx_subaps, "ClosedLoopSimulation: need at least 2 pupil pixels per subaperture");
+        let mut pupil_subaps = Vec::new();
+        for x in 0..nx_subaps {
+            for y in 0..nx_subaps {
+                pupil_subaps.push([
+                    x * n_pupil / nx_subaps,
+                    (x + 1) * n_pupil / nx_subaps,
+                    y * n_pupil / nx_subaps,
+                    (y + 1) * n_pupil / nx_subaps,
+                ]);
+            }
+        }
+
+        Self {
+            atmosphere: atmosphere,
+            atmosphere_enabled: Arc::new(AtomicBool::new(true)),
+            dms: Vec::new(),
+            n_pupil: n_pupil,
+            pupil_subaps: pupil_subaps,
+            pixels_per_lambda_over_d: pixels_per_lambda_over_d,
+            residual_rms: Arc::new(Mutex::new(0.0)),
+        }
+    }
+
+    /// Adds a DM in the beam. Pass the returned handle to `DM::connect_simulation`
+    /// so the commands the loop applies reach the simulation.
+    pub fn add_dm(&mut self, influence: InfluenceFunctions) -> Arc<Mutex<Array1<f32>>> {
+        assert_eq!(influence.n_pupil(), self.n_pupil, "ClosedLoopSimulation: DM pupil grid does not match");
+        let commands = Arc::new(Mutex::new(Array1::<f32>::zeros(influence.n_acts())));
+        self.dms.push(SimulatedDM {
+            influence: influence,
+            commands: Arc::clone(&commands),
+        });
+        commands
+    }
+
+    pub fn n_subaps(&self) -> usize {
+        self.pupil_subaps.len()
+    }
+
+    /// Shared value holding the RMS residual phase of the most recent frame
+    pub fn residual_rms_handle(&self) -> Arc<Mutex<f32>> {
+        Arc::clone(&self.residual_rms)
+    }
+
+    /// Shared switch for the atmosphere. Turn it off to calibrate against the
+    /// DM alone, as on a bench with the turbulence source removed.
+    pub fn atmosphere_enabled_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.atmosphere_enabled)
+    }
+
+    /// Atmospheric phase minus the surfaces of all DMs
+    pub fn residual_phase(&mut self, frame_number: u64) -> Array2<f32> {
+        let mut residual = if self.atmosphere_enabled.load(Ordering::Relaxed) {
+            self.atmosphere.phase(frame_number)
+        } else {
+            Array2::<f32>::zeros((self.n_pupil, self.n_pupil))
+        };
+        assert_eq!(residual.dim(), (self.n_pupil, self.n_pupil), "ClosedLoopSimulation: phase screen does not match pupil grid");
+        for dm in self.dms.iter() {
+            let commands = dm.commands.lock().unwrap();
+            residual -= &dm.influence.surface(&commands);
+        }
+        residual
+    }
+
+    /// Spot displacements in detector pixels for a given phase, in the
+    /// `ShackHartmann` measurement layout
+    pub fn phase_to_slopes(&self, phase: &Array2<f32>) -> Array1<f32> {
+        let n_subaps = self.n_subaps();
+        let mut slopes = Array1::<f32>::zeros(2 * n_subaps);
+        let scale = self.pixels_per_lambda_over_d / (2.0 * std::f32::consts::PI);
+
+        for (i, coords) in self.pupil_subaps.iter().enumerate() {
+            let subap = phase.slice(s![coords[0]..coords[1], coords[2]..coords[3]]);
+            let (n_rows, n_cols) = subap.dim();
+
+            // Mean phase difference between neighbouring pixels, times the
+            // subaperture width, is the tilt across the subaperture
+            let row_gradient = (&subap.slice(s![1.., ..]) - &subap.slice(s![..-1, ..])).mean().unwrap();
+            let col_gradient = (&subap.slice(s![.., 1..]) - &subap.slice(s![.., ..-1])).mean().unwrap();
+
+            slopes[i] = scale * row_gradient * n_rows as f32;
+            slopes[i + n_subaps] = scale * col_gradient * n_cols as f32;
+        }
+        slopes
+    }
+
+    /// Noise free slopes for each actuator of DM `dm_index` pushed by `amplitude`,
+    /// as a (n_measurements, n_acts) matrix. Useful as a model interaction matrix.
+    pub fn synthetic_interaction_matrix(&self, dm_index: usize, amplitude: f32) -> Array2<f32> {
+        let influence = &self.dms[dm_index].influence;
+        let n_acts = influence.n_acts();
+        let mut interaction_matrix = Array2::<f32>::zeros((2 * self.n_subaps(), n_acts));
+        let mut commands = Array1::<f32>::zeros(n_acts);
+        for act in 0..n_acts {
+            commands.fill(0.0);
+            commands[act] = amplitude;
+            // The DM surface is subtracted from the incoming phase
+            let slopes = self.phase_to_slopes(&-influence.surface(&commands)) / amplitude;
+            interaction_matrix.column_mut(act).assign(&slopes);
+        }
+        interaction_matrix
+    }
+}
+
+impl SlopeSource for ClosedLoopSimulation {
+    fn next_slopes(&mut self, frame_number: u64) -> Array1<f32> {
+        let residual = self.residual_phase(frame_number);
+        let mean = residual.mean().unwrap();
+        let rms = residual.mapv(|p| (p - mean).powi(2)).mean().unwrap().sqrt();
+        *self.residual_rms.lock().unwrap() = rms;
+        trace!("Frame {}: residual phase RMS {} rad", frame_number, rms);
+
+        self.phase_to_slopes(&residual)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tilt_slopes() {
+        // One wave of tilt across the whole pupil is a quarter wave per subaperture
+        let n_pupil = 16;
+        let tilt = Array2::from_shape_fn((n_pupil, n_pupil), |(row, _)| {
+            2.0 * std::f32::consts::PI * row as f32 / n_pupil as f32
+        });
+        let sim = ClosedLoopSimulation::new(Box::new(StaticPhaseScreen::new(tilt.clone())), n_pupil, 4, 2.0);
+        let slopes = sim.phase_to_slopes(&tilt);
+        for i in 0..16 {
+            assert!((slopes[i] - 0.5).abs() < 1e-4, "slope {}: {}", i, slopes[i]);
+            assert!(slopes[i + 16].abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_dm_cancels_its_own_surface() {
+        let n_pupil = 16;
+        let influence = InfluenceFunctions::gaussian(5, 5, n_pupil, 0.2, 1.0);
+        let mut commands = Array1::<f32>::zeros(25);
+        commands[12] = 0.7;
+        commands[3] = -0.3;
+        let phase = influence.surface(&commands);
+
+        let mut sim = ClosedLoopSimulation::new(Box::new(StaticPhaseScreen::new(phase)), n_pupil, 4, 2.0);
+        let dm_commands = sim.add_dm(InfluenceFunctions::gaussian(5, 5, n_pupil, 0.2, 1.0));
+        dm_commands.lock().unwrap().assign(&commands);
+
+        let slopes = sim.next_slopes(0);
+        assert!(slopes.iter().all(|s| s.abs() < 1e-5));
+        assert!(*sim.residual_rms_handle().lock().unwrap() < 1e-5);
+    }
+}
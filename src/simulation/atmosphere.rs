@@ -0,0 +1,604 @@
+/// Von Kármán atmospheric turbulence for the simulation
+///
+/// Each layer is carried across the pupil by the wind (frozen flow).
+/// `FrozenFlowLayer` is an FFT generated phase screen with optional
+/// subharmonics for the low orders. It is periodic, so it wraps without a
+/// seam, but it repeats every screen width of travel.
+///
+/// `ExtrudingLayer` never repeats: it starts from an FFT screen and, as the
+/// wind carries the screen along, adds new rows and columns on the upwind
+/// side and drops them downwind (Assémat et al. 2006). Each new line is
+/// drawn from the von Kármán statistics conditioned on a stencil of the
+/// existing screen, the nearest lines in full and sparser ones further in
+/// for the low orders. `Atmosphere` uses extruding layers, so it can run
+/// for as long as the simulation does.
+///
+use ndarray::{Array1, Array2};
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rand_distr::StandardNormal;
+use rustfft::num_complex::Complex;
+use std::f32::consts::PI;
+use log::{info, warn};
+
+use crate::fft::{ifft2, fft_frequencies};
+use crate::linalg::{pseudo_inverse, svd};
+use super::PhaseScreen;
+
+/// Gamma function values for the von Kármán covariance
+const GAMMA_5_6: f64 = 1.128_787_029_908_125_5;
+const GAMMA_11_6: f64 = 0.940_655_858_256_771_2;
+const GAMMA_6_5: f64 = 0.918_168_742_399_760_6;
+
+/// Turbulence statistics, in metres at the wavelength the phase is wanted
+#[derive(Debug, Clone, Copy)]
+pub struct TurbulenceParameters {
+    /// Fried parameter
+    pub r0: f32,
+    /// Outer scale
+    pub outer_scale: f32,
+    /// Inner scale, zero for none
+    pub inner_scale: f32,
+}
+
+impl TurbulenceParameters {
+    /// Modified von Kármán phase power spectral density, in rad² m², at a
+    /// spatial frequency `f` in cycles per metre
+    pub fn phase_psd(&self, f: f32) -> f32 {
+        let f0 = 1.0 / self.outer_scale;
+        let inner = if self.inner_scale > 0.0 {
+            let fm = 5.92 / (2.0 * PI * self.inner_scale);
+            (-(f / fm).powi(2)).exp()
+        } else {
+            1.0
+        };
+        0.023 * self.r0.powf(-5.0 / 3.0) * inner / (f * f + f0 * f0).powf(11.0 / 6.0)
+    }
+
+    /// Von Kármán phase covariance, in rad², between points `r` metres
+    /// apart. The inner scale is not included.
+    pub fn phase_covariance(&self, r: f64) -> f64 {
+        let outer_scale = self.outer_scale as f64;
+        let scale = (outer_scale / self.r0 as f64).powf(5.0 / 3.0)
+            * 2.0_f64.powf(-5.0 / 6.0) * GAMMA_11_6 / std::f64::consts::PI.powf(8.0 / 3.0)
+            * (24.0 / 5.0 * GAMMA_6_5).powf(5.0 / 6.0);
+        scale * scaled_bessel_k_5_6(2.0 * std::f64::consts::PI * r / outer_scale)
+    }
+
+    /// The same turbulence with only `weight` of the total Cn²
+    pub fn scaled(&self, weight: f32) -> Self {
+        Self {
+            r0: self.r0 * weight.powf(-3.0 / 5.0),
+            ..*self
+        }
+    }
+}
+
+/// One layer of a turbulence profile
+#[derive(Debug, Clone, Copy)]
+pub struct LayerProfile {
+    /// Relative Cn² of this layer, normalised over the whole profile
+    pub cn2_weight: f32,
+    /// Wind speed in m/s
+    pub wind_speed: f32,
+    /// Wind direction in degrees, from the pupil column axis towards the row axis
+    pub wind_direction: f32,
+}
+
+/// A low spatial frequency plane wave added to the FFT screen
+struct Subharmonic {
+    f_row: f32,
+    f_col: f32,
+    coefficient: Complex<f32>,
+}
+
+pub struct FrozenFlowLayer {
+    screen: Array2<f32>,
+    subharmonics: Vec<Subharmonic>,
+    pixel_scale: f32,
+    /// Wind velocity in m/s along (rows, cols)
+    velocity: (f32, f32),
+}
+
+impl FrozenFlowLayer {
+    /// Generates an `n_screen` x `n_screen` layer with `pixel_scale` metres per pixel
+    pub fn new<R: Rng>(
+            turbulence: TurbulenceParameters, n_screen: usize, pixel_scale: f32,
+            wind_speed: f32, wind_direction: f32, subharmonics: bool, rng: &mut R) -> Self {
+
+        let direction = wind_direction.to_radians();
+        let velocity = (wind_speed * direction.sin(), wind_speed * direction.cos());
+
+        let subharmonics = if subharmonics {
+            generate_subharmonics(turbulence, n_screen as f32 * pixel_scale, rng)
+        } else {
+            Vec::new()
+        };
+
+        Self {
+            screen: fft_phase_screen(turbulence, n_screen, pixel_scale, rng),
+            subharmonics: subharmonics,
+            pixel_scale: pixel_scale,
+            velocity: velocity,
+        }
+    }
+
+    /// Phase on an `n_pupil` x `n_pupil` grid, `time` seconds after the start
+    pub fn phase(&self, n_pupil: usize, time: f64) -> Array2<f32> {
+        let n_screen = self.screen.nrows();
+        // Reduce the shift to one period of the screen in f64, so precision
+        // is not lost however far the layer has travelled
+        let period = n_screen as f64;
+        let shift_row = (self.velocity.0 as f64 * time / self.pixel_scale as f64).rem_euclid(period);
+        let shift_col = (self.velocity.1 as f64 * time / self.pixel_scale as f64).rem_euclid(period);
+
+        // Frozen flow: what is over a point now was upwind of it earlier
+        let rows = (0..n_pupil).map(|r| interpolation_weights(r as f64 - shift_row, n_screen)).collect::<Vec<_>>();
+        let cols = (0..n_pupil).map(|c| interpolation_weights(c as f64 - shift_col, n_screen)).collect::<Vec<_>>();
+
+        let mut phase = Array2::from_shape_fn((n_pupil, n_pupil), |(r, c)| {
+            let (r0, r1, fr) = rows[r];
+            let (c0, c1, fc) = cols[c];
+            (1.0 - fr) * ((1.0 - fc) * self.screen[[r0, c0]] + fc * self.screen[[r0, c1]])
+                + fr * ((1.0 - fc) * self.screen[[r1, c0]] + fc * self.screen[[r1, c1]])
+        });
+
+        // The subharmonics are not periodic over the screen, so take the
+        // full shift, in cycles reduced to a fraction in f64
+        let shift_row = self.velocity.0 as f64 * time / self.pixel_scale as f64;
+        let shift_col = self.velocity.1 as f64 * time / self.pixel_scale as f64;
+        for subharmonic in self.subharmonics.iter() {
+            let row_phasors = (0..n_pupil).map(|r| {
+                let cycles = subharmonic.f_row as f64 * (r as f64 - shift_row) * self.pixel_scale as f64;
+                Complex::from_polar(1.0, 2.0 * PI * cycles.rem_euclid(1.0) as f32)
+            }).collect::<Vec<_>>();
+            let col_phasors = (0..n_pupil).map(|c| {
+                let cycles = subharmonic.f_col as f64 * (c as f64 - shift_col) * self.pixel_scale as f64;
+                Complex::from_polar(1.0, 2.0 * PI * cycles.rem_euclid(1.0) as f32)
+            }).collect::<Vec<_>>();
+
+            phase.indexed_iter_mut().for_each(|((r, c), p)| {
+                *p += (subharmonic.coefficient * row_phasors[r] * col_phasors[c]).re;
+            });
+        }
+        phase
+    }
+}
+
+/// A layer that is extruded line by line as it moves, so it never repeats
+pub struct ExtrudingLayer {
+    screen: Array2<f32>,
+    /// Position of `screen[[0, 0]]` along (rows, cols), in pixels from where
+    /// the layer started
+    origin: (i64, i64),
+    /// (line, position along the line) of the points a new line is drawn
+    /// from, lines counted in from the edge it is added at
+    stencil: Vec<(usize, usize)>,
+    /// A new line is `mean_matrix` times the stencil values plus
+    /// `noise_matrix` times white noise
+    mean_matrix: Array2<f64>,
+    noise_matrix: Array2<f64>,
+    pixel_scale: f64,
+    /// Wind velocity in m/s along (rows, cols)
+    velocity: (f64, f64),
+    rng: StdRng,
+}
+
+impl ExtrudingLayer {
+    /// Generates an `n_screen` x `n_screen` layer with `pixel_scale` metres
+    /// per pixel. It must be at least one pixel larger than the pupil, and
+    /// any more lets the pupil move across it before lines are added.
+    pub fn new<R: Rng>(
+            turbulence: TurbulenceParameters, n_screen: usize, pixel_scale: f32,
+            wind_speed: f32, wind_direction: f32, rng: &mut R) -> Self {
+        assert!(n_screen >= 2, "ExtrudingLayer: screen must be at least 2 pixels across");
+
+        // Start from a larger periodic screen so it does not wrap within this one
+        let initial = FrozenFlowLayer::new(turbulence, 2 * n_screen, pixel_scale, 0.0, 0.0, true, rng);
+        let stencil = extrusion_stencil(n_screen);
+        let (mean_matrix, noise_matrix) = extrusion_matrices(turbulence, &stencil, n_screen, pixel_scale as f64);
+
+        let direction = (wind_direction as f64).to_radians();
+        Self {
+            screen: initial.phase(n_screen, 0.0),
+            origin: (0, 0),
+            stencil: stencil,
+            mean_matrix: mean_matrix,
+            noise_matrix: noise_matrix,
+            pixel_scale: pixel_scale as f64,
+            velocity: (wind_speed as f64 * direction.sin(), wind_speed as f64 * direction.cos()),
+            rng: StdRng::seed_from_u64(rng.random()),
+        }
+    }
+
+    /// Phase on an `n_pupil` x `n_pupil` grid, `time` seconds after the
+    /// start. Time should only move forward: going back extrudes new
+    /// turbulence rather than returning to the old.
+    pub fn phase(&mut self, n_pupil: usize, time: f64) -> Array2<f32> {
+        let n_screen = self.screen.nrows();
+        assert!(n_pupil < n_screen, "ExtrudingLayer: pupil must be smaller than the screen");
+        assert!(time.is_finite(), "ExtrudingLayer: time must be finite, got {}", time);
+
+        // Frozen flow: what is over a point now was upwind of it earlier
+        let top = -self.velocity.0 * time / self.pixel_scale;
+        let left = -self.velocity.1 * time / self.pixel_scale;
+        self.extrude_to(top.floor() as i64, left.floor() as i64, n_pupil);
+
+        let row_offset = top - self.origin.0 as f64;
+        let col_offset = left - self.origin.1 as f64;
+        let rows = (0..n_pupil).map(|r| interpolation_weights(r as f64 + row_offset, n_screen)).collect::<Vec<_>>();
+        let cols = (0..n_pupil).map(|c| interpolation_weights(c as f64 + col_offset, n_screen)).collect::<Vec<_>>();
+        Array2::from_shape_fn((n_pupil, n_pupil), |(r, c)| {
+            let (r0, r1, fr) = rows[r];
+            let (c0, c1, fc) = cols[c];
+            (1.0 - fr) * ((1.0 - fc) * self.screen[[r0, c0]] + fc * self.screen[[r0, c1]])
+                + fr * ((1.0 - fc) * self.screen[[r1, c0]] + fc * self.screen[[r1, c1]])
+        })
+    }
+
+    /// Adds lines until the screen covers `n_pupil + 1` pixels from (top, left).
+    /// At most a screen's worth of lines is added along each axis: further
+    /// than that nothing of the old screen is left, so it skips ahead instead.
+    fn extrude_to(&mut self, top: i64, left: i64, n_pupil: usize) {
+        let n_screen = self.screen.nrows() as i64;
+        let last = n_screen - 1;
+        let shift = |start: i64, origin: i64| {
+            if start < origin {
+                start.saturating_sub(origin)
+            } else {
+                start.saturating_add(n_pupil as i64).saturating_sub(origin + last).max(0)
+            }
+        };
+        let (row_shift, col_shift) = (shift(top, self.origin.0), shift(left, self.origin.1));
+        if row_shift.unsigned_abs() > n_screen as u64 || col_shift.unsigned_abs() > n_screen as u64 {
+            warn!("ExtrudingLayer: skipping ({}, {}) pixels ahead, more than the screen size", row_shift, col_shift);
+        }
+        if row_shift.unsigned_abs() > n_screen as u64 {
+            self.origin.0 += row_shift - row_shift.signum() * n_screen;
+        }
+        if col_shift.unsigned_abs() > n_screen as u64 {
+            self.origin.1 += col_shift - col_shift.signum() * n_screen;
+        }
+
+        while top < self.origin.0 {
+            self.add_line(true, true);
+        }
+        while top + n_pupil as i64 > self.origin.0 + last {
+            self.add_line(true, false);
+        }
+        while left < self.origin.1 {
+            self.add_line(false, true);
+        }
+        while left + n_pupil as i64 > self.origin.1 + last {
+            self.add_line(false, false);
+        }
+    }
+
+    /// Draws a new row (or column) before the first one (or after the
+    /// last) and drops the one at the other edge. By symmetry of the square
+    /// screen the same matrices serve all four edges.
+    fn add_line(&mut self, row: bool, first: bool) {
+        let n_screen = self.screen.nrows();
+        let edge = |line: usize| if first { line } else { n_screen - 1 - line };
+        let stencil_values = Array1::from_iter(self.stencil.iter().map(|&(line, k)| {
+            let value = if row { self.screen[[edge(line), k]] } else { self.screen[[k, edge(line)]] };
+            value as f64
+        }));
+        let noise = Array1::from_iter((0..n_screen).map(|_| self.rng.sample::<f64, _>(StandardNormal)));
+        let new_line = (self.mean_matrix.dot(&stencil_values) + self.noise_matrix.dot(&noise)).mapv(|p| p as f32);
+
+        let mut screen = if row { self.screen.view_mut() } else { self.screen.view_mut().reversed_axes() };
+        if first {
+            for line in (1..n_screen).rev() {
+                let previous = screen.row(line - 1).to_owned();
+                screen.row_mut(line).assign(&previous);
+            }
+            screen.row_mut(0).assign(&new_line);
+        } else {
+            for line in 0..n_screen - 1 {
+                let next = screen.row(line + 1).to_owned();
+                screen.row_mut(line).assign(&next);
+            }
+            screen.row_mut(n_screen - 1).assign(&new_line);
+        }
+
+        let step = if first { -1 } else { 1 };
+        if row {
+            self.origin.0 += step;
+        } else {
+            self.origin.1 += step;
+        }
+    }
+}
+
+/// The two lines nearest the new one in full, then lines 2, 4, 8, ... in
+/// sampled every that many pixels, plus the last pixel of each line
+fn extrusion_stencil(n_screen: usize) -> Vec<(usize, usize)> {
+    let mut stencil = (0..2).flat_map(|line| (0..n_screen).map(move |k| (line, k))).collect::<Vec<_>>();
+    let mut line = 2;
+    while line < n_screen {
+        stencil.extend((0..n_screen).step_by(line).map(|k| (line, k)));
+        if !(n_screen - 1).is_multiple_of(line) {
+            stencil.push((line, n_screen - 1));
+        }
+        line *= 2;
+    }
+    stencil
+}
+
+/// Conditional mean and noise matrices for a new line one pixel before
+/// line 0, from the covariances of the new line (x) and the stencil (z):
+/// mean = C_xz C_zz⁻¹ and noise noise^T = C_xx - mean C_zx
+fn extrusion_matrices(
+        turbulence: TurbulenceParameters, stencil: &[(usize, usize)],
+        n_screen: usize, pixel_scale: f64) -> (Array2<f64>, Array2<f64>) {
+    let new_line = (0..n_screen).map(|k| (-1.0, k as f64)).collect::<Vec<_>>();
+    let stencil = stencil.iter().map(|&(line, k)| (line as f64, k as f64)).collect::<Vec<_>>();
+    let covariance = |a: &[(f64, f64)], b: &[(f64, f64)]| {
+        Array2::from_shape_fn((a.len(), b.len()), |(i, j)| {
+            let distance = (a[i].0 - b[j].0).hypot(a[i].1 - b[j].1) * pixel_scale;
+            turbulence.phase_covariance(distance)
+        })
+    };
+    let c_xx = covariance(&new_line, &new_line);
+    let c_xz = covariance(&new_line, &stencil);
+    let c_zz = covariance(&stencil, &stencil);
+
+    // The covariances are dominated by the large piston variance, so only
+    // discard what is lost in f64 round-off
+    let mean_matrix = c_xz.dot(&pseudo_inverse(&c_zz, 1e-14));
+    let residual = &c_xx - &mean_matrix.dot(&c_xz.t());
+    let residual_svd = svd(&((&residual + &residual.t()) / 2.0));
+    let noise_matrix = &residual_svd.u * &residual_svd.s.mapv(f64::sqrt);
+    (mean_matrix, noise_matrix)
+}
+
+/// Multi-layer atmosphere, summing the phase of every layer on the pupil grid
+pub struct Atmosphere {
+    layers: Vec<ExtrudingLayer>,
+    n_pupil: usize,
+    frame_rate: f32,
+}
+
+impl Atmosphere {
+    /// `pixel_scale` is metres per pupil pixel and `frame_rate` converts the
+    /// camera frame number to time. Each layer screen is `n_screen` pixels
+    /// across, at least one more than the pupil. Setting up the extrusion
+    /// takes an SVD about three times the screen size, so keep it close.
+    pub fn new(
+            turbulence: TurbulenceParameters, profile: &[LayerProfile],
+            n_pupil: usize, pixel_scale: f32, n_screen: usize, frame_rate: f32, seed: u64) -> Self {
+        assert!(n_screen > n_pupil, "Atmosphere: screen must be larger than the pupil");
+        assert!(frame_rate > 0.0, "Atmosphere: frame_rate must be positive, got {}", frame_rate);
+        let mut rng = StdRng::seed_from_u64(seed);
+        let total_weight: f32 = profile.iter().map(|layer| layer.cn2_weight).sum();
+
+        let layers = profile.iter().map(|layer| {
+            ExtrudingLayer::new(
+                turbulence.scaled(layer.cn2_weight / total_weight), n_screen, pixel_scale,
+                layer.wind_speed, layer.wind_direction, &mut rng)
+        }).collect::<Vec<_>>();
+
+        info!("Atmosphere: {} layers, r0: {} m, L0: {} m", layers.len(), turbulence.r0, turbulence.outer_scale);
+
+        Self {
+            layers: layers,
+            n_pupil: n_pupil,
+            frame_rate: frame_rate,
+        }
+    }
+}
+
+impl PhaseScreen for Atmosphere {
+    fn phase(&mut self, frame_number: u64) -> Array2<f32> {
+        let time = frame_number as f64 / self.frame_rate as f64;
+        let mut phase = Array2::<f32>::zeros((self.n_pupil, self.n_pupil));
+        for layer in self.layers.iter_mut() {
+            phase += &layer.phase(self.n_pupil, time);
+        }
+        phase
+    }
+}
+
+/// Neighbouring screen pixels and the fractional distance between them
+/// for a position on a periodic screen
+fn interpolation_weights(position: f64, n_screen: usize) -> (usize, usize, f32) {
+    let floor = position.floor();
+    let index = (floor as i64).rem_euclid(n_screen as i64) as usize;
+    (index, (index + 1) % n_screen, (position - floor) as f32)
+}
+
+/// x^(5/6) K_5/6(x), the modified Bessel function of the second kind scaled
+/// to stay finite at 0, from K_ν(x) = ∫ exp(-x cosh t) cosh(ν t) dt over
+/// t > 0. The integrand is smooth and falls off double exponentially, so the
+/// trapezoidal rule converges quickly.
+fn scaled_bessel_k_5_6(x: f64) -> f64 {
+    let nu = 5.0 / 6.0;
+    if x < 1e-12 {
+        return GAMMA_5_6 * 2.0_f64.powf(nu - 1.0);
+    }
+    let step = 0.05;
+    let t_max = (50.0 / x).max(1.0).acosh() + step;
+    let n_steps = (t_max / step).ceil() as usize;
+    let integral = 0.5 * (-x).exp()
+        + (1..=n_steps).map(|i| {
+            let t = i as f64 * step;
+            (-x * t.cosh()).exp() * (nu * t).cosh()
+        }).sum::<f64>();
+    x.powf(nu) * integral * step
+}
+
+fn complex_normal<R: Rng>(rng: &mut R) -> Complex<f32> {
+    Complex::new(rng.sample(StandardNormal), rng.sample(StandardNormal))
+}
+
+/// Periodic phase screen from filtering white noise by the von Kármán spectrum
+fn fft_phase_screen<R: Rng>(turbulence: TurbulenceParameters, n_screen: usize, pixel_scale: f32, rng: &mut R) -> Array2<f32> {
+    let del_f = 1.0 / (n_screen as f32 * pixel_scale);
+    let frequencies = fft_frequencies(n_screen).iter().map(|f| f / pixel_scale).collect::<Vec<_>>();
+
+    let mut spectrum = Array2::from_shape_fn((n_screen, n_screen), |(i, j)| {
+        let f = (frequencies[i].powi(2) + frequencies[j].powi(2)).sqrt();
+        if f == 0.0 {
+            Complex::new(0.0, 0.0)
+        } else {
+            complex_normal(rng) * turbulence.phase_psd(f).sqrt() * del_f
+        }
+    });
+    ifft2(&mut spectrum);
+    spectrum.mapv(|c| c.re)
+}
+
+/// Three levels of subharmonics below the lowest FFT frequency of a screen
+/// `screen_size` metres across
+fn generate_subharmonics<R: Rng>(turbulence: TurbulenceParameters, screen_size: f32, rng: &mut R) -> Vec<Subharmonic> {
+    let mut subharmonics = Vec::new();
+    for level in 1..=3 {
+        let del_f = 1.0 / (3.0_f32.powi(level) * screen_size);
+        for a in -1..=1 {
+            for b in -1..=1 {
+                if a == 0 && b == 0 {
+                    continue;
+                }
+                let f_row = a as f32 * del_f;
+                let f_col = b as f32 * del_f;
+                let f = (f_row * f_row + f_col * f_col).sqrt();
+                subharmonics.push(Subharmonic {
+                    f_row: f_row,
+                    f_col: f_col,
+                    coefficient: complex_normal(rng) * turbulence.phase_psd(f).sqrt() * del_f,
+                });
+            }
+        }
+    }
+    subharmonics
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frozen_flow_translation() {
+        let turbulence = TurbulenceParameters { r0: 0.1, outer_scale: 20.0, inner_scale: 0.0 };
+        let mut rng = StdRng::seed_from_u64(1);
+        // One pixel per second along the columns
+        let layer = FrozenFlowLayer::new(turbulence, 64, 0.05, 0.05, 0.0, true, &mut rng);
+
+        let before = layer.phase(32, 0.0);
+        let after = layer.phase(32, 1.0);
+        for r in 0..32 {
+            for c in 1..32 {
+                assert!((after[[r, c]] - before[[r, c - 1]]).abs() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn test_kolmogorov_structure_function() {
+        // Well inside the outer scale D(r) = 6.88 (r / r0)^(5/3)
+        let turbulence = TurbulenceParameters { r0: 0.1, outer_scale: 1000.0, inner_scale: 0.0 };
+        let mut rng = StdRng::seed_from_u64(2);
+        let pixel_scale = 0.01;
+        let separation = 4;
+        let n_screen = 256;
+
+        let mut total = 0.0;
+        let mut count = 0;
+        for _ in 0..4 {
+            let layer = FrozenFlowLayer::new(turbulence, n_screen, pixel_scale, 0.0, 0.0, true, &mut rng);
+            let phase = layer.phase(n_screen, 0.0);
+            for r in 0..n_screen - separation {
+                for c in 0..n_screen - separation {
+                    total += (phase[[r + separation, c]] - phase[[r, c]]).powi(2);
+                    total += (phase[[r, c + separation]] - phase[[r, c]]).powi(2);
+                    count += 2;
+                }
+            }
+        }
+        let measured = total / count as f32;
+        let expected = 6.88 * (separation as f32 * pixel_scale / turbulence.r0).powf(5.0 / 3.0);
+        assert!((measured / expected - 1.0).abs() < 0.2, "measured: {}, expected: {}", measured, expected);
+    }
+
+    #[test]
+    fn test_von_karman_covariance() {
+        let turbulence = TurbulenceParameters { r0: 0.1, outer_scale: 100.0, inner_scale: 0.0 };
+        // Piston variance of 0.0863 (L0 / r0)^(5/3) and, well inside the
+        // outer scale, the Kolmogorov structure function less the leading
+        // outer scale term
+        let variance = turbulence.phase_covariance(0.0);
+        assert!((variance / (0.0863 * 1000.0_f64.powf(5.0 / 3.0)) - 1.0).abs() < 1e-3);
+        for r in [0.01, 0.05, 0.2] {
+            let structure = 2.0 * (variance - turbulence.phase_covariance(r));
+            let expected = 6.88 * (r / 0.1_f64).powf(5.0 / 3.0) * (1.0 - 1.485 * (r / 100.0_f64).powf(1.0 / 3.0));
+            assert!((structure / expected - 1.0).abs() < 0.01, "r: {}, {} != {}", r, structure, expected);
+        }
+    }
+
+    #[test]
+    fn test_extruding_layer_translates_without_repeating() {
+        let turbulence = TurbulenceParameters { r0: 0.1, outer_scale: 20.0, inner_scale: 0.0 };
+        let mut rng = StdRng::seed_from_u64(3);
+        // One pixel per second, along the columns then against the rows
+        for (direction, shift) in [(0.0, (0, 1)), (-90.0, (-1, 0))] {
+            let mut layer = ExtrudingLayer::new(turbulence, 17, 0.05, 0.05, direction, &mut rng);
+            let mut before = layer.phase(16, 0.0);
+            for time in 1..40 {
+                let after = layer.phase(16, time as f64);
+                for r in 1..15 {
+                    for c in 1..15 {
+                        let moved = before[[(r as i64 - shift.0) as usize, (c as i64 - shift.1) as usize]];
+                        assert!((after[[r, c]] - moved).abs() < 1e-3);
+                    }
+                }
+                before = after;
+            }
+            // A periodic screen would be back where it started
+            let start = layer.phase(16, 17.0);
+            let later = layer.phase(16, 34.0);
+            assert!((&start - &later).iter().map(|d| d.abs()).sum::<f32>() > 16.0 * 16.0 * 0.1);
+        }
+    }
+
+    #[test]
+    fn test_extruding_layer_skips_ahead_after_a_long_gap() {
+        let turbulence = TurbulenceParameters { r0: 0.1, outer_scale: 20.0, inner_scale: 0.0 };
+        let mut rng = StdRng::seed_from_u64(5);
+        let mut layer = ExtrudingLayer::new(turbulence, 17, 0.05, 10.0, 30.0, &mut rng);
+        layer.phase(16, 0.0);
+        // Billions of pixels away, but only a screen's worth of lines is drawn
+        let phase = layer.phase(16, 1e7);
+        assert!(phase.iter().all(|p| p.is_finite()));
+        let next = layer.phase(16, 1e7 + 0.005);
+        assert!((&phase - &next).iter().all(|d| d.abs() < 10.0));
+    }
+
+    #[test]
+    fn test_extruded_structure_function() {
+        let turbulence = TurbulenceParameters { r0: 0.1, outer_scale: 100.0, inner_scale: 0.0 };
+        let mut rng = StdRng::seed_from_u64(4);
+        let pixel_scale = 0.01;
+        let (n_screen, n_pupil, separation) = (17, 16, 2);
+        // Diagonal wind of one pixel per second along each axis, so new rows
+        // and columns are both extruded and each sample is a fresh screen
+        let mut layer = ExtrudingLayer::new(turbulence, n_screen, pixel_scale, pixel_scale * 2.0_f32.sqrt(), 45.0, &mut rng);
+
+        let mut total = 0.0;
+        let mut count = 0;
+        for time in (1..=100).map(|i| (i * n_screen) as f64) {
+            let phase = layer.phase(n_pupil, time);
+            for r in 0..n_pupil - separation {
+                for c in 0..n_pupil - separation {
+                    total += (phase[[r + separation, c]] - phase[[r, c]]).powi(2);
+                    total += (phase[[r, c + separation]] - phase[[r, c]]).powi(2);
+                    count += 2;
+                }
+            }
+        }
+        let measured = total / count as f32;
+        let r = separation as f32 * pixel_scale;
+        let expected = 6.88 * (r / turbulence.r0).powf(5.0 / 3.0) * (1.0 - 1.485 * (r / turbulence.outer_scale).powf(1.0 / 3.0));
+        assert!((measured / expected - 1.0).abs() < 0.15, "measured: {}, expected: {}", measured, expected);
+    }
+}
@@ -0,0 +1,67 @@
+/// DM influence function models for the simulation
+///
+/// The surface is built from separable Gaussian influence functions on a
+/// rectangular actuator grid, so a surface is two small matrix products
+/// rather than a (pupil pixels x actuators) matrix.
+///
+use ndarray::{Array1, Array2};
+
+pub struct InfluenceFunctions {
+    n_act_rows: usize,
+    n_act_cols: usize,
+    /// Influence of each actuator row on each pupil row, (n_pupil, n_act_rows)
+    row_functions: Array2<f32>,
+    /// Influence of each actuator column on each pupil column, (n_pupil, n_act_cols)
+    col_functions: Array2<f32>,
+    stroke: f32,
+}
+
+impl InfluenceFunctions {
+    /// Gaussian influence functions on an `n_act_rows` x `n_act_cols` grid spanning
+    /// an `n_pupil` x `n_pupil` pupil, with the outer actuators on the pupil edge.
+    /// `coupling` is the fraction of a poke seen at the neighbouring actuator and
+    /// `stroke` the surface phase, in radians, for a unit command.
+    pub fn gaussian(n_act_rows: usize, n_act_cols: usize, n_pupil: usize, coupling: f32, stroke: f32) -> Self {
+        assert!(coupling > 0.0 && coupling < 1.0, "InfluenceFunctions: coupling must be in (0, 1), got {}", coupling);
+        Self {
+            n_act_rows: n_act_rows,
+            n_act_cols: n_act_cols,
+            row_functions: gaussian_1d(n_act_rows, n_pupil, coupling),
+            col_functions: gaussian_1d(n_act_cols, n_pupil, coupling),
+            stroke: stroke,
+        }
+    }
+
+    /// Number of actuators, ordered row by row across the grid
+    pub fn n_acts(&self) -> usize {
+        self.n_act_rows * self.n_act_cols
+    }
+
+    pub fn n_pupil(&self) -> usize {
+        self.row_functions.nrows()
+    }
+
+    /// Grid (row, col) of each actuator, in actuator order
+    pub fn actuator_grid(&self) -> (usize, usize) {
+        (self.n_act_rows, self.n_act_cols)
+    }
+
+    /// Surface phase on the pupil grid for the given actuator commands
+    pub fn surface(&self, commands: &Array1<f32>) -> Array2<f32> {
+        assert_eq!(commands.len(), self.n_acts(), "InfluenceFunctions: expected {} commands, got {}", self.n_acts(), commands.len());
+        let commands = commands.view().into_shape_with_order((self.n_act_rows, self.n_act_cols)).unwrap();
+        self.stroke * self.row_functions.dot(&commands).dot(&self.col_functions.t())
+    }
+}
+
+/// One dimensional Gaussians for `n_act` actuators evenly spaced over `n_pupil` pixels
+fn gaussian_1d(n_act: usize, n_pupil: usize, coupling: f32) -> Array2<f32> {
+    let pitch = if n_act > 1 { (n_pupil - 1) as f32 / (n_act - 1) as f32 } else { n_pupil as f32 };
+    let offset = if n_act > 1 { 0.0 } else { (n_pupil - 1) as f32 / 2.0 };
+    let sigma = pitch / (-2.0 * coupling.ln()).sqrt();
+
+    Array2::from_shape_fn((n_pupil, n_act), |(pixel, act)| {
+        let d = pixel as f32 - (offset + act as f32 * pitch);
+        (-d * d / (2.0 * sigma * sigma)).exp()
+    })
+}
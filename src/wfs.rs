@@ -4,34 +4,238 @@
 /// frame, and output vectors representin wavefront measurements.
 /// The WFS also is responsible for calibrating detector pixels
 /// 
-use log::{trace, debug, info, warn};
-use ndarray::{Array, array, Array1, Array2, Array3, s};
-use std::sync::{Arc, Mutex};
+/// Sensors implement the `WavefrontSensor` trait so `AOLoop` can run any of
+/// them. `ShackHartmann` is the default, `pyramid::PyramidWFS` the other.
+///
+use log::{trace, info};
+use ndarray::{Array, array, Array1, Array2, Array3, ArrayView2, s};
+use std::fmt;
+use std::ops::Range;
+use std::sync::{Arc, Mutex, RwLock};
 
 pub mod centreofgravity;
-use centreofgravity::{simple_centre_of_gravity, threshold_centre_of_gravity};
+pub mod correlation;
+pub mod matchedfilter;
+pub mod pyramid;
+pub mod recording;
+use centreofgravity::{
+    CentroidAlgorithm, simple_centre_of_gravity, threshold_centre_of_gravity, weighted_centre_of_gravity,
+    windowed_centre_of_gravity, brightest_pixels_centre_of_gravity,
+};
 use rayon::prelude::*;
+use recording::SubapRecorder;
+
+/// Detector calibration applied to the pixels before measuring, as full
+/// frames: (raw - dark - background) / flat
+#[derive(Debug, Clone, PartialEq)]
+pub struct PixelCalibration {
+    pub dark: Array2<f32>,
+    pub background: Array2<f32>,
+    pub flat: Array2<f32>,
+}
+
+impl PixelCalibration {
+    /// No dark or background and a uniform flat
+    pub fn new(n_rows: usize, n_cols: usize) -> Self {
+        Self {
+            dark: Array2::<f32>::zeros((n_rows, n_cols)),
+            background: Array2::<f32>::zeros((n_rows, n_cols)),
+            flat: Array2::<f32>::ones((n_rows, n_cols)),
+        }
+    }
+}
+
+/// A sensor turning detector frames into wavefront measurements
+pub trait WavefrontSensor: Send + Sync {
+    fn n_measurements(&self) -> usize;
+
+    /// Index of the detector this sensor reads in the loop's camera list
+    fn detector_id(&self) -> usize;
+
+    /// Number of pixel rows in the detector frames this sensor expects
+    fn n_rows(&self) -> usize;
+
+    /// Number of pixel columns in the detector frames this sensor expects
+    fn n_cols(&self) -> usize;
+
+    /// Measures `frame` into `measurements`, which holds `n_measurements()`
+    /// values, so the loop does not allocate every frame
+    fn measure_into(&self, frame: &ArrayView2<u16>, measurements: &mut Array1<f32>);
+
+    fn measure(&self, frame: &ArrayView2<u16>) -> Array1<f32> {
+        let mut measurements = Array1::<f32>::zeros(self.n_measurements());
+        self.measure_into(frame, &mut measurements);
+        measurements
+    }
+
+    /// Replaces the dark, background and flat frames. Safe to call while
+    /// the loop is running, it takes effect on the next measurement.
+    fn set_pixel_calibration(&self, calibration: PixelCalibration);
+
+    fn pixel_calibration(&self) -> PixelCalibration;
+
+    /// Starts measuring a frame that arrives a band of rows at a time.
+    /// Sensors that cannot measure part of a frame wait for every row.
+    fn stream(&self) -> Box<dyn MeasurementStream + '_> {
+        Box::new(WholeFrameStream {
+            wfs: self,
+            measurements: Array1::<f32>::zeros(self.n_measurements()),
+            complete: false,
+        })
+    }
+}
+
+/// Incremental measurement of one frame as its rows arrive from the top
+pub trait MeasurementStream {
+    /// Measures what the rows received so far allow. `frame` holds every
+    /// row received so far, from the top. Returns the ranges of
+    /// `measurements` that changed.
+    fn update(&mut self, frame: &ArrayView2<u16>) -> Vec<Range<usize>>;
+
+    /// Measurements so far. Parts not yet updated hold their value from the
+    /// previous frame.
+    fn measurements(&self) -> &Array1<f32>;
+
+    /// True once every measurement has been updated
+    fn is_complete(&self) -> bool;
+
+    /// Rows needed before more measurements can be updated
+    fn rows_needed(&self) -> usize;
+
+    /// Starts on the next frame, keeping the measurements until they are replaced
+    fn reset(&mut self);
+}
+
+/// Stream for sensors that need the whole frame, see `WavefrontSensor::stream`
+struct WholeFrameStream<'a, W: WavefrontSensor + ?Sized> {
+    wfs: &'a W,
+    measurements: Array1<f32>,
+    complete: bool,
+}
+
+impl<W: WavefrontSensor + ?Sized> MeasurementStream for WholeFrameStream<'_, W> {
+    fn update(&mut self, frame: &ArrayView2<u16>) -> Vec<Range<usize>> {
+        if self.complete || frame.nrows() < self.wfs.n_rows() {
+            return Vec::new();
+        }
+        self.wfs.measure_into(frame, &mut self.measurements);
+        self.complete = true;
+        let all = 0..self.measurements.len();
+        vec![all]
+    }
+
+    fn measurements(&self) -> &Array1<f32> {
+        &self.measurements
+    }
+
+    fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    fn rows_needed(&self) -> usize {
+        self.wfs.n_rows()
+    }
+
+    fn reset(&mut self) {
+        self.complete = false;
+    }
+}
+
+/// A centroid algorithm whose weights, references or kernels do not match
+/// the sensor's sub-apertures
+#[derive(Debug, Clone, PartialEq)]
+pub struct CentroidAlgorithmError(pub String);
+
+impl fmt::Display for CentroidAlgorithmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid centroid algorithm: {}", self.0)
+    }
+}
+
+impl std::error::Error for CentroidAlgorithmError {}
+
+/// Shared handle to a `ShackHartmann`'s centroid algorithm, to change it once
+/// the sensor is in the loop. Every change is checked against the sensor's
+/// sub-apertures before it is applied.
+#[derive(Clone)]
+pub struct CentroidAlgorithmHandle {
+    algorithm: Arc<RwLock<CentroidAlgorithm>>,
+    n_subaps: usize,
+    pixels_per_subap: usize,
+}
+
+impl CentroidAlgorithmHandle {
+    /// Changes how the spots are found. Takes effect on the next measurement.
+    pub fn set(&self, algorithm: CentroidAlgorithm) -> Result<(), CentroidAlgorithmError> {
+        self.check(&algorithm)?;
+        info!("ShackHartmann: centroid algorithm {}", algorithm_name(&algorithm));
+        *self.algorithm.write().unwrap() = algorithm;
+        Ok(())
+    }
+
+    /// Applies `change` to a copy of the current algorithm and swaps it in
+    /// if it still fits the sensor, e.g. to replace a matched filter's
+    /// kernels. The sensor keeps measuring with the old algorithm meanwhile.
+    pub fn modify<T>(&self, change: impl FnOnce(&mut CentroidAlgorithm) -> T) -> Result<T, CentroidAlgorithmError> {
+        let mut algorithm = self.algorithm.read().unwrap().clone();
+        let result = change(&mut algorithm);
+        self.check(&algorithm)?;
+        *self.algorithm.write().unwrap() = algorithm;
+        Ok(result)
+    }
+
+    pub fn get(&self) -> CentroidAlgorithm {
+        self.algorithm.read().unwrap().clone()
+    }
+
+    fn check(&self, algorithm: &CentroidAlgorithm) -> Result<(), CentroidAlgorithmError> {
+        let subaps_shape = (self.n_subaps, self.pixels_per_subap, self.pixels_per_subap);
+        let invalid = |reason: &str| Err(CentroidAlgorithmError(reason.to_string()));
+        match algorithm {
+            CentroidAlgorithm::Weighted(weights) if weights.dim() != subaps_shape => {
+                invalid("weights must be (n_subaps, pixels_per_subap, pixels_per_subap)")
+            }
+            CentroidAlgorithm::Correlation(correlation) if correlation.references().dim() != subaps_shape => {
+                invalid("references must be (n_subaps, pixels_per_subap, pixels_per_subap)")
+            }
+            CentroidAlgorithm::MatchedFilter { filter, fallback } => {
+                let kernels_shape = (3, self.pixels_per_subap, self.pixels_per_subap);
+                if filter.n_subaps() != self.n_subaps {
+                    invalid("matched filter must have kernels for n_subaps")
+                } else if !(0..self.n_subaps).filter_map(|i| filter.kernels(i)).all(|k| k.dim() == kernels_shape) {
+                    invalid("matched filter kernels must be (3, pixels_per_subap, pixels_per_subap)")
+                } else {
+                    self.check(fallback)
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// A `PixelCalibration` and the same frames cut into sub-apertures
+struct SubapCalibration {
+    frames: PixelCalibration,
+    dark: Array3<f32>,
+    background: Array3<f32>,
+    flat: Array3<f32>,
+}
 
-/// Constructs a new Wavefront Sensor object;
-pub struct WFS {
-    n_rows: usize,
-    n_cols: usize,
-    n_measurements: usize,
-} 
-#[allow(dead_code)]
 pub struct ShackHartmann {
     n_rows: usize,
     n_cols: usize,
-    pub n_measurements: usize,
+    n_measurements: usize,
     n_subaps: usize,
     pixels_per_subap: usize,
     subap_coordinates: Vec<Vec<usize>>,
-    dark_subaps: Array3<f32>,
-    flat_subaps: Array3<f32>,
-    bg_subaps: Array3<f32>,
-    cal_subaps: Array3<f32>,
-    pub detector_id: usize,
-    measurements: Arc<Mutex<Array1<f32>>>,
+    /// (row after the last pixel row, sub-aperture) in readout order
+    subap_order: Vec<(usize, usize)>,
+    pixel_calibration: RwLock<SubapCalibration>,
+    centroid_algorithm: Arc<RwLock<CentroidAlgorithm>>,
+    /// Last measured spot positions, all x then all y, for windowed centroiding
+    spot_positions: Mutex<Array1<f32>>,
+    recorder: Arc<SubapRecorder>,
+    detector_id: usize,
 }
 
 impl ShackHartmann {
@@ -44,16 +248,21 @@ impl ShackHartmann {
         let n_subaps = subap_coordinates.len();
         let n_measurements = 2 * n_subaps;
 
-        let cal_subaps = Array3::<f32>::zeros((n_subaps, pixels_per_subap, pixels_per_subap));
-        let dark_subaps = Array3::<f32>::zeros((n_subaps, pixels_per_subap, pixels_per_subap));
-        let bg_subaps = Array3::<f32>::zeros((n_subaps, pixels_per_subap, pixels_per_subap));
-        let flat_subaps = Array3::<f32>::ones((n_subaps, pixels_per_subap, pixels_per_subap));
+        let pixel_calibration = SubapCalibration {
+            frames: PixelCalibration::new(n_rows, n_cols),
+            dark: Array3::<f32>::zeros((n_subaps, pixels_per_subap, pixels_per_subap)),
+            background: Array3::<f32>::zeros((n_subaps, pixels_per_subap, pixels_per_subap)),
+            flat: Array3::<f32>::ones((n_subaps, pixels_per_subap, pixels_per_subap)),
+        };
 
         info!("ShackHartmann: Created new ShackHartmann Sensor");
         info!("n_subaps: {}", n_subaps);
         info!("n_measurements: {}", n_measurements);
 
-        let measurements = Arc::new(Mutex::new(Array1::<f32>::zeros(n_measurements)));
+        let mut subap_order: Vec<(usize, usize)> = subap_coordinates.iter().enumerate()
+            .map(|(i, subap_coords)| (subap_coords[1], i))
+            .collect();
+        subap_order.sort();
 
         Self {
             n_rows: n_rows,
@@ -62,61 +271,272 @@ impl ShackHartmann {
             n_subaps: n_subaps,
             pixels_per_subap: pixels_per_subap,
             subap_coordinates: subap_coordinates,
-            cal_subaps: cal_subaps,
-            dark_subaps: dark_subaps,
-            bg_subaps: bg_subaps,
-            flat_subaps: flat_subaps,
+            subap_order: subap_order,
+            pixel_calibration: RwLock::new(pixel_calibration),
+            centroid_algorithm: Arc::new(RwLock::new(CentroidAlgorithm::Simple)),
+            spot_positions: Mutex::new(Array1::<f32>::zeros(n_measurements)),
+            recorder: Arc::new(SubapRecorder::new(n_subaps, pixels_per_subap)),
             detector_id: detector_id,
-            measurements: measurements
         }
     }
 
-    pub fn measure(&self, frame: &Array2<u16>) -> Array1<f32> {
+    /// Starts measuring a frame that arrives a band of rows at a time,
+    /// measuring each sub-aperture as soon as its last row is in
+    pub fn slope_stream(&self) -> SlopeStream<'_> {
+        SlopeStream {
+            wfs: self,
+            frame: Array2::<u16>::zeros((self.n_rows, self.n_cols)),
+            rows_received: 0,
+            next_subap: 0,
+            measurements: Array1::<f32>::zeros(self.n_measurements),
+        }
+    }
+
+    /// Measures only the listed sub-apertures into `measurements`, which
+    /// holds all x then all y as from `measure_into`. `frame` only needs to
+    /// reach the last row of those sub-apertures.
+    fn measure_subaps(&self, frame: &ArrayView2<u16>, subaps: &[usize], measurements: &mut Array1<f32>) {
+        let algorithm = self.centroid_algorithm.read().unwrap();
+        let calibration = self.pixel_calibration.read().unwrap();
+        let mut spot_positions = self.spot_positions.lock().unwrap();
+        let recording = self.recorder.is_recording();
+        let slopes = subaps.par_iter().map(|&i| {
+            let last_position = (spot_positions[i], spot_positions[i + self.n_subaps]);
+            let (position, cal_subap) = self.measure_subap(frame, i, &calibration, &algorithm, last_position);
+            (position, if recording { Some(cal_subap) } else { None })
+        }).collect::<Vec<_>>();
+        for (&i, ((x, y), cal_subap)) in subaps.iter().zip(slopes) {
+            if let Some(cal_subap) = cal_subap {
+                self.recorder.add(i, &cal_subap);
+            }
+            measurements[i] = x;
+            measurements[i + self.n_subaps] = y;
+            spot_positions[i] = x;
+            spot_positions[i + self.n_subaps] = y;
+        }
+    }
+
+    /// Changes how the spots are found. Takes effect on the next measurement.
+    pub fn set_centroid_algorithm(&self, algorithm: CentroidAlgorithm) -> Result<(), CentroidAlgorithmError> {
+        self.centroid_algorithm().set(algorithm)
+    }
 
-        let measurements_mutex = &self.measurements;
+    /// Shared handle to the centroid algorithm, to change it once the sensor
+    /// is in the loop
+    pub fn centroid_algorithm(&self) -> CentroidAlgorithmHandle {
+        CentroidAlgorithmHandle {
+            algorithm: Arc::clone(&self.centroid_algorithm),
+            n_subaps: self.n_subaps,
+            pixels_per_subap: self.pixels_per_subap,
+        }
+    }
 
-        self.subap_coordinates.par_iter().enumerate().for_each(|(i, subap_coords)| {
+    /// Shared handle to record calibrated sub-aperture images from the
+    /// frames this sensor measures, e.g. to capture correlation references
+    /// while the loop runs
+    pub fn recorder(&self) -> Arc<SubapRecorder> {
+        Arc::clone(&self.recorder)
+    }
 
-            // Slice out the data of that Sub-aperture (maybe want to actually copy the data out for processing)
-            let subap_data = frame.slice(
-                s![
-                    subap_coords[0]..subap_coords[1],
-                    subap_coords[2]..subap_coords[3]
-                    ]).to_owned();
+    pub fn n_subaps(&self) -> usize {
+        self.n_subaps
+    }
 
-            let mut cal_subap = self.cal_subaps.slice(s![i, .., ..]).to_owned();
-            let dark_subap = self.dark_subaps.slice(s![i, .., ..]);
-            let bg_subap = self.bg_subaps.slice(s![i, .., ..]);
-            let flat_subap = self.flat_subaps.slice(s![i, .., ..]);
+    /// Copies each sub-aperture's part of a full detector frame
+    fn subap_pixels(&self, frame: &Array2<f32>) -> Array3<f32> {
+        let mut subaps = Array3::<f32>::zeros((self.n_subaps, self.pixels_per_subap, self.pixels_per_subap));
+        for (i, subap_coords) in self.subap_coordinates.iter().enumerate() {
+            subaps.slice_mut(s![i, .., ..]).assign(&frame.slice(s![
+                subap_coords[0]..subap_coords[1],
+                subap_coords[2]..subap_coords[3]
+                ]));
+        }
+        subaps
+    }
 
-            // Pixel calibration
-            cal_subap.iter_mut().enumerate().for_each(|(idx, pixel)| {
-                let x = idx / self.pixels_per_subap;
-                let y = idx % self.pixels_per_subap;
-                *pixel = (subap_data[[x, y]] as f32 - bg_subap[[x, y]] - dark_subap[[x, y]]) / flat_subap[[x, y]];
-            });
+    /// Spot position in sub-aperture `i` and its calibrated pixels
+    fn measure_subap(&self, frame: &ArrayView2<u16>, i: usize, calibration: &SubapCalibration, algorithm: &CentroidAlgorithm, last_position: (f32, f32)) -> ((f32, f32), Array2<f32>) {
+        let subap_coords = &self.subap_coordinates[i];
 
-            // CoG computation
-            let (x, y) = simple_centre_of_gravity(&cal_subap);
- 
-            // Update the measurements vector
-            let mut measurements = measurements_mutex.lock().unwrap();
-            measurements[i] = x;
-            measurements[i + self.n_subaps] = y;
+        // Slice out the data of that Sub-aperture (maybe want to actually copy the data out for processing)
+        let subap_data = frame.slice(
+            s![
+                subap_coords[0]..subap_coords[1],
+                subap_coords[2]..subap_coords[3]
+                ]).to_owned();
 
+        let dark_subap = calibration.dark.slice(s![i, .., ..]);
+        let bg_subap = calibration.background.slice(s![i, .., ..]);
+        let flat_subap = calibration.flat.slice(s![i, .., ..]);
 
-            trace!("subap: {}", i);
-            trace!("subap_coords: x: {}-{}, y: {}-{}", subap_coords[0], subap_coords[1], subap_coords[2], subap_coords[3]);
-            trace!("subap_data:\n{:?}", subap_data);
-            trace!("cal_subap:\n{:?}", cal_subap);
-            trace!("x: {}, y: {}", x, y);
+        // Pixel calibration
+        let cal_subap = Array2::from_shape_fn(subap_data.dim(), |(x, y)| {
+            (subap_data[[x, y]] as f32 - bg_subap[[x, y]] - dark_subap[[x, y]]) / flat_subap[[x, y]]
         });
-        measurements_mutex.lock().unwrap().to_owned()
+
+        // CoG computation
+        let (x, y) = Self::centroid(algorithm, i, &cal_subap, last_position);
+
+        trace!("subap: {}", i);
+        trace!("subap_coords: x: {}-{}, y: {}-{}", subap_coords[0], subap_coords[1], subap_coords[2], subap_coords[3]);
+        trace!("subap_data:\n{:?}", subap_data);
+        trace!("cal_subap:\n{:?}", cal_subap);
+        trace!("x: {}, y: {}", x, y);
+        ((x, y), cal_subap)
+    }
+
+    /// Spot position in the calibrated pixels of sub-aperture `i`
+    fn centroid(algorithm: &CentroidAlgorithm, i: usize, cal_subap: &Array2<f32>, last_position: (f32, f32)) -> (f32, f32) {
+        match algorithm {
+            CentroidAlgorithm::Simple => simple_centre_of_gravity(cal_subap),
+            CentroidAlgorithm::Threshold(threshold) => threshold_centre_of_gravity(cal_subap, *threshold),
+            CentroidAlgorithm::Weighted(weights) => weighted_centre_of_gravity(cal_subap, &weights.slice(s![i, .., ..])),
+            CentroidAlgorithm::Windowed { radius } => windowed_centre_of_gravity(cal_subap, last_position, *radius),
+            CentroidAlgorithm::BrightestPixels(n_pixels) => brightest_pixels_centre_of_gravity(cal_subap, *n_pixels),
+            CentroidAlgorithm::Correlation(correlation) => correlation.centroid(i, cal_subap),
+            CentroidAlgorithm::MatchedFilter { filter, fallback } => filter.centroid(i, cal_subap)
+                .unwrap_or_else(|| Self::centroid(fallback, i, cal_subap, last_position)),
+        }
     }
 
 
 }
 
+fn algorithm_name(algorithm: &CentroidAlgorithm) -> String {
+    match algorithm {
+        CentroidAlgorithm::Simple => "simple".to_string(),
+        CentroidAlgorithm::Threshold(_) => "threshold".to_string(),
+        CentroidAlgorithm::Weighted(_) => "weighted".to_string(),
+        CentroidAlgorithm::Windowed { .. } => "windowed".to_string(),
+        CentroidAlgorithm::BrightestPixels(_) => "brightest pixels".to_string(),
+        CentroidAlgorithm::Correlation(_) => "correlation".to_string(),
+        CentroidAlgorithm::MatchedFilter { fallback, .. } => format!("matched filter, else {}", algorithm_name(fallback)),
+    }
+}
+
+impl WavefrontSensor for ShackHartmann {
+    fn n_measurements(&self) -> usize {
+        self.n_measurements
+    }
+
+    fn detector_id(&self) -> usize {
+        self.detector_id
+    }
+
+    fn n_rows(&self) -> usize {
+        self.n_rows
+    }
+
+    fn n_cols(&self) -> usize {
+        self.n_cols
+    }
+
+    fn measure_into(&self, frame: &ArrayView2<u16>, measurements: &mut Array1<f32>) {
+        let subaps = (0..self.n_subaps).collect::<Vec<_>>();
+        self.measure_subaps(frame, &subaps, measurements);
+    }
+
+    fn set_pixel_calibration(&self, calibration: PixelCalibration) {
+        assert_eq!(calibration.dark.dim(), (self.n_rows, self.n_cols), "ShackHartmann: dark frame has the wrong shape");
+        assert_eq!(calibration.background.dim(), (self.n_rows, self.n_cols), "ShackHartmann: background frame has the wrong shape");
+        assert_eq!(calibration.flat.dim(), (self.n_rows, self.n_cols), "ShackHartmann: flat frame has the wrong shape");
+        let subap_calibration = SubapCalibration {
+            dark: self.subap_pixels(&calibration.dark),
+            background: self.subap_pixels(&calibration.background),
+            flat: self.subap_pixels(&calibration.flat),
+            frames: calibration,
+        };
+        *self.pixel_calibration.write().unwrap() = subap_calibration;
+    }
+
+    fn pixel_calibration(&self) -> PixelCalibration {
+        self.pixel_calibration.read().unwrap().frames.clone()
+    }
+
+    fn stream(&self) -> Box<dyn MeasurementStream + '_> {
+        Box::new(self.slope_stream())
+    }
+}
+
+/// Incremental measurement of one frame by a `ShackHartmann`. Rows arrive
+/// from the top, and each sub-aperture is calibrated and centroided as soon
+/// as its last row is in, so the slopes are nearly done when the frame is.
+pub struct SlopeStream<'a> {
+    wfs: &'a ShackHartmann,
+    frame: Array2<u16>,
+    rows_received: usize,
+    /// Position in the WFS's readout order of the next sub-aperture to measure
+    next_subap: usize,
+    measurements: Array1<f32>,
+}
+
+impl SlopeStream<'_> {
+    /// Copies in the next `rows` of the frame and measures the sub-apertures
+    /// they complete, returning the ranges of `measurements` that changed
+    pub fn push_rows(&mut self, rows: ArrayView2<u16>) -> Vec<Range<usize>> {
+        let end = self.rows_received + rows.nrows();
+        assert!(end <= self.wfs.n_rows, "SlopeStream: pushed {} rows into a frame of {}", end, self.wfs.n_rows);
+        self.frame.slice_mut(s![self.rows_received..end, ..]).assign(&rows);
+        self.rows_received = end;
+        let frame = self.frame.slice(s![..end, ..]);
+        Self::measure_completed(self.wfs, &frame, &mut self.next_subap, &mut self.measurements)
+    }
+
+    fn measure_completed(wfs: &ShackHartmann, frame: &ArrayView2<u16>, next_subap: &mut usize, measurements: &mut Array1<f32>) -> Vec<Range<usize>> {
+        let remaining = &wfs.subap_order[*next_subap..];
+        let n_completed = remaining.partition_point(|&(last_row, _)| last_row <= frame.nrows());
+        if n_completed == 0 {
+            return Vec::new();
+        }
+        let subaps = remaining[..n_completed].iter().map(|&(_, i)| i).collect::<Vec<_>>();
+        wfs.measure_subaps(frame, &subaps, measurements);
+        *next_subap += n_completed;
+        measurement_ranges(&subaps, wfs.n_subaps)
+    }
+}
+
+impl MeasurementStream for SlopeStream<'_> {
+    /// As `push_rows`, for frames already in memory as they read out, e.g. a
+    /// camera's ring buffer, which are measured in place
+    fn update(&mut self, frame: &ArrayView2<u16>) -> Vec<Range<usize>> {
+        assert!(frame.nrows() >= self.rows_received, "SlopeStream: frame went back from {} to {} rows", self.rows_received, frame.nrows());
+        self.rows_received = frame.nrows();
+        Self::measure_completed(self.wfs, frame, &mut self.next_subap, &mut self.measurements)
+    }
+
+    /// All x then all y
+    fn measurements(&self) -> &Array1<f32> {
+        &self.measurements
+    }
+
+    fn is_complete(&self) -> bool {
+        self.next_subap == self.wfs.subap_order.len()
+    }
+
+    fn rows_needed(&self) -> usize {
+        self.wfs.subap_order.get(self.next_subap).map_or(self.rows_received, |&(last_row, _)| last_row)
+    }
+
+    fn reset(&mut self) {
+        self.rows_received = 0;
+        self.next_subap = 0;
+    }
+}
+
+/// Ranges of the measurement vector, x then y, belonging to `subaps`
+fn measurement_ranges(subaps: &[usize], n_subaps: usize) -> Vec<Range<usize>> {
+    let mut indices = subaps.iter().flat_map(|&i| [i, i + n_subaps]).collect::<Vec<_>>();
+    indices.sort_unstable();
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    for i in indices {
+        match ranges.last_mut() {
+            Some(range) if range.end == i => range.end += 1,
+            _ => ranges.push(i..i + 1),
+        }
+    }
+    ranges
+}
+
 
 pub fn test_shackhartmann () {
     let n_rows = 16;
@@ -135,8 +555,163 @@ pub fn test_shackhartmann () {
     let frame = Array::from_iter(0..(n_rows*n_cols) as u16).to_shape((n_rows, n_cols)).unwrap().to_owned();
 
 
-    let measurements = sh.measure(&frame);
+    let measurements = sh.measure(&frame.view());
     println!("{:?}", measurements);
     let expected = array![1.4117646, 1.2444444, 0.44799995, 0.42966747, 0.08823538, 0.07777786, 0.028000116, 0.026854277];
     assert_eq!(measurements, expected);
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use correlation::{CorrelationCentroider, CorrelationMethod, PeakInterpolation};
+    use matchedfilter::MatchedFilter;
+
+    #[test]
+    fn test_measurement_ranges_coalesce() {
+        assert_eq!(measurement_ranges(&[2, 0, 1], 4), vec![0..3, 4..7]);
+        assert_eq!(measurement_ranges(&[3, 1], 4), vec![1..2, 3..4, 5..6, 7..8]);
+    }
+
+    #[test]
+    fn test_streamed_rows_match_full_frame() {
+        let subap_coordinates = vec![
+            vec![0, 4, 0, 4],
+            vec![0, 4, 4, 8],
+            vec![4, 8, 0, 4],
+            vec![4, 8, 4, 8],
+        ];
+        let sh = ShackHartmann::new(8, 8, 4, subap_coordinates, 0);
+        let frame = Array::from_shape_fn((8, 8), |(r, c)| ((r * 7 + c * 3) % 11) as u16);
+        let expected = sh.measure(&frame.view());
+
+        let mut stream = sh.slope_stream();
+        for _ in 0..2 {
+            assert!(stream.push_rows(frame.slice(s![0..3, ..])).is_empty());
+            assert_eq!(stream.rows_needed(), 4);
+            // Completes the top row of sub-apertures only
+            assert_eq!(stream.push_rows(frame.slice(s![3..6, ..])), vec![0..2, 4..6]);
+            assert!(!stream.is_complete());
+            stream.push_rows(frame.slice(s![6..8, ..]));
+            assert!(stream.is_complete());
+            assert_eq!(stream.measurements(), &expected);
+            stream.reset();
+        }
+
+        // Measuring in place as the rows of the frame arrive
+        for rows in [5, 8] {
+            stream.update(&frame.slice(s![..rows, ..]));
+        }
+        assert_eq!(stream.measurements(), &expected);
+    }
+
+    #[test]
+    fn test_pixel_calibration_removes_dark() {
+        let sh = ShackHartmann::new(4, 4, 2, vec![vec![0, 2, 0, 2], vec![2, 4, 2, 4]], 0);
+        let frame = Array::from_shape_fn((4, 4), |(r, c)| (r + 2 * c) as u16);
+        let expected = sh.measure(&frame.view());
+
+        let mut calibration = PixelCalibration::new(4, 4);
+        calibration.dark.fill(7.0);
+        calibration.flat.fill(2.0);
+        sh.set_pixel_calibration(calibration.clone());
+        assert_eq!(sh.pixel_calibration(), calibration);
+        // A dark offset and a uniform flat do not move the centroids
+        assert_eq!(sh.measure(&(&frame + 7).view()), expected);
+    }
+
+    #[test]
+    fn test_centroid_algorithm_switch_at_runtime() {
+        let sh = ShackHartmann::new(8, 8, 8, vec![vec![0, 8, 0, 8]], 0);
+        // Background of 2 and a 2x2 spot at (2, -2) from the middle
+        let mut frame = Array2::<u16>::from_elem((8, 8), 2);
+        frame.slice_mut(s![5..7, 1..3]).fill(20);
+
+        let simple = sh.measure(&frame.view());
+        assert!(simple[0] < 1.5);
+
+        let handle = sh.centroid_algorithm();
+        handle.set(CentroidAlgorithm::BrightestPixels(4)).unwrap();
+        assert_eq!(sh.measure(&frame.view()), array![2.0, -2.0]);
+
+        // Weights for the wrong number of sub-apertures are refused and the
+        // sensor keeps its algorithm
+        assert!(handle.set(CentroidAlgorithm::gaussian(2, 8, 3.0)).is_err());
+        assert_eq!(handle.get(), CentroidAlgorithm::BrightestPixels(4));
+
+        // Windowed CoG starts from the last spot position, so a bright
+        // pixel far from the spot is ignored
+        sh.set_centroid_algorithm(CentroidAlgorithm::Windowed { radius: 1.5 }).unwrap();
+        frame[[0, 7]] = 1000;
+        let windowed = sh.measure(&frame.view());
+        assert!((windowed[0] - 2.0).abs() < 1e-6 && (windowed[1] + 2.0).abs() < 1e-6);
+
+        sh.set_centroid_algorithm(CentroidAlgorithm::gaussian(1, 8, 3.0)).unwrap();
+        let weighted = sh.measure(&frame.view());
+        assert!(weighted[0] < 2.0);
+    }
+
+    #[test]
+    fn test_correlation_reference_from_live_frames() {
+        let sh = ShackHartmann::new(8, 16, 8, vec![vec![0, 8, 0, 8], vec![0, 8, 8, 16]], 0);
+        let spot = |row: usize, col: usize| {
+            let mut frame = Array2::<u16>::zeros((8, 16));
+            for c0 in [0, 8] {
+                frame.slice_mut(s![row..row + 2, c0 + col..c0 + col + 3]).fill(100);
+            }
+            frame
+        };
+
+        // Capture the references while measuring with the default centroider
+        let recorder = sh.recorder();
+        recorder.start(2);
+        for _ in 0..2 {
+            sh.measure(&spot(3, 2).view());
+        }
+        let references = recorder.take().unwrap().mean;
+        let correlation = CorrelationCentroider::new(references, 2, CorrelationMethod::Direct, PeakInterpolation::Parabolic);
+        sh.set_centroid_algorithm(CentroidAlgorithm::Correlation(correlation)).unwrap();
+
+        // Shifts are measured from the reference spots
+        assert_eq!(sh.measure(&spot(3, 2).view()), array![0.0, 0.0, 0.0, 0.0]);
+        let shifted = sh.measure(&spot(4, 1).view());
+        assert!(shifted.iter().zip([1.0, 1.0, -1.0, -1.0]).all(|(m, e)| (m - e).abs() < 0.1), "{}", shifted);
+    }
+
+    #[test]
+    fn test_matched_filter_rebuilt_from_live_frames() {
+        let sh = ShackHartmann::new(8, 16, 8, vec![vec![0, 8, 0, 8], vec![0, 8, 8, 16]], 0);
+        // Spots elongated along the columns, centred `shift` from the middle
+        let spots = |shift: (f32, f32)| Array2::from_shape_fn((8, 16), |(i, j)| {
+            let dr = i as f32 - 3.5 - shift.0;
+            let dc = (j % 8) as f32 - 3.5 - shift.1;
+            (1000.0 * (-(dr * dr) / 2.0 - (dc * dc) / 8.0).exp()).round() as u16 + 10
+        });
+
+        // Start from kernels on a reference off by a pixel, then rebuild them
+        // from frames recorded while measuring
+        let stale = spots((1.0, 0.0)).mapv(|p| p as f32);
+        let stale = Array3::from_shape_fn((2, 8, 8), |(i, r, c)| stale[[r, c + 8 * i]]);
+        let mut filter = MatchedFilter::from_mean(&stale, 1.0, 2.0);
+        filter.select_only(&[0]);
+        sh.set_centroid_algorithm(CentroidAlgorithm::MatchedFilter { filter: filter, fallback: Box::new(CentroidAlgorithm::Simple) }).unwrap();
+        assert!(sh.measure(&spots((0.0, 0.0)).view())[0] < -0.5);
+
+        let recorder = sh.recorder();
+        let algorithm = sh.centroid_algorithm();
+        assert!(!matchedfilter::rebuild_from_recorder(&recorder, &algorithm, 1.0, 2.0));
+        recorder.start(3);
+        for shift in [(0.1, 0.0), (0.0, 0.0), (-0.1, 0.0)] {
+            sh.measure(&spots(shift).view());
+        }
+        assert!(matchedfilter::rebuild_from_recorder(&recorder, &algorithm, 1.0, 2.0));
+
+        // Sub-aperture 0 now measures from the recorded spot, sub-aperture 1
+        // is not selected and stays on the simple CoG
+        let measured = sh.measure(&spots((0.2, -0.3)).view());
+        assert!((measured[0] - 0.2).abs() < 0.05 && (measured[2] + 0.3).abs() < 0.05, "{}", measured);
+        let simple = simple_centre_of_gravity(&spots((0.2, -0.3)).slice(s![.., 8..]).mapv(|p| p as f32));
+        assert_eq!((measured[1], measured[3]), simple);
+    }
+}
@@ -1,6 +1,9 @@
-use ndarray::Array2;
-use std::time::{Instant, SystemTime};
-use log::{debug, info, warn};
+use ndarray::{Array2, Array3, ArrayView2};
+
+use super::correlation::CorrelationCentroider;
+use super::matchedfilter::MatchedFilter;
+use std::time::Instant;
+use log::debug;
 
 pub fn simple_centre_of_gravity(data: &Array2<f32>) -> (f32, f32) {
     debug!("Simple COG");
@@ -40,13 +43,138 @@ pub fn threshold_centre_of_gravity(data: &Array2<f32>, threshold: f32) -> (f32,
     (x / total - n_rows as f32 / 2.0 + 0.5, y / total - n_rows as f32 / 2.0 + 0.5)
 }
 
+/// How a `ShackHartmann` finds the spot in each sub-aperture
+#[derive(Debug, Clone, PartialEq)]
+pub enum CentroidAlgorithm {
+    Simple,
+    /// Only pixels above the threshold
+    Threshold(f32),
+    /// Pixels multiplied by a weight map per sub-aperture,
+    /// (n_subaps, pixels_per_subap, pixels_per_subap)
+    Weighted(Array3<f32>),
+    /// Only pixels within `radius` pixels of where the spot was last seen
+    Windowed { radius: f32 },
+    /// Only the brightest N pixels, above the next brightest
+    BrightestPixels(usize),
+    /// Shift against a reference image, for extended or elongated spots
+    Correlation(CorrelationCentroider),
+    /// Linearised matched filter on the selected sub-apertures, for LGS
+    /// spots, and `fallback` on the rest
+    MatchedFilter { filter: MatchedFilter, fallback: Box<CentroidAlgorithm> },
+}
+
+impl CentroidAlgorithm {
+    /// The same Gaussian weight map, centred on the sub-aperture, for every sub-aperture
+    pub fn gaussian(n_subaps: usize, pixels_per_subap: usize, fwhm: f32) -> Self {
+        let weights = gaussian_weights(pixels_per_subap, fwhm);
+        let weights = weights.broadcast((n_subaps, pixels_per_subap, pixels_per_subap)).unwrap().to_owned();
+        CentroidAlgorithm::Weighted(weights)
+    }
+}
+
+/// Gaussian weight map peaking at 1 on the centre of an `n_pixels` square
+pub fn gaussian_weights(n_pixels: usize, fwhm: f32) -> Array2<f32> {
+    let sigma = fwhm / (2.0 * (2.0 * 2.0_f32.ln()).sqrt());
+    let centre = (n_pixels as f32 - 1.0) / 2.0;
+    Array2::from_shape_fn((n_pixels, n_pixels), |(i, j)| {
+        let r2 = (i as f32 - centre).powi(2) + (j as f32 - centre).powi(2);
+        (-r2 / (2.0 * sigma * sigma)).exp()
+    })
+}
+
+/// Centre of gravity of `data` times `weights`. A weight map matched to the
+/// spot cuts the noise from pixels that hold little of its light.
+pub fn weighted_centre_of_gravity(data: &Array2<f32>, weights: &ArrayView2<f32>) -> (f32, f32) {
+    let (n_rows, n_cols) = data.dim();
+    let mut x: f32 = 0.0;
+    let mut y: f32 = 0.0;
+    let mut total: f32 = 0.0;
+    for i in 0..n_rows {
+        for j in 0..n_cols {
+            let val = data[[i, j]] * weights[[i, j]];
+            x += val * i as f32;
+            y += val * j as f32;
+            total += val;
+        }
+    }
+    centre_offset(x, y, total, n_rows, n_cols).unwrap_or((0.0, 0.0))
+}
+
+/// Centre of gravity of the pixels within `radius` of `centre`, an offset
+/// from the middle of `data` in the units the centroids are returned in.
+/// If there is no light in the window, e.g. the spot jumped out of it, the
+/// spot is found again from the positive pixels of the whole of `data`, and
+/// only without any light at all is `centre` returned.
+pub fn windowed_centre_of_gravity(data: &Array2<f32>, centre: (f32, f32), radius: f32) -> (f32, f32) {
+    let (n_rows, n_cols) = data.dim();
+    let row_centre = centre.0 + (n_rows as f32 - 1.0) / 2.0;
+    let col_centre = centre.1 + (n_cols as f32 - 1.0) / 2.0;
+    let mut x: f32 = 0.0;
+    let mut y: f32 = 0.0;
+    let mut total: f32 = 0.0;
+    let mut x_all: f32 = 0.0;
+    let mut y_all: f32 = 0.0;
+    let mut total_all: f32 = 0.0;
+    for i in 0..n_rows {
+        for j in 0..n_cols {
+            let val = data[[i, j]];
+            if (i as f32 - row_centre).powi(2) + (j as f32 - col_centre).powi(2) <= radius * radius {
+                x += val * i as f32;
+                y += val * j as f32;
+                total += val;
+            }
+            if val > 0.0 {
+                x_all += val * i as f32;
+                y_all += val * j as f32;
+                total_all += val;
+            }
+        }
+    }
+    centre_offset(x, y, total, n_rows, n_cols)
+        .or_else(|| centre_offset(x_all, y_all, total_all, n_rows, n_cols))
+        .unwrap_or(centre)
+}
+
+/// Centre of gravity of the brightest `n_pixels` pixels, with the level of
+/// the next brightest pixel subtracted so the background drops out
+pub fn brightest_pixels_centre_of_gravity(data: &Array2<f32>, n_pixels: usize) -> (f32, f32) {
+    let (n_rows, n_cols) = data.dim();
+    let mut sorted = data.iter().copied().collect::<Vec<_>>();
+    sorted.sort_unstable_by(|a, b| b.total_cmp(a));
+    let n_pixels = n_pixels.min(sorted.len());
+    let floor = sorted.get(n_pixels).copied().unwrap_or(0.0);
+    let mut x: f32 = 0.0;
+    let mut y: f32 = 0.0;
+    let mut total: f32 = 0.0;
+    for i in 0..n_rows {
+        for j in 0..n_cols {
+            let val = data[[i, j]] - floor;
+            if val > 0.0 {
+                x += val * i as f32;
+                y += val * j as f32;
+                total += val;
+            }
+        }
+    }
+    centre_offset(x, y, total, n_rows, n_cols).unwrap_or((0.0, 0.0))
+}
+
+/// Position relative to the middle of the data, or `None` without light
+fn centre_offset(x: f32, y: f32, total: f32, n_rows: usize, n_cols: usize) -> Option<(f32, f32)> {
+    if total > 0.0 {
+        Some((x / total - (n_rows as f32 - 1.0) / 2.0, y / total - (n_cols as f32 - 1.0) / 2.0))
+    } else {
+        None
+    }
+}
+
 fn bench_simple_centre_of_gravity() {
 
     let n = 1e6 as u32;    
     let data = Array2::<f32>::ones((8, 8));
     let t = Instant::now();
     for _ in 0..n {
-        let (x, y) = simple_centre_of_gravity(&data);
+        std::hint::black_box(simple_centre_of_gravity(&data));
     }
     println!("Nx = 8: Simple COG: {} ns", 1e9 * t.elapsed().as_secs_f32() / n as f32);
 
@@ -54,7 +182,7 @@ fn bench_simple_centre_of_gravity() {
     let data = Array2::<f32>::ones((16, 16));
     let t = Instant::now();
     for _ in 0..n {
-        let (x, y) = simple_centre_of_gravity(&data);
+        std::hint::black_box(simple_centre_of_gravity(&data));
     }
     println!("Nx = 16: Simple COG: {} ns", 1e9 * t.elapsed().as_secs_f32() / n as f32);
 
@@ -62,7 +190,7 @@ fn bench_simple_centre_of_gravity() {
     let data = Array2::<f32>::ones((32, 32));
     let t = Instant::now();
     for _ in 0..n {
-        let (x, y) = simple_centre_of_gravity(&data);
+        std::hint::black_box(simple_centre_of_gravity(&data));
     }
     println!("Nx = 32: Simple COG: {} ns", 1e9 * t.elapsed().as_secs_f32() / n as f32);
 
@@ -96,5 +224,63 @@ mod tests {
         assert_eq!(x, 0.0);
         assert_eq!(y, 0.0);
     }
+
+    /// Background of 1 with a 2x2 spot of 10 covering rows 5-6, cols 1-2
+    fn spot() -> Array2<f32> {
+        let mut data = Array2::<f32>::ones((8, 8));
+        for (i, j) in [(5, 1), (5, 2), (6, 1), (6, 2)] {
+            data[[i, j]] = 10.0;
+        }
+        data
+    }
+
+    #[test]
+    fn test_brightest_pixels_ignore_background() {
+        // The spot is at (5.5, 1.5), i.e. (2, -2) from the middle
+        assert_eq!(brightest_pixels_centre_of_gravity(&spot(), 4), (2.0, -2.0));
+        // The simple CoG is pulled towards the middle by the background
+        let (x, _) = simple_centre_of_gravity(&spot());
+        assert!(x < 1.0);
+    }
+
+    #[test]
+    fn test_windowed_tracks_spot() {
+        let mut data = spot();
+        data[[0, 7]] = 50.0;
+        // A window around the spot's last position ignores the stray pixel
+        let (x, y) = windowed_centre_of_gravity(&(&data - 1.0), (2.0, -2.0), 1.5);
+        assert_eq!((x, y), (2.0, -2.0));
+        // No light at all keeps the last position
+        let empty = Array2::<f32>::zeros((8, 8));
+        assert_eq!(windowed_centre_of_gravity(&empty, (1.0, 1.0), 1.5), (1.0, 1.0));
+    }
+
+    #[test]
+    fn test_windowed_reacquires_spot_outside_radius() {
+        // The spot jumps from (-2, 2) to (2, -2), leaving the window dark
+        let data = &spot() - 1.0;
+        let (x, y) = windowed_centre_of_gravity(&data, (-2.0, 2.0), 1.5);
+        assert_eq!((x, y), (2.0, -2.0));
+        // and the next frame tracks it from there with the window again
+        let mut data = data;
+        data[[0, 7]] = 50.0;
+        assert_eq!(windowed_centre_of_gravity(&data, (x, y), 1.5), (2.0, -2.0));
+    }
+
+    #[test]
+    fn test_weighted_uniform_matches_simple() {
+        let data = spot();
+        let (x, y) = weighted_centre_of_gravity(&data, &Array2::<f32>::ones((8, 8)).view());
+        let (simple_x, simple_y) = simple_centre_of_gravity(&data);
+        assert!((x - simple_x).abs() < 1e-5 && (y - simple_y).abs() < 1e-5);
+
+        let weights = gaussian_weights(8, 4.0);
+        assert_eq!(weights.dim(), (8, 8));
+        assert!((weights[[3, 3]] - weights[[4, 4]]).abs() < 1e-6);
+        assert!(weights[[0, 0]] < weights[[3, 4]]);
+        // Weighting towards the middle pulls the estimate in, but keeps its sign
+        let (x, y) = weighted_centre_of_gravity(&(&data - 1.0), &weights.view());
+        assert!(x > 0.0 && x < 2.0 && y < 0.0 && y > -2.0);
+    }
 }
 
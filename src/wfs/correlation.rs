@@ -0,0 +1,230 @@
+/// RUST-AO Correlation Centroiding
+///
+/// Finds the shift of each sub-aperture image against a reference image by
+/// cross-correlation instead of a centre of gravity. For extended sources,
+/// such as solar granulation, and elongated LGS spots truncated by the
+/// sub-aperture, the CoG is biased while the correlation peak still tracks
+/// the image. The shifts are relative to the reference, so references
+/// captured from live frames make the current wavefront the zero point.
+///
+/// The correlation is computed directly over the search window, or by FFT,
+/// which is faster for large sub-apertures and windows. Both give the same
+/// map. The peak is refined to sub-pixel precision by fitting a parabola or
+/// a Gaussian through it and its neighbours on each axis.
+///
+use ndarray::{Array2, Array3, ArrayView2, s};
+use rustfft::num_complex::Complex;
+
+use crate::fft::{fft2, ifft2};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CorrelationMethod {
+    Direct,
+    Fft,
+}
+
+/// Sub-pixel refinement of the correlation peak
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PeakInterpolation {
+    Parabolic,
+    /// Parabola through the log of the peak, exact for Gaussian peaks
+    Gaussian,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorrelationCentroider {
+    /// (n_subaps, pixels_per_subap, pixels_per_subap)
+    references: Array3<f32>,
+    search_radius: usize,
+    method: CorrelationMethod,
+    interpolation: PeakInterpolation,
+}
+
+impl CorrelationCentroider {
+    /// One reference image per sub-aperture. Shifts of up to `search_radius`
+    /// pixels each way are searched.
+    pub fn new(references: Array3<f32>, search_radius: usize, method: CorrelationMethod, interpolation: PeakInterpolation) -> Self {
+        let (_, n_rows, n_cols) = references.dim();
+        assert!(search_radius < n_rows && search_radius < n_cols, "CorrelationCentroider: search radius must be smaller than the sub-aperture");
+        Self {
+            references: references,
+            search_radius: search_radius,
+            method: method,
+            interpolation: interpolation,
+        }
+    }
+
+    /// The same reference image for every sub-aperture, e.g. one good
+    /// sub-aperture image of the solar surface
+    pub fn shared_reference(reference: &Array2<f32>, n_subaps: usize, search_radius: usize, method: CorrelationMethod, interpolation: PeakInterpolation) -> Self {
+        let (n_rows, n_cols) = reference.dim();
+        let references = reference.broadcast((n_subaps, n_rows, n_cols)).unwrap().to_owned();
+        Self::new(references, search_radius, method, interpolation)
+    }
+
+    /// Replaces the references, e.g. with the mean of a `SubapRecorder`
+    /// recording. Must keep the same shape.
+    pub fn set_references(&mut self, references: Array3<f32>) {
+        assert_eq!(references.dim(), self.references.dim(), "CorrelationCentroider: references have the wrong shape");
+        self.references = references;
+    }
+
+    pub fn references(&self) -> &Array3<f32> {
+        &self.references
+    }
+
+    pub fn set_search_radius(&mut self, search_radius: usize) {
+        let (_, n_rows, n_cols) = self.references.dim();
+        assert!(search_radius < n_rows && search_radius < n_cols, "CorrelationCentroider: search radius must be smaller than the sub-aperture");
+        self.search_radius = search_radius;
+    }
+
+    pub fn set_method(&mut self, method: CorrelationMethod) {
+        self.method = method;
+    }
+
+    pub fn set_interpolation(&mut self, interpolation: PeakInterpolation) {
+        self.interpolation = interpolation;
+    }
+
+    /// Shift of `data` against the reference of sub-aperture `subap`, in
+    /// pixels along (rows, cols)
+    pub fn centroid(&self, subap: usize, data: &Array2<f32>) -> (f32, f32) {
+        let reference = self.references.slice(s![subap, .., ..]);
+        let map = match self.method {
+            CorrelationMethod::Direct => correlate_direct(data, &reference, self.search_radius),
+            CorrelationMethod::Fft => correlate_fft(data, &reference, self.search_radius),
+        };
+        peak_position(&map, self.interpolation)
+    }
+}
+
+/// Cross-correlation of the mean-subtracted `data` and `reference` for
+/// shifts up to `search_radius` each way. Element (r + dr, r + dc) is the
+/// correlation with `data` moved by (dr, dc) from the reference.
+pub fn correlate_direct(data: &Array2<f32>, reference: &ArrayView2<f32>, search_radius: usize) -> Array2<f32> {
+    let (data, reference) = zero_mean(data, reference);
+    let (n_rows, n_cols) = data.dim();
+    let r = search_radius as isize;
+    let mut map = Array2::<f32>::zeros((2 * search_radius + 1, 2 * search_radius + 1));
+    for dr in -r..=r {
+        for dc in -r..=r {
+            let mut sum = 0.0;
+            for i in dr.max(0)..(n_rows as isize + dr.min(0)) {
+                for j in dc.max(0)..(n_cols as isize + dc.min(0)) {
+                    sum += data[[i as usize, j as usize]] * reference[[(i - dr) as usize, (j - dc) as usize]];
+                }
+            }
+            map[[(dr + r) as usize, (dc + r) as usize]] = sum;
+        }
+    }
+    map
+}
+
+/// As `correlate_direct`, through zero-padded FFTs
+pub fn correlate_fft(data: &Array2<f32>, reference: &ArrayView2<f32>, search_radius: usize) -> Array2<f32> {
+    let (data, reference) = zero_mean(data, reference);
+    let (n_rows, n_cols) = data.dim();
+    // Padding by the search radius keeps the circular correlation from
+    // wrapping within the searched shifts
+    let (m_rows, m_cols) = (n_rows + search_radius, n_cols + search_radius);
+
+    let padded = |image: &Array2<f32>| {
+        let mut padded = Array2::<Complex<f32>>::zeros((m_rows, m_cols));
+        padded.slice_mut(s![..n_rows, ..n_cols]).assign(&image.mapv(|p| Complex::new(p, 0.0)));
+        padded
+    };
+    let mut data_spectrum = padded(&data);
+    let mut reference_spectrum = padded(&reference);
+    fft2(&mut data_spectrum);
+    fft2(&mut reference_spectrum);
+
+    let mut correlation = data_spectrum * reference_spectrum.mapv(|c| c.conj());
+    ifft2(&mut correlation);
+
+    let scale = 1.0 / (m_rows * m_cols) as f32;
+    let r = search_radius as isize;
+    Array2::from_shape_fn((2 * search_radius + 1, 2 * search_radius + 1), |(i, j)| {
+        let dr = (i as isize - r).rem_euclid(m_rows as isize) as usize;
+        let dc = (j as isize - r).rem_euclid(m_cols as isize) as usize;
+        correlation[[dr, dc]].re * scale
+    })
+}
+
+/// Position of the highest value of a correlation map relative to its
+/// centre, refined to sub-pixel precision. A peak on the edge of the map is
+/// not refined along that axis.
+pub fn peak_position(map: &Array2<f32>, interpolation: PeakInterpolation) -> (f32, f32) {
+    let (n_rows, n_cols) = map.dim();
+    let ((row, col), _) = map.indexed_iter()
+        .max_by(|(_, a), (_, b)| a.total_cmp(b))
+        .unwrap();
+
+    let refine = |minus: f32, peak: f32, plus: f32| {
+        let (minus, peak, plus) = match interpolation {
+            PeakInterpolation::Gaussian if minus > 0.0 && peak > 0.0 && plus > 0.0 => (minus.ln(), peak.ln(), plus.ln()),
+            _ => (minus, peak, plus),
+        };
+        let curvature = minus - 2.0 * peak + plus;
+        if curvature < 0.0 { 0.5 * (minus - plus) / curvature } else { 0.0 }
+    };
+    let row_offset = if row > 0 && row + 1 < n_rows {
+        refine(map[[row - 1, col]], map[[row, col]], map[[row + 1, col]])
+    } else {
+        0.0
+    };
+    let col_offset = if col > 0 && col + 1 < n_cols {
+        refine(map[[row, col - 1]], map[[row, col]], map[[row, col + 1]])
+    } else {
+        0.0
+    };
+    (
+        row as f32 + row_offset - (n_rows / 2) as f32,
+        col as f32 + col_offset - (n_cols / 2) as f32,
+    )
+}
+
+fn zero_mean(data: &Array2<f32>, reference: &ArrayView2<f32>) -> (Array2<f32>, Array2<f32>) {
+    assert_eq!(data.dim(), reference.dim(), "Correlation: data and reference must be the same size");
+    let data_mean = data.mean().unwrap_or(0.0);
+    let reference_mean = reference.mean().unwrap_or(0.0);
+    (data.mapv(|p| p - data_mean), reference.mapv(|p| p - reference_mean))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Elongated Gaussian spot on a background in a 16x16 sub-aperture,
+    /// centred `shift` pixels from the middle
+    fn elongated_spot(shift: (f32, f32)) -> Array2<f32> {
+        Array2::from_shape_fn((16, 16), |(i, j)| {
+            let dr = i as f32 - 7.5 - shift.0;
+            let dc = j as f32 - 7.5 - shift.1;
+            100.0 * (-(dr * dr) / (2.0 * 1.2 * 1.2) - (dc * dc) / (2.0 * 2.5 * 2.5)).exp() + 5.0
+        })
+    }
+
+    #[test]
+    fn test_direct_and_fft_agree() {
+        let reference = elongated_spot((0.0, 0.0));
+        let data = elongated_spot((1.0, -2.0));
+        let direct = correlate_direct(&data, &reference.view(), 3);
+        let fft = correlate_fft(&data, &reference.view(), 3);
+        assert!((&direct - &fft).iter().all(|d| d.abs() < 1e-2 * direct[[3, 3]].abs()));
+        let (row, col) = peak_position(&direct, PeakInterpolation::Parabolic);
+        assert!((row - 1.0).abs() < 0.15 && (col + 2.0).abs() < 0.15, "({}, {})", row, col);
+    }
+
+    #[test]
+    fn test_subpixel_shift_of_elongated_spot() {
+        let reference = elongated_spot((0.0, 0.0));
+        for interpolation in [PeakInterpolation::Parabolic, PeakInterpolation::Gaussian] {
+            let centroider = CorrelationCentroider::shared_reference(&reference, 1, 3, CorrelationMethod::Fft, interpolation);
+            assert_eq!(centroider.centroid(0, &reference), (0.0, 0.0));
+            let (row, col) = centroider.centroid(0, &elongated_spot((0.3, 1.4)));
+            assert!((row - 0.3).abs() < 0.15, "{:?}: row {}", interpolation, row);
+            assert!((col - 1.4).abs() < 0.15, "{:?}: col {}", interpolation, col);
+        }
+    }
+}
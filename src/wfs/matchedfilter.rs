@@ -0,0 +1,267 @@
+/// RUST-AO Matched-filter Centroiding
+///
+/// Linearised matched filter for elongated LGS spots. Around a reference
+/// spot I0, a spot shifted by (x, y) with flux a is modelled as
+/// a (I0 + x dI0/dx + y dI0/dy). Each sub-aperture gets three kernels, for
+/// the row shift, the column shift and the flux, from the noise-weighted
+/// least-squares fit of that model, so a pixel counts less the noisier it
+/// is. The shift is the shift kernel applied to the pixels over the flux
+/// kernel applied to them, which leaves it independent of the spot flux.
+///
+/// Shifts are relative to the reference, so kernels rebuilt from frames
+/// recorded in closed loop make the current wavefront the zero point, as
+/// with the correlation references. The filter is linear over about the
+/// spot's width; beyond that it underestimates the shift.
+///
+use ndarray::{Array2, Array3, ArrayView2, s};
+use log::{info, warn};
+
+use super::centreofgravity::CentroidAlgorithm;
+use super::recording::SubapRecorder;
+use super::CentroidAlgorithmHandle;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchedFilter {
+    /// Per sub-aperture (3, pixels_per_subap, pixels_per_subap) row shift,
+    /// column shift and flux kernels, `None` where the reference had too
+    /// little light to fit
+    kernels: Vec<Option<Array3<f32>>>,
+    /// Sub-apertures measured with the matched filter, the rest use the fallback
+    selected: Vec<bool>,
+}
+
+impl MatchedFilter {
+    /// Kernels from one reference spot per sub-aperture and the noise
+    /// variance of each of its pixels, both
+    /// (n_subaps, pixels_per_subap, pixels_per_subap). Every sub-aperture
+    /// starts selected.
+    pub fn new(references: &Array3<f32>, variance: &Array3<f32>) -> Self {
+        let kernels = compute_kernels(references, variance);
+        Self {
+            selected: vec![true; kernels.len()],
+            kernels: kernels,
+        }
+    }
+
+    /// Kernels from the mean spots of a `SubapRecorder` recording, weighted
+    /// by their photon and read noise, see `noise_variance`
+    pub fn from_mean(mean: &Array3<f32>, gain: f32, read_noise: f32) -> Self {
+        Self::new(mean, &noise_variance(mean, gain, read_noise))
+    }
+
+    pub fn n_subaps(&self) -> usize {
+        self.kernels.len()
+    }
+
+    /// Replaces the kernels, e.g. with ones from `compute_kernels` built
+    /// while the loop runs. Keeps the selection.
+    pub fn set_kernels(&mut self, kernels: Vec<Option<Array3<f32>>>) {
+        assert_eq!(kernels.len(), self.kernels.len(), "MatchedFilter: kernels for the wrong number of sub-apertures");
+        self.kernels = kernels;
+    }
+
+    pub fn kernels(&self, subap: usize) -> Option<&Array3<f32>> {
+        self.kernels[subap].as_ref()
+    }
+
+    /// Chooses whether sub-aperture `subap` is measured with the matched
+    /// filter or the fallback algorithm
+    pub fn select(&mut self, subap: usize, selected: bool) {
+        self.selected[subap] = selected;
+    }
+
+    /// Selects exactly the listed sub-apertures, e.g. those seeing the LGS
+    pub fn select_only(&mut self, subaps: &[usize]) {
+        self.selected.fill(false);
+        for &subap in subaps {
+            self.selected[subap] = true;
+        }
+    }
+
+    /// True if sub-aperture `subap` is selected and has kernels
+    pub fn is_active(&self, subap: usize) -> bool {
+        self.selected[subap] && self.kernels[subap].is_some()
+    }
+
+    /// Shift of `data` from the reference of sub-aperture `subap`, in pixels
+    /// along (rows, cols), or `None` if the sub-aperture is not active.
+    /// No light gives no shift.
+    pub fn centroid(&self, subap: usize, data: &Array2<f32>) -> Option<(f32, f32)> {
+        if !self.selected[subap] {
+            return None;
+        }
+        let kernels = self.kernels[subap].as_ref()?;
+        let apply = |k: usize| (&kernels.slice(s![k, .., ..]) * data).sum();
+        let flux = apply(2);
+        if flux > 0.0 {
+            Some((apply(0) / flux, apply(1) / flux))
+        } else {
+            Some((0.0, 0.0))
+        }
+    }
+}
+
+/// Row shift, column shift and flux kernels for every sub-aperture, see
+/// `MatchedFilter::new`
+pub fn compute_kernels(references: &Array3<f32>, variance: &Array3<f32>) -> Vec<Option<Array3<f32>>> {
+    assert_eq!(references.dim(), variance.dim(), "MatchedFilter: references and variance must be the same shape");
+    (0..references.dim().0)
+        .map(|i| subap_kernels(&references.slice(s![i, .., ..]), &variance.slice(s![i, .., ..])))
+        .collect()
+}
+
+/// Photon plus read noise variance of the pixels of `mean`, with `gain` in
+/// counts per photo-electron and `read_noise` in counts. The recorded
+/// variance is no use here: in closed loop it is mostly spot motion, which
+/// is largest on the steep pixels that carry the signal.
+pub fn noise_variance(mean: &Array3<f32>, gain: f32, read_noise: f32) -> Array3<f32> {
+    mean.mapv(|p| gain * p.max(0.0) + read_noise * read_noise)
+}
+
+/// Rebuilds the kernels of a sensor's matched filter from the mean spots of
+/// its finished recording, through the `recorder()` and `centroid_algorithm()` handles,
+/// so it can be called while the loop runs. The kernels are computed before
+/// touching the algorithm, which is only swapped once they are in. Returns
+/// false if no recording was finished, the sensor is not using a matched
+/// filter or the recording does not fit its sub-apertures.
+pub fn rebuild_from_recorder(recorder: &SubapRecorder, algorithm: &CentroidAlgorithmHandle, gain: f32, read_noise: f32) -> bool {
+    let Some(statistics) = recorder.take() else {
+        return false;
+    };
+    let kernels = compute_kernels(&statistics.mean, &noise_variance(&statistics.mean, gain, read_noise));
+    let n_fitted = kernels.iter().filter(|k| k.is_some()).count();
+
+    let rebuilt = algorithm.modify(|algorithm| {
+        let CentroidAlgorithm::MatchedFilter { filter, .. } = algorithm else {
+            return None;
+        };
+        filter.set_kernels(kernels);
+        Some(filter.n_subaps())
+    });
+    match rebuilt {
+        Ok(Some(n_subaps)) => {
+            info!("MatchedFilter: rebuilt kernels from {} frames, {} of {} sub-apertures fitted", statistics.n_frames, n_fitted, n_subaps);
+            true
+        }
+        Ok(None) => {
+            warn!("MatchedFilter: not rebuilding kernels, the sensor is not using a matched filter");
+            false
+        }
+        Err(error) => {
+            warn!("MatchedFilter: not rebuilding kernels, {}", error);
+            false
+        }
+    }
+}
+
+/// Noise-weighted least-squares fit of (row shift, column shift, flux)
+/// to the model pixels a (I0 + x dI0/dx + y dI0/dy): the kernels are the
+/// rows of (H^T W H)^-1 H^T W, with H the columns dI0/dx, dI0/dy and I0
+/// and W the inverse variance
+fn subap_kernels(reference: &ArrayView2<f32>, variance: &ArrayView2<f32>) -> Option<Array3<f32>> {
+    let (n_rows, n_cols) = reference.dim();
+    let reference = reference.mapv(|p| p as f64);
+    // Shifting the spot by +x moves its light to higher rows: dI/dx = -dI0/drow
+    let derivative = |i: usize, j: usize, along_rows: bool| {
+        let (n, k) = if along_rows { (n_rows, i) } else { (n_cols, j) };
+        let at = |k: usize| if along_rows { reference[[k, j]] } else { reference[[i, k]] };
+        if n < 2 {
+            0.0
+        } else if k == 0 {
+            -(at(1) - at(0))
+        } else if k == n - 1 {
+            -(at(k) - at(k - 1))
+        } else {
+            -(at(k + 1) - at(k - 1)) / 2.0
+        }
+    };
+
+    let mut model = Array3::<f64>::zeros((3, n_rows, n_cols));
+    let mut weights = Array2::<f64>::zeros((n_rows, n_cols));
+    for i in 0..n_rows {
+        for j in 0..n_cols {
+            model[[0, i, j]] = derivative(i, j, true);
+            model[[1, i, j]] = derivative(i, j, false);
+            model[[2, i, j]] = reference[[i, j]];
+            weights[[i, j]] = if variance[[i, j]] > 0.0 { 1.0 / variance[[i, j]] as f64 } else { 0.0 };
+        }
+    }
+
+    let normal = std::array::from_fn(|a| std::array::from_fn(|b| {
+        (&model.slice(s![a, .., ..]) * &model.slice(s![b, .., ..]) * &weights).sum()
+    }));
+    let inverse = invert3(&normal)?;
+
+    let mut kernels = Array3::<f32>::zeros((3, n_rows, n_cols));
+    for k in 0..3 {
+        for i in 0..n_rows {
+            for j in 0..n_cols {
+                let fitted = (0..3).map(|b| inverse[k][b] * model[[b, i, j]]).sum::<f64>();
+                kernels[[k, i, j]] = (fitted * weights[[i, j]]) as f32;
+            }
+        }
+    }
+    Some(kernels)
+}
+
+/// Inverse of a symmetric positive 3x3 matrix, `None` if it is close to singular
+fn invert3(m: &[[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let cofactor = |r: usize, c: usize| {
+        let (r0, r1) = ((r + 1) % 3, (r + 2) % 3);
+        let (c0, c1) = ((c + 1) % 3, (c + 2) % 3);
+        m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]
+    };
+    let det = (0..3).map(|c| m[0][c] * cofactor(0, c)).sum::<f64>();
+    let scale = m[0][0] * m[1][1] * m[2][2];
+    if scale <= 0.0 || det.abs() <= 1e-9 * scale {
+        return None;
+    }
+    Some(std::array::from_fn(|r| std::array::from_fn(|c| cofactor(c, r) / det)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Gaussian spot elongated along the columns in an 8x8 sub-aperture,
+    /// centred `shift` pixels from the middle
+    fn lgs_spot(shift: (f32, f32), flux: f32) -> Array2<f32> {
+        Array2::from_shape_fn((8, 8), |(i, j)| {
+            let dr = i as f32 - 3.5 - shift.0;
+            let dc = j as f32 - 3.5 - shift.1;
+            flux * (-(dr * dr) / (2.0 * 1.0 * 1.0) - (dc * dc) / (2.0 * 2.0 * 2.0)).exp()
+        })
+    }
+
+    fn filter() -> MatchedFilter {
+        let reference = lgs_spot((0.0, 0.0), 100.0);
+        let references = reference.broadcast((2, 8, 8)).unwrap().to_owned();
+        // Photon noise plus a read noise of 2
+        MatchedFilter::from_mean(&references, 1.0, 2.0)
+    }
+
+    #[test]
+    fn test_small_shifts_at_any_flux() {
+        let filter = filter();
+        let (row, col) = filter.centroid(0, &lgs_spot((0.0, 0.0), 100.0)).unwrap();
+        assert!(row.abs() < 1e-4 && col.abs() < 1e-4);
+        for flux in [50.0, 100.0, 400.0] {
+            let (row, col) = filter.centroid(0, &lgs_spot((0.2, -0.3), flux)).unwrap();
+            assert!((row - 0.2).abs() < 0.03, "flux {}: row {}", flux, row);
+            assert!((col + 0.3).abs() < 0.03, "flux {}: col {}", flux, col);
+        }
+    }
+
+    #[test]
+    fn test_selection_and_dark_references() {
+        let mut filter = filter();
+        filter.select_only(&[1]);
+        assert!(!filter.is_active(0));
+        assert_eq!(filter.centroid(0, &lgs_spot((0.2, 0.0), 100.0)), None);
+        assert!(filter.centroid(1, &lgs_spot((0.2, 0.0), 100.0)).is_some());
+
+        // No light in the reference leaves nothing to fit
+        let kernels = compute_kernels(&Array3::zeros((1, 8, 8)), &Array3::ones((1, 8, 8)));
+        assert_eq!(kernels, vec![None]);
+    }
+}
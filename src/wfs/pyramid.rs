@@ -0,0 +1,266 @@
+/// RUST-AO Pyramid Wavefront Sensor
+///
+/// The pyramid splits the focal plane four ways, so the detector sees four
+/// images of the pupil. Each valid pupil pixel is read in all four images,
+/// calibrated like the Shack-Hartmann pixels, and turned into either slopes
+/// or the normalised intensities themselves.
+///
+/// The pupil images are, in order: top-left (A), top-right (B), bottom-left
+/// (C) and bottom-right (D). Slopes are x = (B + D - A - C) / N and
+/// y = (C + D - A - B) / N, where N is the flux in that pixel summed over the
+/// four images (local) or the mean of that over the pupil (global).
+///
+use log::info;
+use ndarray::{Array1, Array2, ArrayView2, s};
+use std::sync::RwLock;
+
+use super::{PixelCalibration, WavefrontSensor};
+
+/// How pyramid slopes are normalised
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FluxNormalisation {
+    /// By the mean flux per pupil pixel, keeping the pixels' relative flux
+    Global,
+    /// By each pupil pixel's own flux, insensitive to pupil illumination
+    Local,
+}
+
+/// What a `PyramidWFS` outputs as its measurements
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PyramidSignal {
+    /// All x slopes then all y slopes, one per valid pupil pixel
+    Slopes(FluxNormalisation),
+    /// The valid pixels of the four pupil images in order, divided by the
+    /// mean flux per pupil pixel
+    Intensities,
+}
+
+/// A `PixelCalibration` and the same frames at the pupil pixels
+struct PupilCalibration {
+    frames: PixelCalibration,
+    /// Dark plus background and flat at each pupil pixel, (4, n_valid)
+    offset_pixels: Array2<f32>,
+    flat_pixels: Array2<f32>,
+}
+
+pub struct PyramidWFS {
+    n_rows: usize,
+    n_cols: usize,
+    /// (row, col) of each valid pixel in each of the four pupil images
+    pupil_pixels: [Vec<(usize, usize)>; 4],
+    signal: PyramidSignal,
+    pixel_calibration: RwLock<PupilCalibration>,
+    detector_id: usize,
+}
+
+impl PyramidWFS {
+    /// `pupil_origins` are the top-left (row, col) of the four pupil images
+    /// in the order A, B, C, D. `pupil_mask` marks the valid pixels of a
+    /// pupil image and is the same for all four.
+    pub fn new(
+            n_rows: usize, n_cols: usize,
+            pupil_origins: [(usize, usize); 4], pupil_mask: &Array2<bool>,
+            signal: PyramidSignal, detector_id: usize) -> Self {
+        let (mask_rows, mask_cols) = pupil_mask.dim();
+        for (row, col) in pupil_origins {
+            assert!(row + mask_rows <= n_rows && col + mask_cols <= n_cols,
+                "PyramidWFS: pupil image at ({}, {}) does not fit on the detector", row, col);
+        }
+
+        let valid = pupil_mask.indexed_iter().filter(|&(_, &v)| v).map(|(index, _)| index).collect::<Vec<_>>();
+        let pupil_pixels = pupil_origins.map(|(row, col)| {
+            valid.iter().map(|&(r, c)| (row + r, col + c)).collect::<Vec<_>>()
+        });
+        let n_valid = valid.len();
+        assert!(n_valid > 0, "PyramidWFS: the pupil mask has no valid pixels");
+
+        info!("PyramidWFS: Created new Pyramid Sensor");
+        info!("n_valid_pixels: {}", n_valid);
+
+        let pyramid = Self {
+            n_rows: n_rows,
+            n_cols: n_cols,
+            pupil_pixels: pupil_pixels,
+            signal: signal,
+            pixel_calibration: RwLock::new(PupilCalibration {
+                frames: PixelCalibration::new(n_rows, n_cols),
+                offset_pixels: Array2::<f32>::zeros((4, n_valid)),
+                flat_pixels: Array2::<f32>::ones((4, n_valid)),
+            }),
+            detector_id: detector_id,
+        };
+        info!("n_measurements: {}", pyramid.n_measurements());
+        pyramid
+    }
+
+    /// Number of valid pixels in each pupil image
+    pub fn n_valid_pixels(&self) -> usize {
+        self.pupil_pixels[0].len()
+    }
+
+    /// Switches between slopes and intensities. This changes the number of
+    /// measurements, so the loop's controller must be rebuilt to match.
+    pub fn set_signal(&mut self, signal: PyramidSignal) {
+        self.signal = signal;
+    }
+
+    pub fn get_signal(&self) -> PyramidSignal {
+        self.signal
+    }
+
+    /// Calibrated intensity of every valid pixel in each pupil image, (4, n_valid)
+    pub fn pupil_intensities(&self, frame: &ArrayView2<u16>) -> Array2<f32> {
+        let calibration = self.pixel_calibration.read().unwrap();
+        let mut intensities = Array2::<f32>::zeros((4, self.n_valid_pixels()));
+        for (p, pixels) in self.pupil_pixels.iter().enumerate() {
+            for (k, &(row, col)) in pixels.iter().enumerate() {
+                intensities[[p, k]] = (frame[[row, col]] as f32 - calibration.offset_pixels[[p, k]]) / calibration.flat_pixels[[p, k]];
+            }
+        }
+        intensities
+    }
+}
+
+impl WavefrontSensor for PyramidWFS {
+    fn n_measurements(&self) -> usize {
+        match self.signal {
+            PyramidSignal::Slopes(_) => 2 * self.n_valid_pixels(),
+            PyramidSignal::Intensities => 4 * self.n_valid_pixels(),
+        }
+    }
+
+    fn detector_id(&self) -> usize {
+        self.detector_id
+    }
+
+    fn n_rows(&self) -> usize {
+        self.n_rows
+    }
+
+    fn n_cols(&self) -> usize {
+        self.n_cols
+    }
+
+    fn measure_into(&self, frame: &ArrayView2<u16>, measurements: &mut Array1<f32>) {
+        let intensities = self.pupil_intensities(frame);
+        let (a, b, c, d) = (intensities.row(0), intensities.row(1), intensities.row(2), intensities.row(3));
+        let flux = &a + &b + c + d;
+        let mean_flux = flux.mean().unwrap_or(0.0);
+        let n_valid = self.n_valid_pixels();
+
+        match self.signal {
+            PyramidSignal::Slopes(normalisation) => {
+                for k in 0..n_valid {
+                    let norm = match normalisation {
+                        FluxNormalisation::Global => mean_flux,
+                        FluxNormalisation::Local => flux[k],
+                    };
+                    let (x, y) = if norm > 0.0 {
+                        ((b[k] + d[k] - a[k] - c[k]) / norm, (c[k] + d[k] - a[k] - b[k]) / norm)
+                    } else {
+                        (0.0, 0.0)
+                    };
+                    measurements[k] = x;
+                    measurements[k + n_valid] = y;
+                }
+            }
+            PyramidSignal::Intensities => {
+                let scale = if mean_flux > 0.0 { 1.0 / mean_flux } else { 0.0 };
+                for p in 0..4 {
+                    measurements.slice_mut(s![p * n_valid..(p + 1) * n_valid]).assign(&(&intensities.row(p) * scale));
+                }
+            }
+        }
+    }
+
+    fn set_pixel_calibration(&self, calibration: PixelCalibration) {
+        assert_eq!(calibration.dark.dim(), (self.n_rows, self.n_cols), "PyramidWFS: dark frame has the wrong shape");
+        assert_eq!(calibration.background.dim(), (self.n_rows, self.n_cols), "PyramidWFS: background frame has the wrong shape");
+        assert_eq!(calibration.flat.dim(), (self.n_rows, self.n_cols), "PyramidWFS: flat frame has the wrong shape");
+        let mut offset_pixels = Array2::<f32>::zeros((4, self.n_valid_pixels()));
+        let mut flat_pixels = Array2::<f32>::ones((4, self.n_valid_pixels()));
+        for (p, pixels) in self.pupil_pixels.iter().enumerate() {
+            for (k, &(row, col)) in pixels.iter().enumerate() {
+                offset_pixels[[p, k]] = calibration.dark[[row, col]] + calibration.background[[row, col]];
+                flat_pixels[[p, k]] = calibration.flat[[row, col]];
+            }
+        }
+        *self.pixel_calibration.write().unwrap() = PupilCalibration {
+            frames: calibration,
+            offset_pixels: offset_pixels,
+            flat_pixels: flat_pixels,
+        };
+    }
+
+    fn pixel_calibration(&self) -> PixelCalibration {
+        self.pixel_calibration.read().unwrap().frames.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    /// 2x2 pupil images on a 4x4 detector, top-right pupil pixel masked out
+    fn pyramid(signal: PyramidSignal) -> PyramidWFS {
+        let mask = array![[true, false], [true, true]];
+        PyramidWFS::new(4, 4, [(0, 0), (0, 2), (2, 0), (2, 2)], &mask, signal, 0)
+    }
+
+    /// Each pupil image uniform at the given level, plus `extra` on the
+    /// first valid pixel of pupil B
+    fn frame(levels: [u16; 4], extra: u16) -> Array2<u16> {
+        let mut frame = Array2::<u16>::zeros((4, 4));
+        for (p, (row, col)) in [(0, 0), (0, 2), (2, 0), (2, 2)].into_iter().enumerate() {
+            frame.slice_mut(s![row..row + 2, col..col + 2]).fill(levels[p]);
+        }
+        frame[[0, 2]] += extra;
+        frame
+    }
+
+    #[test]
+    fn test_slopes_and_normalisation() {
+        let mut wfs = pyramid(PyramidSignal::Slopes(FluxNormalisation::Local));
+        assert_eq!(wfs.n_valid_pixels(), 3);
+        assert_eq!(wfs.n_measurements(), 6);
+
+        // More light on the right and the bottom
+        let measurements = wfs.measure(&frame([1, 3, 1, 3], 0).view());
+        assert_eq!(measurements, array![0.5, 0.5, 0.5, 0.0, 0.0, 0.0]);
+        let measurements = wfs.measure(&frame([1, 1, 3, 3], 0).view());
+        assert_eq!(measurements, array![0.0, 0.0, 0.0, 0.5, 0.5, 0.5]);
+
+        // Only the first pixel is brighter: locally its slope grows, globally
+        // the mean flux grows and every pixel's slope shrinks a little
+        let local = wfs.measure(&frame([2, 2, 2, 2], 8).view());
+        assert_eq!(local, array![0.5, 0.0, 0.0, -0.5, 0.0, 0.0]);
+        wfs.set_signal(PyramidSignal::Slopes(FluxNormalisation::Global));
+        let global = wfs.measure(&frame([2, 2, 2, 2], 8).view());
+        let mean_flux = (16.0 + 8.0 + 8.0) / 3.0;
+        assert!((global[0] - 8.0 / mean_flux).abs() < 1e-6);
+        assert_eq!(global[1], 0.0);
+    }
+
+    #[test]
+    fn test_intensities_with_calibration() {
+        let wfs = pyramid(PyramidSignal::Intensities);
+        assert_eq!(wfs.n_measurements(), 12);
+
+        let mut calibration = PixelCalibration::new(4, 4);
+        calibration.dark.fill(10.0);
+        calibration.flat.fill(2.0);
+        wfs.set_pixel_calibration(calibration);
+
+        // After calibration every pupil pixel has 1 count in each image, so
+        // the mean flux per pupil pixel is 4
+        let measurements = wfs.measure(&(frame([2, 2, 2, 2], 0) + 10).view());
+        assert!(measurements.iter().all(|&m| (m - 0.25).abs() < 1e-6));
+    }
+
+    #[test]
+    #[should_panic(expected = "does not fit")]
+    fn test_pupil_must_fit() {
+        PyramidWFS::new(4, 4, [(0, 0), (0, 3), (2, 0), (2, 2)], &Array2::from_elem((2, 2), true), PyramidSignal::Intensities, 0);
+    }
+}
@@ -0,0 +1,138 @@
+/// RUST-AO Sub-aperture Recording
+///
+/// Accumulates the calibrated pixels of every sub-aperture over a number of
+/// frames while the sensor keeps measuring, e.g. to capture a correlation
+/// reference or rebuild matched-filter kernels from live data. The recorder
+/// is shared with the sensor, so a recording can be started and collected
+/// from outside a running loop.
+///
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use ndarray::{Array2, Array3, s};
+use log::info;
+
+/// Per-pixel statistics of the recorded sub-apertures,
+/// each (n_subaps, pixels_per_subap, pixels_per_subap)
+#[derive(Debug, Clone, PartialEq)]
+pub struct SubapStatistics {
+    pub mean: Array3<f32>,
+    /// Variance of a single frame's pixel, e.g. for noise weighting
+    pub variance: Array3<f32>,
+    pub n_frames: usize,
+}
+
+struct Recording {
+    n_frames: usize,
+    /// Frames recorded so far for each sub-aperture
+    counts: Vec<usize>,
+    sum: Array3<f64>,
+    sum_squared: Array3<f64>,
+}
+
+pub struct SubapRecorder {
+    n_subaps: usize,
+    pixels_per_subap: usize,
+    recording_active: AtomicBool,
+    recording: Mutex<Option<Recording>>,
+    result: Mutex<Option<SubapStatistics>>,
+}
+
+impl SubapRecorder {
+    pub fn new(n_subaps: usize, pixels_per_subap: usize) -> Self {
+        Self {
+            n_subaps: n_subaps,
+            pixels_per_subap: pixels_per_subap,
+            recording_active: AtomicBool::new(false),
+            recording: Mutex::new(None),
+            result: Mutex::new(None),
+        }
+    }
+
+    /// Records the next `n_frames` of every sub-aperture, dropping any
+    /// unfinished or uncollected recording
+    pub fn start(&self, n_frames: usize) {
+        assert!(n_frames > 0, "SubapRecorder: need at least one frame");
+        let shape = (self.n_subaps, self.pixels_per_subap, self.pixels_per_subap);
+        *self.result.lock().unwrap() = None;
+        *self.recording.lock().unwrap() = Some(Recording {
+            n_frames: n_frames,
+            counts: vec![0; self.n_subaps],
+            sum: Array3::<f64>::zeros(shape),
+            sum_squared: Array3::<f64>::zeros(shape),
+        });
+        info!("SubapRecorder: recording {} frames", n_frames);
+        self.recording_active.store(true, Ordering::Release);
+    }
+
+    /// True while frames are still being recorded
+    pub fn is_recording(&self) -> bool {
+        self.recording_active.load(Ordering::Acquire)
+    }
+
+    /// Collects the finished recording, or `None` if it is not finished
+    pub fn take(&self) -> Option<SubapStatistics> {
+        self.result.lock().unwrap().take()
+    }
+
+    /// Adds one frame's calibrated pixels of sub-aperture `subap`. Called by
+    /// the sensor for every sub-aperture it measures while recording.
+    pub fn add(&self, subap: usize, pixels: &Array2<f32>) {
+        let mut recording_guard = self.recording.lock().unwrap();
+        let Some(recording) = recording_guard.as_mut() else {
+            return;
+        };
+        if recording.counts[subap] >= recording.n_frames {
+            return;
+        }
+        let pixels = pixels.mapv(|p| p as f64);
+        let mut sum = recording.sum.slice_mut(s![subap, .., ..]);
+        sum += &pixels;
+        let mut sum_squared = recording.sum_squared.slice_mut(s![subap, .., ..]);
+        sum_squared += &(&pixels * &pixels);
+        recording.counts[subap] += 1;
+
+        if recording.counts.iter().all(|&count| count >= recording.n_frames) {
+            let recording = recording_guard.take().unwrap();
+            let n = recording.n_frames as f64;
+            let mean = &recording.sum / n;
+            let variance = if recording.n_frames > 1 {
+                ((&recording.sum_squared / n - &mean * &mean) * (n / (n - 1.0))).mapv(|v| v.max(0.0))
+            } else {
+                Array3::<f64>::zeros(mean.dim())
+            };
+            *self.result.lock().unwrap() = Some(SubapStatistics {
+                mean: mean.mapv(|v| v as f32),
+                variance: variance.mapv(|v| v as f32),
+                n_frames: recording.n_frames,
+            });
+            self.recording_active.store(false, Ordering::Release);
+            info!("SubapRecorder: recording done");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ndarray::array;
+
+    #[test]
+    fn test_recording_statistics() {
+        let recorder = SubapRecorder::new(2, 1);
+        assert!(recorder.take().is_none());
+        recorder.start(2);
+        recorder.add(0, &array![[1.0]]);
+        recorder.add(0, &array![[3.0]]);
+        // Sub-aperture 0 is done, further frames are ignored
+        recorder.add(0, &array![[100.0]]);
+        recorder.add(1, &array![[2.0]]);
+        assert!(recorder.is_recording());
+        recorder.add(1, &array![[2.0]]);
+        assert!(!recorder.is_recording());
+
+        let statistics = recorder.take().unwrap();
+        assert_eq!(statistics.mean, array![[[2.0]], [[2.0]]]);
+        assert_eq!(statistics.variance, array![[[2.0]], [[0.0]]]);
+        assert!(recorder.take().is_none());
+    }
+}